@@ -0,0 +1,101 @@
+use crate::assembler::AssemblerError;
+
+/// Where something came from in the source being assembled. Lines and columns start at 1, columns count characters
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct SourceLocation {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    // Byte offset and length of the span within the source
+    pub offset: usize,
+    pub length: usize,
+}
+
+impl SourceLocation {
+    pub fn new(source: &str, offset: usize, length: usize) -> SourceLocation {
+        LineIndex::new(source).location(offset, length)
+    }
+
+    /// Points at the rest of the line starting at `offset`, for when all we know is where things went wrong
+    pub fn rest_of_line(source: &str, offset: usize) -> SourceLocation {
+        let length = source[offset..].find('\n').unwrap_or(source.len() - offset);
+        SourceLocation::new(source, offset, length)
+    }
+}
+
+/// Where every line of a source starts, so many locations can be found without rescanning it each time
+pub struct LineIndex<'a> {
+    source: &'a str,
+    line_starts: Vec<usize>,
+}
+
+impl<'a> LineIndex<'a> {
+    pub fn new(source: &'a str) -> LineIndex<'a> {
+        let mut line_starts = vec![0];
+        line_starts.extend(source.match_indices('\n').map(|(i, _)| i + 1));
+        LineIndex { source, line_starts }
+    }
+
+    pub fn location(&self, offset: usize, length: usize) -> SourceLocation {
+        let line = match self.line_starts.binary_search(&offset) {
+            Ok(line) => { line },
+            Err(next_line) => { next_line - 1 }
+        };
+        SourceLocation {
+            file: String::new(),
+            line: line + 1,
+            column: self.source[self.line_starts[line]..offset].chars().count() + 1,
+            offset,
+            length,
+        }
+    }
+}
+
+/// Renders an error along with the offending line of source and a caret under the problem, like:
+///
+/// ```text
+/// error: symbol `loop` is already declared
+///  --> loop.iasm:3:1
+///   |
+/// 3 | loop: inc $0
+///   | ^^^^^^^^^^^^
+/// ```
+pub fn render(error: &AssemblerError, source: &str) -> String {
+    let location = error.location();
+    let mut rendered = format!("error: {}\n --> {}:{}:{}\n", error, location.file, location.line, location.column);
+
+    let line_start = source[..location.offset.min(source.len())].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let line_end = source[line_start..].find('\n').map(|i| line_start + i).unwrap_or(source.len());
+    let line = source[line_start..line_end].trim_end_matches('\r');
+    let gutter = " ".repeat(location.line.to_string().len());
+    // Spans running onto later lines get cut off at the end of the first one
+    let caret_length = source[location.offset.min(line_end)..line_end.min(location.offset + location.length)].chars().count().max(1);
+
+    rendered.push_str(&format!("{} |\n", gutter));
+    rendered.push_str(&format!("{} | {}\n", location.line, line));
+    rendered.push_str(&format!("{} | {}{}\n", gutter, " ".repeat(location.column - 1), "^".repeat(caret_length)));
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_location() {
+        let source = ".code\nload $0 #1\n  hlt";
+        let location = SourceLocation::new(source, 19, 3);
+        assert_eq!(location.line, 3);
+        assert_eq!(location.column, 3);
+        assert_eq!(SourceLocation::rest_of_line(source, 6).length, 10);
+    }
+
+    #[test]
+    fn test_render() {
+        let source = ".code\nloop: hlt\nloop: hlt\n";
+        let mut location = SourceLocation::new(source, 16, 9);
+        location.file = "loop.iasm".to_string();
+        let error = AssemblerError::SymbolAlreadyDeclared { name: "loop".to_string(), location };
+        assert_eq!(render(&error, source), "error: symbol `loop` is already declared\n --> loop.iasm:3:1\n  |\n3 | loop: hlt\n  | ^^^^^^^^^\n");
+    }
+}
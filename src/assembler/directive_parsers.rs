@@ -1,3 +1,5 @@
+#![allow(clippy::bool_assert_comparison)]
+
 use crate::assembler::instruction_parsers::AssemblerInstruction;
 use crate::assembler::operand_parsers::operand;
 use crate::assembler::Token;
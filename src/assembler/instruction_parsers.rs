@@ -1,3 +1,5 @@
+#![allow(clippy::needless_return, clippy::collapsible_match, clippy::partialeq_to_none)]
+
 use crate::assembler::Token;
 use crate::assembler::opcode_parsers::*;
 use crate::assembler::operand_parsers::integer_operand;
@@ -5,7 +7,9 @@ use crate::assembler::register_parsers::register;
 use crate::assembler::operand_parsers::operand;
 use crate::assembler::directive_parsers::directive;
 use crate::assembler::label_parsers::label_declaration;
-use crate::assembler::SymbolTable;
+use crate::assembler::{Address, AssemblerError, SymbolTable, SymbolType, INSTRUCTION_LENGTH};
+use crate::assembler::diagnostics::SourceLocation;
+use crate::instruction::{Instruction, Opcode, OperandKind, REGISTER_COUNT};
 use nom::types::CompleteStr;
 use nom::multispace;
 
@@ -109,30 +113,89 @@ named!(instruction_combined<CompleteStr, AssemblerInstruction>,
 );
 
 impl AssemblerInstruction {
-    pub fn to_bytes(&self, symbols: &SymbolTable) -> Vec<u8> {
-        let mut results = vec![];
-        match self.opcode {
-            Some(Token::Op { code }) => {
-                results.push(code as u8);
+    /// Assembles the instruction as if it were at `address` in the program, which relative jumps are measured from
+    pub fn to_bytes(&self, symbols: &SymbolTable, address: Address) -> Result<Vec<u8>, AssemblerError> {
+        let written = match self.opcode {
+            Some(Token::Op { code }) => { code },
+            Some(Token::UnknownOp { ref mnemonic }) => {
+                return Err(AssemblerError::UnknownMnemonic{ mnemonic: mnemonic.clone(), location: SourceLocation::default() });
             },
-            _ => {
-                println!("Non-opcode found in opcode field");
-                std::process::exit(1);
+            Some(ref t) => {
+                return Err(AssemblerError::UnexpectedToken{ token: format!("`{}` where an opcode was expected", t), location: SourceLocation::default() });
+            },
+            None => {
+                return Err(AssemblerError::UnexpectedToken{ token: "instruction without an opcode".to_string(), location: SourceLocation::default() });
             }
         };
+        let code = self.direct_form(written);
+        let mut instruction = Instruction::new(code);
 
-        for operand in vec![&self.operand1, &self.operand2, &self.operand3] {
-            match operand {
-                Some(t) => AssemblerInstruction::extract_operand(t, symbols, &mut results),
-                None => {}
+        // The operands have to be exactly what the opcode expects, or the VM would read them as something else
+        let operands: Vec<&Token> = [&self.operand1, &self.operand2, &self.operand3].iter().filter_map(|o| o.as_ref()).collect();
+        let kinds = code.operands();
+        if operands.len() != kinds.len() {
+            return Err(AssemblerError::WrongOperandCount{ mnemonic: written.mnemonic().to_string(), expected: kinds.len(), found: operands.len(), location: SourceLocation::default() });
+        }
+        for (position, (token, kind)) in operands.iter().zip(kinds).enumerate() {
+            if !AssemblerInstruction::accepts(*kind, token, symbols) {
+                return Err(AssemblerError::WrongOperandKind{ mnemonic: written.mnemonic().to_string(), position: position + 1, expected: *kind, found: token.to_string(), location: SourceLocation::default() });
             }
         }
+        if let (Some(value), Some(Token::Register { reg_num })) = (self.wide_load_value(), &self.operand1) {
+            return AssemblerInstruction::wide_load(*reg_num, value);
+        }
+        for (token, kind) in operands.iter().zip(kinds) {
+            let value = match (kind, token) {
+                (OperandKind::Displacement, Token::LabelUsage { name }) => {
+                    AssemblerInstruction::displacement(written, code, name, symbols, address)?
+                },
+                _ => AssemblerInstruction::operand_value(token, *kind, symbols)?
+            };
+            instruction.add_operand(value);
+        }
+
+        Ok(instruction.encode().to_vec())
+    }
 
-        while results.len() < 4 {
-            results.push(0);
+    /// How many instructions this assembles into
+    pub fn instruction_count(&self) -> usize {
+        if self.wide_load_value().is_some() { 2 } else { 1 }
+    }
+
+    // A `load` of a number that doesn't fit in its 16 bits is a wide load, which becomes a `load` of the bottom half
+    // followed by a `loadhi` of the top half
+    fn wide_load_value(&self) -> Option<i64> {
+        match (&self.opcode, &self.operand1, &self.operand2, &self.operand3) {
+            (Some(Token::Op { code: Opcode::LOAD }), Some(Token::Register { .. }), Some(Token::Number { value }), None) if !(0..=0xFFFF).contains(value) => {
+                Some(*value)
+            },
+            _ => None
+        }
+    }
+
+    fn wide_load(register: u8, value: i64) -> Result<Vec<u8>, AssemblerError> {
+        // Anything a register can hold can be loaded, whether it's written signed or unsigned
+        if value < i32::MIN as i64 || value > u32::MAX as i64 {
+            return Err(AssemblerError::NumberOutOfRange{ value, min: i32::MIN as i64, max: u32::MAX as i64, location: SourceLocation::default() });
         }
+        let mut low = Instruction::new(Opcode::LOAD);
+        low.add_operand(register as u32);
+        low.add_operand(value as u32 & 0xFFFF);
+        let mut high = Instruction::new(Opcode::LOADHI);
+        high.add_operand(register as u32);
+        high.add_operand(value as u32 >> 16);
+        Ok([low.encode(), high.encode()].concat())
+    }
 
-        return results;
+    // Jumps given a label instead of a register are assembled into the direct forms, which carry the address themselves
+    fn direct_form(&self, code: Opcode) -> Opcode {
+        match (code, &self.operand1) {
+            (Opcode::JMP, Some(Token::LabelUsage { .. })) => Opcode::DJMP,
+            (Opcode::JMPE, Some(Token::LabelUsage { .. })) => Opcode::DJMPE,
+            (Opcode::JMPF, Some(Token::LabelUsage { .. })) => Opcode::DJMPF,
+            (Opcode::JMPB, Some(Token::LabelUsage { .. })) => Opcode::DJMPB,
+            _ => code
+        }
     }
 
     pub fn is_label(&self) -> bool {
@@ -189,34 +252,89 @@ impl AssemblerInstruction {
         }
     }
 
-    fn extract_operand(t: &Token, symbols: &SymbolTable, results: &mut Vec<u8>) {
-        match t {
-            Token::Register { reg_num } => {
-                results.push(*reg_num);
+    // A label has to be the right sort of symbol too: code labels for anything that jumps, and constants for anything
+    // that reads the read-only section
+    fn accepts(kind: OperandKind, t: &Token, symbols: &SymbolTable) -> bool {
+        match (kind, t) {
+            (OperandKind::Register, Token::Register { reg_num }) => (*reg_num as usize) < REGISTER_COUNT,
+            (OperandKind::FloatRegister, Token::Register { reg_num }) => (*reg_num as usize) < REGISTER_COUNT,
+            (OperandKind::Register, _) | (OperandKind::FloatRegister, _) => false,
+            (OperandKind::ConstantOffset, Token::Float { .. }) => true,
+            (_, Token::Number { .. }) => true,
+            (OperandKind::Immediate8, _) => false,
+            (_, Token::LabelUsage { name }) => match symbols.symbol_type(name) {
+                Some(SymbolType::Label) => matches!(kind, OperandKind::Immediate16 | OperandKind::CodeAddress | OperandKind::Displacement),
+                Some(SymbolType::Constant) => matches!(kind, OperandKind::Immediate16 | OperandKind::StringOffset | OperandKind::ConstantOffset),
+                // Left for looking up its value to report
+                None => true,
+            },
+            _ => false
+        }
+    }
+
+    // An address is only useful if all of it fits, so unlike a number it is never truncated
+    fn check_address(name: &str, offset: Address, kind: OperandKind) -> Result<u32, AssemblerError> {
+        if offset >> (kind.width() * 8) != 0 {
+            // The assembler knows where this instruction came from and fills in the location, as for every error here
+            return Err(AssemblerError::AddressOutOfRange{ name: name.to_string(), address: offset, width: kind.width(), location: SourceLocation::default() });
+        }
+        Ok(offset)
+    }
+
+    // Relative jumps go from the start of the next instruction, and only in one direction
+    fn displacement(written: Opcode, code: Opcode, name: &str, symbols: &SymbolTable, address: Address) -> Result<u32, AssemblerError> {
+        let target = match symbols.symbol_value(name) {
+            Some(target) => { target },
+            None => {
+                return Err(AssemblerError::UndefinedSymbol{ name: name.to_string(), location: SourceLocation::default() });
             }
+        };
+        let next = address + INSTRUCTION_LENGTH as Address;
+        let distance = match code {
+            Opcode::DJMPF => target.checked_sub(next),
+            _ => next.checked_sub(target)
+        };
+        match distance {
+            Some(distance) => AssemblerInstruction::check_address(name, distance, OperandKind::Displacement),
+            None => Err(AssemblerError::WrongJumpDirection{ mnemonic: written.mnemonic().to_string(), name: name.to_string(), location: SourceLocation::default() })
+        }
+    }
+
+    fn operand_value(t: &Token, kind: OperandKind, symbols: &SymbolTable) -> Result<u32, AssemblerError> {
+        match t {
+            Token::Register { reg_num } => Ok(*reg_num as u32),
             Token::Number { value } => {
-                let converted = *value as u16;
-                let byte1 = converted;
-                let byte2 = converted >> 8;
-                results.push(byte2 as u8);
-                results.push(byte1 as u8);
-            }
+                let (min, max) = kind.range();
+                if *value < min || *value > max {
+                    return Err(AssemblerError::NumberOutOfRange{ value: *value, min, max, location: SourceLocation::default() });
+                }
+                Ok(*value as u32)
+            },
             Token::LabelUsage { name } => {
-                let offset = symbols.symbol_value(name);
-                results.push(offset);
+                let offset = match symbols.symbol_value(name) {
+                    Some(offset) => { offset },
+                    None => {
+                        return Err(AssemblerError::UndefinedSymbol{ name: name.to_string(), location: SourceLocation::default() });
+                    }
+                };
+                AssemblerInstruction::check_address(name, offset, kind)
+            }
+            Token::Float { value } => {
+                // Every float was put in the read-only section during the first phase
+                let offset = symbols.float_offset(*value).unwrap_or(0);
+                AssemblerInstruction::check_address(&t.to_string(), offset, kind)
             }
             _ => {
-                println!("Opcode found in operand field");
-                std::process::exit(1);
+                Err(AssemblerError::UnexpectedToken{ token: format!("`{}` where an operand was expected", t), location: SourceLocation::default() })
             }
-        };
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::instruction::Opcode;
+    use crate::assembler::{Symbol, SymbolType};
 
     #[test]
     fn test_parse_instruction_form_one() {
@@ -274,4 +392,138 @@ mod tests {
             ))
         );
     }
+
+    #[test]
+    fn test_label_jump_to_bytes() {
+        let mut symbols = SymbolTable::new();
+        let mut symbol = Symbol::new("test".to_string(), SymbolType::Label);
+        symbol.set_offset(72);
+        symbols.add_symbol(symbol);
+        let (_, instruction) = instruction_combined(CompleteStr("jmpe @test\n")).unwrap();
+        assert_eq!(instruction.to_bytes(&symbols, 0).unwrap(), vec![Opcode::DJMPE as u8, 0, 0, 72]);
+        let (_, instruction) = instruction_combined(CompleteStr("jmpe $1\n")).unwrap();
+        assert_eq!(instruction.to_bytes(&symbols, 0).unwrap(), vec![Opcode::JMPE as u8, 1, 0, 0]);
+        let (_, instruction) = instruction_combined(CompleteStr("load $2 @test\n")).unwrap();
+        assert_eq!(instruction.to_bytes(&symbols, 0).unwrap(), vec![Opcode::LOAD as u8, 2, 0, 72]);
+    }
+
+    #[test]
+    fn test_to_bytes_errors() {
+        let symbols = SymbolTable::new();
+        let (_, instruction) = instruction_combined(CompleteStr("jmp @nowhere\n")).unwrap();
+        assert_eq!(instruction.to_bytes(&symbols, 0), Err(AssemblerError::UndefinedSymbol { name: "nowhere".to_string(), location: SourceLocation::default() }));
+        let instruction = AssemblerInstruction {
+            opcode: Some(Token::Op { code: Opcode::PUSH }),
+            directive: None,
+            label: None,
+            operand1: Some(Token::Op { code: Opcode::HLT }),
+            operand2: None,
+            operand3: None
+        };
+        assert_eq!(instruction.to_bytes(&symbols, 0).unwrap_err().to_string(), "operand 1 of `push` should be a register, found `hlt`");
+    }
+
+    #[test]
+    fn test_operand_validation() {
+        let symbols = SymbolTable::new();
+        let (_, instruction) = instruction_combined(CompleteStr("add $0 #5\n")).unwrap();
+        assert_eq!(instruction.to_bytes(&symbols, 0).unwrap_err().to_string(), "`add` takes 3 operands but was given 2");
+        let (_, instruction) = instruction_combined(CompleteStr("add $0 #5 $1\n")).unwrap();
+        assert_eq!(instruction.to_bytes(&symbols, 0).unwrap_err().to_string(), "operand 2 of `add` should be a register, found `#5`");
+        let (_, instruction) = instruction_combined(CompleteStr("hlt $1 $2 $3\n")).unwrap();
+        assert_eq!(instruction.to_bytes(&symbols, 0).unwrap_err().to_string(), "`hlt` takes 0 operands but was given 3");
+        let (_, instruction) = instruction_combined(CompleteStr("inc\n")).unwrap();
+        assert_eq!(instruction.to_bytes(&symbols, 0).unwrap_err().to_string(), "`inc` takes 1 operand but was given 0");
+        let (_, instruction) = instruction_combined(CompleteStr("lw $0 $1 $2\n")).unwrap();
+        assert_eq!(instruction.to_bytes(&symbols, 0).unwrap_err().to_string(), "operand 3 of `lw` should be an 8-bit number, found `$2`");
+    }
+
+    #[test]
+    fn test_symbol_type_validation() {
+        let mut symbols = SymbolTable::new();
+        symbols.add_symbol(Symbol::new("loop".to_string(), SymbolType::Label));
+        symbols.add_symbol(Symbol::new("hello".to_string(), SymbolType::Constant));
+        let (_, instruction) = instruction_combined(CompleteStr("prts @loop\n")).unwrap();
+        assert_eq!(instruction.to_bytes(&symbols, 0).unwrap_err().to_string(), "operand 1 of `prts` should be a string label, found `@loop`");
+        let (_, instruction) = instruction_combined(CompleteStr("call @hello\n")).unwrap();
+        assert_eq!(instruction.to_bytes(&symbols, 0).unwrap_err().to_string(), "operand 1 of `call` should be a code label, found `@hello`");
+        let (_, instruction) = instruction_combined(CompleteStr("jmpb @hello\n")).unwrap();
+        assert!(instruction.to_bytes(&symbols, 0).is_err());
+        let (_, instruction) = instruction_combined(CompleteStr("prts @hello\n")).unwrap();
+        assert!(instruction.to_bytes(&symbols, 0).is_ok());
+        let (_, instruction) = instruction_combined(CompleteStr("load $0 @loop\n")).unwrap();
+        assert!(instruction.to_bytes(&symbols, 0).is_ok());
+    }
+
+    #[test]
+    fn test_register_out_of_range() {
+        let symbols = SymbolTable::new();
+        let (_, instruction) = instruction_combined(CompleteStr("inc $31\n")).unwrap();
+        assert!(instruction.to_bytes(&symbols, 0).is_ok());
+        let (_, instruction) = instruction_combined(CompleteStr("inc $32\n")).unwrap();
+        assert_eq!(instruction.to_bytes(&symbols, 0).unwrap_err().to_string(), "operand 1 of `inc` should be a register, found `$32`");
+        let (_, instruction) = instruction_combined(CompleteStr("load $255 #0x12345678\n")).unwrap();
+        assert!(instruction.to_bytes(&symbols, 0).is_err());
+        let (_, instruction) = instruction_combined(CompleteStr("addf $0 $1 $40\n")).unwrap();
+        assert!(instruction.to_bytes(&symbols, 0).is_err());
+    }
+
+    #[test]
+    fn test_numbers_fill_their_operand() {
+        let symbols = SymbolTable::new();
+        let (_, instruction) = instruction_combined(CompleteStr("call #300\n")).unwrap();
+        assert_eq!(instruction.to_bytes(&symbols, 0).unwrap(), vec![Opcode::CALL as u8, 0, 1, 44]);
+    }
+
+    #[test]
+    fn test_wide_immediates_to_bytes() {
+        let symbols = SymbolTable::new();
+        let (_, instruction) = instruction_combined(CompleteStr("load $1 #0xFFFF\n")).unwrap();
+        assert_eq!(instruction.to_bytes(&symbols, 0).unwrap(), vec![Opcode::LOAD as u8, 1, 255, 255]);
+        let (_, instruction) = instruction_combined(CompleteStr("load $1 #-2\n")).unwrap();
+        assert_eq!(instruction.instruction_count(), 2);
+        assert_eq!(instruction.to_bytes(&symbols, 0).unwrap(), vec![Opcode::LOAD as u8, 1, 255, 254, Opcode::LOADHI as u8, 1, 255, 255]);
+        let (_, instruction) = instruction_combined(CompleteStr("load $1 #0x12345678\n")).unwrap();
+        assert_eq!(instruction.to_bytes(&symbols, 0).unwrap(), vec![Opcode::LOAD as u8, 1, 0x56, 0x78, Opcode::LOADHI as u8, 1, 0x12, 0x34]);
+        let (_, instruction) = instruction_combined(CompleteStr("loadhi $1 #-1\n")).unwrap();
+        assert_eq!(instruction.to_bytes(&symbols, 0).unwrap(), vec![Opcode::LOADHI as u8, 1, 255, 255]);
+    }
+
+    #[test]
+    fn test_number_out_of_range() {
+        let symbols = SymbolTable::new();
+        for (source, value, min, max) in [
+            ("load $1 #0x100000000\n", 0x1_0000_0000, i32::MIN as i64, u32::MAX as i64),
+            ("lw $0 $1 #256\n", 256, 0, 255),
+            ("lw $0 $1 #-1\n", -1, 0, 255),
+            ("loadhi $0 #65536\n", 65536, -32768, 65535),
+            ("call #0x1000000\n", 0x100_0000, 0, 0xFF_FFFF),
+        ] {
+            let (_, instruction) = instruction_combined(CompleteStr(source)).unwrap();
+            assert_eq!(instruction.to_bytes(&symbols, 0), Err(AssemblerError::NumberOutOfRange { value, min, max, location: SourceLocation::default() }), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_relative_jump_to_bytes() {
+        let mut symbols = SymbolTable::new();
+        let mut symbol = Symbol::new("test".to_string(), SymbolType::Label);
+        symbol.set_offset(72);
+        symbols.add_symbol(symbol);
+        let (_, instruction) = instruction_combined(CompleteStr("jmpf @test\n")).unwrap();
+        assert_eq!(instruction.to_bytes(&symbols, 64).unwrap(), vec![Opcode::DJMPF as u8, 0, 0, 4]);
+        assert_eq!(instruction.to_bytes(&symbols, 72).unwrap_err().to_string(), "`jmpf` can't reach `@test`, which is the other way");
+        let (_, instruction) = instruction_combined(CompleteStr("jmpb @test\n")).unwrap();
+        assert_eq!(instruction.to_bytes(&symbols, 72).unwrap(), vec![Opcode::DJMPB as u8, 0, 0, 4]);
+        assert_eq!(instruction.to_bytes(&symbols, 64).unwrap_err().to_string(), "`jmpb` can't reach `@test`, which is the other way");
+        let (_, instruction) = instruction_combined(CompleteStr("jmpf $1\n")).unwrap();
+        assert_eq!(instruction.to_bytes(&symbols, 64).unwrap(), vec![Opcode::JMPF as u8, 1, 0, 0]);
+    }
+
+    #[test]
+    fn test_offset_to_bytes() {
+        let symbols = SymbolTable::new();
+        let (_, instruction) = instruction_combined(CompleteStr("lw $0 $1 #4\n")).unwrap();
+        assert_eq!(instruction.to_bytes(&symbols, 0).unwrap(), vec![Opcode::LW as u8, 0, 1, 4]);
+    }
 }
@@ -1,3 +1,5 @@
+#![allow(clippy::bool_assert_comparison)]
+
 use nom::types::CompleteStr;
 use nom::{alphanumeric, multispace};
 
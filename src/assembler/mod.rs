@@ -1,12 +1,22 @@
+#![allow(clippy::new_without_default, clippy::single_match, clippy::needless_return)]
+
+use std::fmt;
+
+use crate::assembler::diagnostics::SourceLocation;
 use crate::assembler::instruction_parsers::AssemblerInstruction;
-use crate::assembler::program_parsers::program;
+use crate::assembler::program_parsers::{program, trivia};
 use crate::assembler::program_parsers::Program;
-use crate::instruction::Opcode;
+use crate::assembler::source_map::SourceMap;
+use crate::instruction::{Opcode, OperandKind};
+use crate::debug_info::DebugInfo;
+use crate::pie::{build_image_with_debug_info, PIE_HEADER_LENGTH};
 
 use nom::types::CompleteStr;
 
-pub const PIE_HEADER_PREFIX: [u8; 4] = [45, 50, 49, 45];
-pub const PIE_HEADER_LENGTH: usize = 64;
+pub use crate::instruction::INSTRUCTION_LENGTH;
+
+/// An offset into the assembled program or its read-only section
+pub type Address = u32;
 
 #[derive(Debug, PartialEq)]
 pub enum AssemblerPhase {
@@ -16,6 +26,7 @@ pub enum AssemblerPhase {
 
 #[derive(Debug, PartialEq)]
 pub enum AssemblerSection {
+    Data,
     Code,
     Unknown
 }
@@ -23,19 +34,92 @@ pub enum AssemblerSection {
 impl From<&str> for AssemblerSection {
     fn from(name: &str) -> Self { 
         match name {
+            "data" => { AssemblerSection::Data },
             "code" => { AssemblerSection::Code },
-            _ => todo!("{:?}", name)
+            _ => { AssemblerSection::Unknown }
         }
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum AssemblerError {
-    UnknownDirectiveFound{directive: String},
-    NoSegmentDeclarationFound{instruction: u32},
-    SymbolAlreadyDeclared,
-    StringConstantDeclaredWithoutLabel{instruction: u32},
-    ParseError{error: String}
+    UnknownDirectiveFound{directive: String, location: SourceLocation},
+    UnknownMnemonic{mnemonic: String, location: SourceLocation},
+    NoSegmentDeclarationFound{instruction: u32, location: SourceLocation},
+    SymbolAlreadyDeclared{name: String, location: SourceLocation},
+    StringConstantDeclaredWithoutLabel{instruction: u32, location: SourceLocation},
+    AddressOutOfRange{name: String, address: Address, width: usize, location: SourceLocation},
+    UndefinedSymbol{name: String, location: SourceLocation},
+    UnexpectedToken{token: String, location: SourceLocation},
+    WrongOperandCount{mnemonic: String, expected: usize, found: usize, location: SourceLocation},
+    WrongOperandKind{mnemonic: String, position: usize, expected: OperandKind, found: String, location: SourceLocation},
+    NumberOutOfRange{value: i64, min: i64, max: i64, location: SourceLocation},
+    WrongJumpDirection{mnemonic: String, name: String, location: SourceLocation},
+    ParseError{error: String, location: SourceLocation}
+}
+
+impl AssemblerError {
+    pub fn location(&self) -> &SourceLocation {
+        match self {
+            AssemblerError::UnknownDirectiveFound{location, ..} => location,
+            AssemblerError::UnknownMnemonic{location, ..} => location,
+            AssemblerError::NoSegmentDeclarationFound{location, ..} => location,
+            AssemblerError::SymbolAlreadyDeclared{location, ..} => location,
+            AssemblerError::StringConstantDeclaredWithoutLabel{location, ..} => location,
+            AssemblerError::AddressOutOfRange{location, ..} => location,
+            AssemblerError::UndefinedSymbol{location, ..} => location,
+            AssemblerError::UnexpectedToken{location, ..} => location,
+            AssemblerError::WrongOperandCount{location, ..} => location,
+            AssemblerError::WrongOperandKind{location, ..} => location,
+            AssemblerError::NumberOutOfRange{location, ..} => location,
+            AssemblerError::WrongJumpDirection{location, ..} => location,
+            AssemblerError::ParseError{location, ..} => location,
+        }
+    }
+
+    // Errors found while turning a single instruction into bytes don't know where it came from, so the assembler fills
+    // that in afterwards
+    fn set_location(&mut self, new_location: SourceLocation) {
+        match self {
+            AssemblerError::UnknownDirectiveFound{location, ..} => *location = new_location,
+            AssemblerError::UnknownMnemonic{location, ..} => *location = new_location,
+            AssemblerError::NoSegmentDeclarationFound{location, ..} => *location = new_location,
+            AssemblerError::SymbolAlreadyDeclared{location, ..} => *location = new_location,
+            AssemblerError::StringConstantDeclaredWithoutLabel{location, ..} => *location = new_location,
+            AssemblerError::AddressOutOfRange{location, ..} => *location = new_location,
+            AssemblerError::UndefinedSymbol{location, ..} => *location = new_location,
+            AssemblerError::UnexpectedToken{location, ..} => *location = new_location,
+            AssemblerError::WrongOperandCount{location, ..} => *location = new_location,
+            AssemblerError::WrongOperandKind{location, ..} => *location = new_location,
+            AssemblerError::NumberOutOfRange{location, ..} => *location = new_location,
+            AssemblerError::WrongJumpDirection{location, ..} => *location = new_location,
+            AssemblerError::ParseError{location, ..} => *location = new_location,
+        }
+    }
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AssemblerError::UnknownDirectiveFound{directive, ..} => write!(f, "unknown directive `.{}`", directive),
+            AssemblerError::UnknownMnemonic{mnemonic, ..} => write!(f, "unknown instruction `{}`", mnemonic),
+            AssemblerError::NoSegmentDeclarationFound{..} => write!(f, "label declared before any `.data` or `.code` section"),
+            AssemblerError::SymbolAlreadyDeclared{name, ..} => write!(f, "symbol `{}` is already declared", name),
+            AssemblerError::StringConstantDeclaredWithoutLabel{..} => write!(f, "string constant has no label"),
+            AssemblerError::AddressOutOfRange{name, address, width, ..} => write!(f, "address {} of `{}` does not fit in {} bytes", address, name, width),
+            AssemblerError::UndefinedSymbol{name, ..} => write!(f, "symbol `{}` is not declared", name),
+            AssemblerError::UnexpectedToken{token, ..} => write!(f, "unexpected {}", token),
+            AssemblerError::WrongOperandCount{mnemonic, expected, found, ..} => {
+                write!(f, "`{}` takes {} operand{} but was given {}", mnemonic, expected, if *expected == 1 { "" } else { "s" }, found)
+            },
+            AssemblerError::WrongOperandKind{mnemonic, position, expected, found, ..} => {
+                write!(f, "operand {} of `{}` should be {}, found `{}`", position, mnemonic, expected, found)
+            },
+            AssemblerError::NumberOutOfRange{value, min, max, ..} => write!(f, "{} does not fit here, which takes {} to {}", value, min, max),
+            AssemblerError::WrongJumpDirection{mnemonic, name, ..} => write!(f, "`{}` can't reach `@{}`, which is the other way", mnemonic, name),
+            AssemblerError::ParseError{error, ..} => write!(f, "unable to parse: {}", error),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -44,9 +128,16 @@ pub struct Assembler {
     pub symbols: SymbolTable,
     pub ro: Vec<u8>,
     pub bytecode: Vec<u8>,
-    ro_offset: u8,
+    /// Where each instruction in the last program assembled came from
+    pub source_map: SourceMap,
+    ro_offset: Address,
+    code_offset: Address,
     current_section: Option<AssemblerSection>,
     current_instruction: u32,
+    // Name of the file being assembled, for error messages
+    file_name: String,
+    // Whether images get a debug info section
+    debug_info: bool,
     errors: Vec<AssemblerError>
 }
 
@@ -57,95 +148,195 @@ impl Assembler {
             symbols: SymbolTable::new(),
             ro: vec![],
             bytecode: vec![],
+            source_map: SourceMap::new(),
             ro_offset: 0,
+            code_offset: 0,
             current_section: None,
             current_instruction: 0,
+            file_name: "<input>".to_string(),
+            debug_info: false,
             errors: vec![]
         }
     }
 
+    pub fn set_file_name(&mut self, file_name: &str) {
+        self.file_name = file_name.to_string();
+    }
+
+    /// Puts the source map and symbol names in the images assembled from now on, so traps and disassembly can refer
+    /// to the source. Off by default, since the VM doesn't need it to run anything
+    pub fn set_debug_info(&mut self, debug_info: bool) {
+        self.debug_info = debug_info;
+    }
+
     pub fn assemble(&mut self, raw: &str) -> Result<Vec<u8>, Vec<AssemblerError>> {
         match program(CompleteStr(raw)) {
-            Ok((_remainder, program)) => {
-                let mut assembled_program = self.write_pie_header();
+            Ok((remainder, mut program)) => {
+                for location in program.locations.iter_mut() {
+                    location.file = self.file_name.clone();
+                }
+                // The parser stops at the first thing it doesn't understand, so anything left over is an error
+                if !remainder.trim().is_empty() {
+                    let offset = raw.len() - remainder.trim_start().len();
+                    return Err(vec![AssemblerError::ParseError{
+                        error: "expected an instruction or directive".to_string(),
+                        location: self.location_at(raw, offset)
+                    }]);
+                }
+
+                // Both phases run even if the first one found problems, so everything wrong with the program is
+                // reported at once
                 self.process_first_phase(&program);
+                let body = self.process_second_phase(&program, raw);
 
                 if !self.errors.is_empty() {
-                    // TODO: Can we avoid a clone here?
                     return Err(self.errors.clone());
                 };
 
-                let mut body = self.process_second_phase(&program);
-
-                assembled_program.append(&mut body);
-                Ok(assembled_program)
+                // The header describes both sections, so it can only be written once they are done
+                let debug_info = match self.debug_info {
+                    true => DebugInfo::new(self.source_map.clone(), &self.symbols).to_bytes(),
+                    false => vec![],
+                };
+                Ok(build_image_with_debug_info(&self.ro, &body, &debug_info))
             },
-            Err(e) => {
-                println!("There was an error assembling the code: {:?}", e);
-                Err(vec![AssemblerError::ParseError{ error: e.to_string() }])
+            Err(_) => {
+                let (rest, _) = trivia(CompleteStr(raw));
+                let offset = raw.len() - rest.len();
+                Err(vec![AssemblerError::ParseError{
+                    error: "expected an instruction or directive".to_string(),
+                    location: self.location_at(raw, offset)
+                }])
             }
         }
     }
+
+    fn location_at(&self, raw: &str, offset: usize) -> SourceLocation {
+        let mut location = SourceLocation::rest_of_line(raw, offset);
+        location.file = self.file_name.clone();
+        location
+    }
     
     fn process_first_phase(&mut self, p: &Program) {
         self.extract_labels_and_directives(p);
         self.phase = AssemblerPhase::Second;
     }
     
-    fn process_second_phase(&mut self, p: &Program) -> Vec<u8> {
+    fn process_second_phase(&mut self, p: &Program, raw: &str) -> Vec<u8> {
         self.current_instruction = 0;
         let mut program = vec![];
-        for i in &p.instructions {
+        // Worked out separately from the bytes so far, so an instruction that fails to assemble doesn't throw off the
+        // addresses of everything after it
+        let mut address = (PIE_HEADER_LENGTH + self.ro.len()) as Address;
+        for (i, location) in p.instructions.iter().zip(&p.locations) {
             if i.is_opcode() {
+                self.source_map.add(address, location.clone());
                 // Opcodes know how to properly transform themselves into 32-bits, so we can just call `to_bytes` and append to our program
-                let mut bytes = i.to_bytes(&self.symbols);
-                program.append(&mut bytes);
+                match i.to_bytes(&self.symbols, address) {
+                    Ok(mut bytes) => { program.append(&mut bytes); },
+                    Err(mut e) => {
+                        let location = match e {
+                            AssemblerError::UnknownMnemonic{..} => self.mnemonic_location(raw, i, location),
+                            _ => location.clone(),
+                        };
+                        e.set_location(location);
+                        self.errors.push(e);
+                    }
+                }
+                address += (i.instruction_count() * INSTRUCTION_LENGTH) as Address;
             }
             if i.is_directive() {
                 // In this phase, we can have directives but of different types than we care about in the first pass. The Directive itself can check which pass the Assembler
                 // is in and decide what to do about it
-                self.process_directive(i);
+                self.process_directive(i, location);
             }
             self.current_instruction += 1
         }
+        self.source_map.set_end(address);
         program
     }
 
+    // Where the mnemonic is written within an instruction, which comes after its label if it has one
+    fn mnemonic_location(&self, raw: &str, i: &AssemblerInstruction, location: &SourceLocation) -> SourceLocation {
+        let span = &raw[location.offset..location.offset + location.length];
+        let after_label = match i.is_label() {
+            true => span.find(':').map(|colon| colon + 1).unwrap_or(0),
+            false => 0,
+        };
+        let start = location.offset + after_label + span[after_label..].len() - span[after_label..].trim_start().len();
+        let length = raw[start..].find(|c: char| !c.is_alphabetic()).unwrap_or(raw.len() - start);
+        let mut mnemonic = SourceLocation::new(raw, start, length);
+        mnemonic.file = location.file.clone();
+        mnemonic
+    }
+
     fn extract_labels_and_directives(&mut self, p: &Program) {
-        for i in &p.instructions {
+        for (i, location) in p.instructions.iter().zip(&p.locations) {
             if i.is_label() {
                 if self.current_section.is_some() {
-                    let name: String = match i.label_name() {
-                        Some(name) => { name },
+                    match i.label_name() {
+                        Some(name) => { self.declare_symbol(i, name, location) },
                         None => {
-                            self.errors.push(AssemblerError::StringConstantDeclaredWithoutLabel{instruction: self.current_instruction});
-                            return;
+                            self.errors.push(AssemblerError::StringConstantDeclaredWithoutLabel{instruction: self.current_instruction, location: location.clone()});
                         }
                     };
-                    if self.symbols.has_symbol(&name) {
-                        self.errors.push(AssemblerError::SymbolAlreadyDeclared);
-                        return;
-                    }
-                    let symbol = Symbol::new(name.to_string(), SymbolType::Label);
-                    self.symbols.add_symbol(symbol);
                 } else {
                     // If we have *not* hit a segment header yet, then we have a label outside of a segment, which is not allowed
-                    self.errors.push(AssemblerError::NoSegmentDeclarationFound{instruction: self.current_instruction});
+                    self.errors.push(AssemblerError::NoSegmentDeclarationFound{instruction: self.current_instruction, location: location.clone()});
                 }
             }
             if i.is_directive() {
-                self.process_directive(i);
+                self.process_directive(i, location);
+            }
+            if i.is_opcode() {
+                self.pool_floats(i);
+                self.code_offset += (i.instruction_count() * INSTRUCTION_LENGTH) as Address;
             }
             self.current_instruction += 1;
         }
+        // The code comes after the header and the read-only section, so labels can only be given their real address once
+        // every constant has been read in
+        self.symbols.relocate_labels(PIE_HEADER_LENGTH + self.ro.len());
+    }
+
+    // Float literals are too big to fit in an instruction, so they are kept in the read-only section and the
+    // instruction refers to them by offset. Each distinct value is only stored once
+    fn pool_floats(&mut self, i: &AssemblerInstruction) {
+        for operand in [&i.operand1, &i.operand2, &i.operand3] {
+            if let Some(Token::Float { value }) = operand {
+                if self.symbols.float_offset(*value).is_none() {
+                    self.symbols.add_float(*value, self.ro_offset);
+                    self.ro.extend_from_slice(&value.to_le_bytes());
+                    self.ro_offset += 8;
+                }
+            }
+        }
+    }
+
+    fn declare_symbol(&mut self, i: &AssemblerInstruction, name: String, location: &SourceLocation) {
+        if self.symbols.has_symbol(&name) {
+            self.errors.push(AssemblerError::SymbolAlreadyDeclared{name, location: location.clone()});
+            return;
+        }
+        // Labels on instructions point into the code, anything else is a constant that lives in the read-only section
+        let symbol = if i.is_opcode() {
+            let mut symbol = Symbol::new(name, SymbolType::Label);
+            symbol.set_offset(self.code_offset);
+            symbol
+        } else {
+            Symbol::new(name, SymbolType::Constant)
+        };
+        self.symbols.add_symbol(symbol);
     }
 
-    fn process_directive(&mut self, i: &AssemblerInstruction) { 
+    fn process_directive(&mut self, i: &AssemblerInstruction, location: &SourceLocation) { 
         // First let’s make sure we have a parseable name 
         let directive_name = match i.get_directive_name() { 
             Some(name) => { name }, 
             None => { 
-                println!("Directive has an invalid name: {:?}", i); 
+                if self.phase == AssemblerPhase::First {
+                    self.errors.push(AssemblerError::UnexpectedToken{ token: "directive name".to_string(), location: location.clone() });
+                }
                 return; 
             } 
         };
@@ -156,30 +347,35 @@ impl Assembler {
             match directive_name.as_ref() {
                 // If this is the operand, we're declaring a null terminated string
                 "asciiz" => {
-                    self.handle_asciiz(i);
+                    self.handle_asciiz(i, location);
                 }
                 _ => {
-                    self.errors.push(AssemblerError::UnknownDirectiveFound{ directive: directive_name.clone() });
+                    // Both phases see every directive, so only complain about it once
+                    if self.phase == AssemblerPhase::First {
+                        self.errors.push(AssemblerError::UnknownDirectiveFound{ directive: directive_name.clone(), location: location.clone() });
+                    }
                     return;
                 }
             }
         } else {
             // If there were not any operands, (e.g., `.code`), then we know it is a section header
-            self.process_section_header(&directive_name);
+            self.process_section_header(&directive_name, location);
         }
     }
 
-    fn process_section_header(&mut self, header_name: &str) {
+    fn process_section_header(&mut self, header_name: &str, location: &SourceLocation) {
         let new_section: AssemblerSection = header_name.into();
         // Only specific section names are allowed
         if new_section == AssemblerSection::Unknown {
-            println!("Found an section header that is unknown: {:#?}", header_name);
+            if self.phase == AssemblerPhase::First {
+                self.errors.push(AssemblerError::UnknownDirectiveFound{ directive: header_name.to_string(), location: location.clone() });
+            }
             return;
         }
         self.current_section = Some(new_section);
     }
 
-    fn handle_asciiz(&mut self, i: &AssemblerInstruction) {
+    fn handle_asciiz(&mut self, i: &AssemblerInstruction, location: &SourceLocation) {
         // Being a constant declaration, this is only meaningful in the first pass
         if self.phase != AssemblerPhase::First { return; }
     
@@ -191,7 +387,7 @@ impl Assembler {
                     None => {
                         // This would be someone typing:
                         // .asciiz 'Hello'
-                        println!("Found a string constant with no associated label!");
+                        self.errors.push(AssemblerError::StringConstantDeclaredWithoutLabel{ instruction: self.current_instruction, location: location.clone() });
                         return;
                     }
                 };
@@ -205,40 +401,52 @@ impl Assembler {
                 self.ro_offset += 1;
             }
             None => {
-                // This just means someone typed `.asciiz` for some reason
-                println!("String constant following an .asciiz was empty");
+                // This means someone gave `.asciiz` something other than a string
+                self.errors.push(AssemblerError::UnexpectedToken{ token: "operand to `.asciiz`, expected a string".to_string(), location: location.clone() });
             }
         }
     }
 
-    fn write_pie_header(&self) -> Vec<u8> {
-        let mut header = vec![];
-        for byte in PIE_HEADER_PREFIX {
-            header.push(byte.clone());
-        }
-        while header.len() < PIE_HEADER_LENGTH {
-            header.push(0 as u8);
-        }
-        header
-    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token {
     Op{code: Opcode},
+    UnknownOp{mnemonic: String},
     Register{reg_num: u8},
-    Number{value: i32},
+    Number{value: i64},
+    Float{value: f64},
     LabelDeclaration { name: String },
     LabelUsage { name: String },
     Directive { name: String },
     IrString { literal: String }
 }
 
+/// Writes a token back out the way it would appear in source
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Op{code} => write!(f, "{}", code.mnemonic()),
+            Token::UnknownOp{mnemonic} => write!(f, "{}", mnemonic),
+            Token::Register{reg_num} => write!(f, "${}", reg_num),
+            Token::Number{value} => write!(f, "#{}", value),
+            // Debug keeps the point or exponent that marks this as a float
+            Token::Float{value} => write!(f, "#{:?}", value),
+            Token::LabelDeclaration{name} => write!(f, "{}:", name),
+            Token::LabelUsage{name} => write!(f, "@{}", name),
+            Token::Directive{name} => write!(f, ".{}", name),
+            // Single quotes unless the string has one in it, which only double quotes can hold
+            Token::IrString{literal} if literal.contains('\'') => write!(f, "\"{}\"", literal),
+            Token::IrString{literal} => write!(f, "'{}'", literal),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Symbol {
     name: String,
     symbol_type: SymbolType,
-    offset: u8
+    offset: Address
 }
 
 impl Symbol {
@@ -250,25 +458,29 @@ impl Symbol {
         }
     }
 
-    pub fn set_offset(&mut self, offset: u8) {
+    pub fn set_offset(&mut self, offset: Address) {
         self.offset = offset;
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum SymbolType {
     Label,
+    Constant,
 }
 
 #[derive(Debug)]
 pub struct SymbolTable {
-    symbols: Vec<Symbol>
+    symbols: Vec<Symbol>,
+    // Where each float literal was put in the read-only section, keyed by its bits
+    floats: Vec<(u64, Address)>
 }
 
 impl SymbolTable {
     pub fn new() -> SymbolTable {
         SymbolTable{
-            symbols: vec![]
+            symbols: vec![],
+            floats: vec![]
         }
     }
 
@@ -283,17 +495,54 @@ impl SymbolTable {
         }
     }
 
-    pub fn symbol_value(&self, s: &str) -> u8 {
-        for symbol in &self.symbols {
-            if symbol.name == s {
-                return symbol.offset;
-            }
-        }
-        println!("Symbol {} not found", s);
-        std::process::exit(1);
+    pub fn symbol_value(&self, s: &str) -> Option<Address> {
+        self.symbols.iter().find(|symbol| symbol.name == s).map(|symbol| symbol.offset)
+    }
+
+    pub fn symbol_type(&self, s: &str) -> Option<&SymbolType> {
+        self.symbols.iter().find(|symbol| symbol.name == s).map(|symbol| &symbol.symbol_type)
+    }
+
+    pub fn add_float(&mut self, value: f64, offset: Address) {
+        self.floats.push((value.to_bits(), offset));
+    }
+
+    pub fn float_offset(&self, value: f64) -> Option<Address> {
+        self.floats.iter().find(|(bits, _)| *bits == value.to_bits()).map(|(_, offset)| *offset)
+    }
+
+    /// The name of the code label at an address, if there is one
+    pub fn label_at(&self, offset: Address) -> Option<&str> {
+        self.symbols.iter()
+            .find(|s| s.symbol_type == SymbolType::Label && s.offset == offset)
+            .map(|s| s.name.as_str())
+    }
+
+    /// The closest code label at or before an address, which is usually the routine the address is in
+    pub fn label_before(&self, offset: Address) -> Option<&str> {
+        self.symbols.iter()
+            .filter(|s| s.symbol_type == SymbolType::Label && s.offset <= offset)
+            .max_by_key(|s| s.offset)
+            .map(|s| s.name.as_str())
+    }
+
+    /// Every code label along with its address
+    pub fn labels(&self) -> impl Iterator<Item = (&str, Address)> {
+        self.symbols_of_type(SymbolType::Label)
+    }
+
+    /// Every constant along with its offset into the read-only section
+    pub fn constants(&self) -> impl Iterator<Item = (&str, Address)> {
+        self.symbols_of_type(SymbolType::Constant)
+    }
+
+    fn symbols_of_type(&self, symbol_type: SymbolType) -> impl Iterator<Item = (&str, Address)> {
+        self.symbols.iter()
+            .filter(move |s| s.symbol_type == symbol_type)
+            .map(|s| (s.name.as_str(), s.offset))
     }
 
-    pub fn set_symbol_offset(&mut self, name: String, offset: u8) {
+    pub fn set_symbol_offset(&mut self, name: String, offset: Address) {
         let position: Option<usize> = self.symbols.iter().position(|s| s.name == name);
         match position {
             Some(index) => {
@@ -302,8 +551,16 @@ impl SymbolTable {
             None => {}
         }
     }
+
+    /// Moves every code label from an offset within the code to an absolute offset within the program
+    pub fn relocate_labels(&mut self, base: usize) {
+        for symbol in self.symbols.iter_mut().filter(|s| s.symbol_type == SymbolType::Label) {
+            symbol.offset += base as Address;
+        }
+    }
 }
 
+pub mod diagnostics;
 pub mod opcode_parsers;
 pub mod operand_parsers;
 pub mod register_parsers;
@@ -311,12 +568,15 @@ pub mod instruction_parsers;
 pub mod program_parsers;
 pub mod directive_parsers;
 pub mod label_parsers;
+pub mod source_map;
 
 #[cfg(test)]
 mod tests {
 
     use super::*;
-    use crate::vm::VM;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::vm::{ExitReason, SharedBuffer, VM};
     
     #[test]
     fn test_symbol_table() {
@@ -326,7 +586,8 @@ mod tests {
         sym.set_symbol_offset("test".to_string(), 12);
         assert_eq!(sym.symbols.len(), 1);
         let v = sym.symbol_value("test");
-        assert_eq!(v, 12);
+        assert_eq!(v, Some(12));
+        assert_eq!(sym.symbol_value("missing"), None);
     }
 
     #[test]
@@ -337,7 +598,235 @@ mod tests {
         println!("{:?}", program);
         let mut vm = VM::new();
         assert_eq!(program.len(), PIE_HEADER_LENGTH + 28);
-        vm.add_bytes(&mut program);
+        vm.add_bytes(&mut program).unwrap();
         assert_eq!(vm.program.len(), PIE_HEADER_LENGTH + 28);
     }
+
+    #[test]
+    fn test_label_offsets() {
+        let mut asm = Assembler::new();
+        let test_string = ".data\nhello: .asciiz 'Hi'\n.code\nload $0 #0\nload $1 #5\nloop: inc $0\nneq $0 $1\njmpe @loop\nhlt";
+        asm.assemble(test_string).unwrap();
+        assert_eq!(asm.symbols.symbol_value("hello"), Some(0));
+        // Header, then 'Hi' and its terminator, then two instructions
+        assert_eq!(asm.symbols.symbol_value("loop").unwrap() as usize, PIE_HEADER_LENGTH + 3 + 8);
+    }
+
+    #[test]
+    fn test_labels_past_u8() {
+        let mut asm = Assembler::new();
+        let mut test_string = ".code\nload $0 #0\n".to_string();
+        for _ in 0..100 {
+            test_string.push_str("inc $1\n");
+        }
+        test_string.push_str("loop: inc $0\nload $2 #3\neq $0 $2\njmpe @end\njmp @loop\nend: hlt");
+        let mut program = asm.assemble(&test_string).unwrap();
+        assert_eq!(asm.symbols.symbol_value("loop").unwrap() as usize, PIE_HEADER_LENGTH + 404);
+        let mut vm = VM::new();
+        vm.add_bytes(&mut program).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.registers[0], 3);
+        assert_eq!(vm.registers[1], 100);
+    }
+
+    #[test]
+    fn test_run_subroutine() {
+        let mut asm = Assembler::new();
+        let test_string = ".code\nload $0 #2\ncall @double\ncall @double\nhlt\ndouble: push $1\nadd $0 $0 $0\npop $1\nret";
+        let mut program = asm.assemble(test_string).unwrap();
+        let mut vm = VM::new();
+        vm.add_bytes(&mut program).unwrap();
+        assert_eq!(vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(vm.registers[0], 8);
+    }
+
+    #[test]
+    fn test_run_heap_access() {
+        let mut asm = Assembler::new();
+        let test_string = ".code\nload $0 #8\naloc $0\nload $1 #500\nload $2 #0\nsw $1 $2 #4\nlw $3 $2 #4\nlb $4 $2 #5\nhlt";
+        let mut program = asm.assemble(test_string).unwrap();
+        let mut vm = VM::new();
+        vm.add_bytes(&mut program).unwrap();
+        assert_eq!(vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(vm.registers[3], 500);
+        assert_eq!(vm.registers[4], 1);
+    }
+
+    #[test]
+    fn test_run_prts() {
+        let mut asm = Assembler::new();
+        let test_string = ".data\nhello: .asciiz 'Hello'\nworld: .asciiz 'World'\n.code\nprts @world\nprts @hello\nhlt";
+        let mut program = asm.assemble(test_string).unwrap();
+        assert_eq!(&program[PIE_HEADER_LENGTH..PIE_HEADER_LENGTH + 12], b"Hello\0World\0");
+        let mut vm = VM::new();
+        let output = Rc::new(RefCell::new(vec![]));
+        vm.set_output(Box::new(SharedBuffer(output.clone())));
+        vm.add_bytes(&mut program).unwrap();
+        assert_eq!(vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(*output.borrow(), b"WorldHello".to_vec());
+    }
+
+    #[test]
+    fn test_label_out_of_range() {
+        let mut asm = Assembler::new();
+        let mut test_string = ".code\nload $0 @end\n".to_string();
+        for _ in 0..20000 {
+            test_string.push_str("inc $1\n");
+        }
+        test_string.push_str("end: hlt");
+        let errors = asm.assemble(&test_string).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        match &errors[0] {
+            AssemblerError::AddressOutOfRange { name, width, location, .. } => {
+                assert_eq!(name, "end");
+                assert_eq!(*width, 2);
+                assert_eq!((location.line, location.column), (2, 1));
+            },
+            e => panic!("Unexpected error {:?}", e)
+        }
+    }
+
+    #[test]
+    fn test_error_locations() {
+        let mut asm = Assembler::new();
+        asm.set_file_name("loop.iasm");
+        let errors = asm.assemble(".code\nloop: inc $0\n  loop: hlt\n").unwrap_err();
+        let location = errors[0].location();
+        assert_eq!((location.file.as_str(), location.line, location.column, location.length), ("loop.iasm", 3, 3, 9));
+        assert_eq!(errors[0], AssemblerError::SymbolAlreadyDeclared { name: "loop".to_string(), location: location.clone() });
+    }
+
+    #[test]
+    fn test_register_too_big_to_parse() {
+        let mut asm = Assembler::new();
+        let errors = asm.assemble(".code\nload $300 #1\nhlt\n").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        match &errors[0] {
+            AssemblerError::ParseError { location, .. } => { assert_eq!(location.line, 2); },
+            e => panic!("Unexpected error {:?}", e)
+        }
+    }
+
+    #[test]
+    fn test_nothing_to_assemble() {
+        let mut asm = Assembler::new();
+        let errors = asm.assemble("; just a comment\n").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].to_string(), "unable to parse: expected an instruction or directive");
+        assert_eq!(errors[0].location().line, 2);
+    }
+
+    #[test]
+    fn test_collects_every_error() {
+        let mut asm = Assembler::new();
+        let errors = asm.assemble(".code\nloop: jmp @nowhere\nloop: hlt\n.bogus\ncall @missing\n").unwrap_err();
+        let found: Vec<String> = errors.iter().map(|e| format!("{}:{}", e.location().line, e)).collect();
+        assert_eq!(found, vec![
+            "3:symbol `loop` is already declared",
+            "4:unknown directive `.bogus`",
+            "2:symbol `nowhere` is not declared",
+            "5:symbol `missing` is not declared",
+        ]);
+    }
+
+    #[test]
+    fn test_operand_mismatch_location() {
+        let mut asm = Assembler::new();
+        let errors = asm.assemble(".code\nload $0 #1\nadd $0 #5\nhlt\n").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        match &errors[0] {
+            AssemblerError::WrongOperandCount { mnemonic, location, .. } => {
+                assert_eq!(mnemonic, "add");
+                assert_eq!((location.line, location.column), (3, 1));
+            },
+            e => panic!("Unexpected error {:?}", e)
+        }
+    }
+
+    #[test]
+    fn test_unknown_mnemonic_location() {
+        let mut asm = Assembler::new();
+        let errors = asm.assemble(".code\nfoo\nloop:  LAOD $1 #1\nhlt\n").unwrap_err();
+        let found: Vec<(String, usize, usize, usize)> = errors.iter()
+            .map(|e| (e.to_string(), e.location().line, e.location().column, e.location().length))
+            .collect();
+        assert_eq!(found, vec![
+            ("unknown instruction `foo`".to_string(), 2, 1, 3),
+            ("unknown instruction `laod`".to_string(), 3, 8, 4),
+        ]);
+        // `igl` is still an instruction, for anyone who wants one
+        assert!(Assembler::new().assemble(".code\nigl\n").is_ok());
+    }
+
+    #[test]
+    fn test_run_floats() {
+        let mut asm = Assembler::new();
+        let test_string = ".data\nhello: .asciiz 'Hi'\n.code\nloadf $0 #1.5\nloadf $1 #0.25\nloadf $2 #1.5\naddf $0 $1 $3\nftoi $3 $4\nhlt";
+        let mut program = asm.assemble(test_string).unwrap();
+        // The string, then each distinct float once
+        assert_eq!(asm.ro.len(), 3 + 16);
+        let mut vm = VM::new();
+        vm.add_bytes(&mut program).unwrap();
+        assert_eq!(vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(vm.float_registers[2], 1.5);
+        assert_eq!(vm.float_registers[3], 1.75);
+        assert_eq!(vm.registers[4], 1);
+    }
+
+    #[test]
+    fn test_run_wide_loads() {
+        let mut asm = Assembler::new();
+        let test_string = ".code\nload $0 #-1\nload $1 #100000\nload $2 #'A'\nload $3 #0b101\nload $5 #0\nloop: inc $5\ndjmp @end\nend: hlt";
+        let mut program = asm.assemble(test_string).unwrap();
+        // The two wide loads take up two instructions each
+        assert_eq!(asm.symbols.symbol_value("loop").unwrap() as usize, PIE_HEADER_LENGTH + 7 * INSTRUCTION_LENGTH);
+        let mut vm = VM::new();
+        vm.add_bytes(&mut program).unwrap();
+        assert_eq!(vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(&vm.registers[0..4], &[-1, 100000, 65, 5]);
+    }
+
+    #[test]
+    fn test_run_while_loop() {
+        let mut asm = Assembler::new();
+        let test_string = ".code\nload $0 #0\nload $1 #5\nload $2 #0\ntop: gte $0 $1\njmpe @done\ninc $0\nadd $2 $0 $2\njmpb @top\ndone: lt $2 $1\njlt @never\nhlt\nnever: inc $9";
+        let mut program = asm.assemble(test_string).unwrap();
+        let mut vm = VM::new();
+        vm.add_bytes(&mut program).unwrap();
+        assert_eq!(vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(vm.registers[2], 15);
+        assert_eq!(vm.registers[9], 0);
+    }
+
+    #[test]
+    fn test_relative_jump_wrong_direction() {
+        let mut asm = Assembler::new();
+        let errors = asm.assemble(".code\ntop: hlt\njmpf @top\n").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].to_string(), "`jmpf` can't reach `@top`, which is the other way");
+        assert_eq!(errors[0].location().line, 3);
+    }
+
+    #[test]
+    fn test_trailing_garbage() {
+        let mut asm = Assembler::new();
+        let errors = asm.assemble(".code\nhlt\n!!! nonsense\n").unwrap_err();
+        match &errors[0] {
+            AssemblerError::ParseError { location, .. } => {
+                assert_eq!((location.line, location.column, location.length), (3, 1, 12));
+            },
+            e => panic!("Unexpected error {:?}", e)
+        }
+    }
+
+    #[test]
+    fn test_run_label_loop() {
+        let mut asm = Assembler::new();
+        let test_string = ".data\nhello: .asciiz 'Hi'\n.code\nload $0 #0\nload $1 #5\nloop: inc $0\nneq $0 $1\njmpe @loop\nhlt";
+        let mut program = asm.assemble(test_string).unwrap();
+        let mut vm = VM::new();
+        vm.add_bytes(&mut program).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.registers[0], 5);
+    }
 }
\ No newline at end of file
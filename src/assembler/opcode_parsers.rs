@@ -8,13 +8,19 @@ named!(pub opcode<CompleteStr, Token>,
       opcode: alpha1 >>
       (
         {
-            Token::Op{code: Opcode::from(opcode)}
+            // Mnemonics can be written in any case. Ones that aren't known are kept, so the assembler can say which
+            let mnemonic = opcode.to_lowercase();
+            match Opcode::from_mnemonic(&mnemonic) {
+                Some(code) => Token::Op{code},
+                None => Token::UnknownOp{mnemonic},
+            }
         }
       )
   )
 );
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::opcode;
     use super::CompleteStr;
@@ -30,9 +36,14 @@ mod tests {
         assert_eq!(token, Token::Op { code: Opcode::LOAD });
         assert_eq!(rest, CompleteStr(""));
 
+        let (_, token) = opcode(CompleteStr("LoadF")).unwrap();
+        assert_eq!(token, Token::Op { code: Opcode::LOADF });
+
         // Tests that an invalid opcode isn't recognized
         let result = opcode(CompleteStr("aold"));
         let (_, token) = result.unwrap();
+        assert_eq!(token, Token::UnknownOp { mnemonic: "aold".to_string() });
+        let (_, token) = opcode(CompleteStr("igl")).unwrap();
         assert_eq!(token, Token::Op { code: Opcode::IGL });
     }
 }
@@ -1,19 +1,108 @@
+#![allow(clippy::bool_assert_comparison)]
+
 use nom::types::CompleteStr;
-use nom::digit;
+use nom::{anychar, digit, hex_digit, one_of};
 
 use crate::assembler::Token;
 use crate::assembler::register_parsers::register;
 use crate::assembler::label_parsers::label_usage;
 
-// Parser for integer numbers, which we preface with `#` in our assembly language:
-// #100
+// Parser for integer numbers, which we preface with `#` in our assembly language. They can be negative, hex, binary
+// or a character:
+// #100, #-1, #0xFF, #0b1010, #'a'
 named!(pub integer_operand<CompleteStr, Token>,
     ws!(
         do_parse!(
             tag!("#") >>
-            reg_num: digit >>
+            value: alt!(character | signed_integer) >>
             (
-                Token::Number{value: reg_num.parse::<i32>().unwrap()}
+                Token::Number{value}
+            )
+        )
+    )
+);
+
+named!(signed_integer<CompleteStr, i64>,
+    do_parse!(
+        sign: opt!(tag!("-")) >>
+        magnitude: alt!(hex | binary | decimal) >>
+        (
+            if sign.is_some() { -magnitude } else { magnitude }
+        )
+    )
+);
+
+named!(hex<CompleteStr, i64>,
+    do_parse!(
+        tag_no_case!("0x") >>
+        digits: hex_digit >>
+        (parse_magnitude(digits, 16))
+    )
+);
+
+named!(binary<CompleteStr, i64>,
+    do_parse!(
+        tag_no_case!("0b") >>
+        digits: is_a!("01") >>
+        (parse_magnitude(digits, 2))
+    )
+);
+
+named!(decimal<CompleteStr, i64>,
+    map!(digit, |digits| parse_magnitude(digits, 10))
+);
+
+named!(character<CompleteStr, i64>,
+    do_parse!(
+        tag!("'") >>
+        c: anychar >>
+        tag!("'") >>
+        (c as i64)
+    )
+);
+
+// Anything too long for an i64 is far out of range for every operand, so the assembler reports it as such instead
+// of the parser failing on it
+fn parse_magnitude(digits: CompleteStr, radix: u32) -> i64 {
+    i64::from_str_radix(&digits, radix).unwrap_or(i64::MAX)
+}
+
+// The digits of a float, which need either a decimal point or an exponent to tell them apart from an integer:
+// 3.14, 2.5e-3, 1e10
+named!(float_literal<CompleteStr, CompleteStr>,
+    recognize!(
+        do_parse!(
+            opt!(tag!("-")) >>
+            digit >>
+            alt!(
+                do_parse!(tag!(".") >> digit >> opt!(complete!(exponent)) >> ()) |
+                do_parse!(exponent >> ())
+            ) >>
+            ()
+        )
+    )
+);
+
+named!(exponent<CompleteStr, CompleteStr>,
+    recognize!(
+        do_parse!(
+            one_of!("eE") >>
+            opt!(one_of!("+-")) >>
+            digit >>
+            ()
+        )
+    )
+);
+
+// Parser for float numbers, which are prefaced with `#` like integers:
+// #12.75
+named!(pub float_operand<CompleteStr, Token>,
+    ws!(
+        do_parse!(
+            tag!("#") >>
+            literal: float_literal >>
+            (
+                Token::Float{value: literal.parse::<f64>().unwrap()}
             )
         )
     )
@@ -21,6 +110,7 @@ named!(pub integer_operand<CompleteStr, Token>,
 
 named!(pub operand<CompleteStr, Token>,
     alt!(
+        float_operand |
         integer_operand |
         register |
         label_usage |
@@ -28,14 +118,15 @@ named!(pub operand<CompleteStr, Token>,
     )
 );
 
+// Strings can be quoted either way, so one with an apostrophe in it can be written between double quotes:
+// 'Hello' or "it's"
 named!(irstring<CompleteStr, Token>,
-    do_parse!(
-        tag!("'") >>
-        content: take_until!("'") >>
-        tag!("'") >>
-        (
-            Token::IrString{ literal: content.to_string() }
-        )
+    map!(
+        alt!(
+            delimited!(tag!("'"), take_until!("'"), tag!("'")) |
+            delimited!(tag!("\""), take_until!("\""), tag!("\""))
+        ),
+        |content| Token::IrString{ literal: content.to_string() }
     )
 );
 
@@ -50,13 +141,35 @@ fn test_parse_integer_operand() {
     assert_eq!(rest, CompleteStr(""));
     assert_eq!(value, Token::Number{value: 10});
 
+    // Negative, hex, binary and character literals
+    assert_eq!(integer_operand(CompleteStr("#-1")), Ok((CompleteStr(""), Token::Number{value: -1})));
+    assert_eq!(integer_operand(CompleteStr("#0xFf")), Ok((CompleteStr(""), Token::Number{value: 255})));
+    assert_eq!(integer_operand(CompleteStr("#-0x10")), Ok((CompleteStr(""), Token::Number{value: -16})));
+    assert_eq!(integer_operand(CompleteStr("#0b1010")), Ok((CompleteStr(""), Token::Number{value: 10})));
+    assert_eq!(integer_operand(CompleteStr("#'a'")), Ok((CompleteStr(""), Token::Number{value: 97})));
+    assert_eq!(integer_operand(CompleteStr("#99999999999999999999")), Ok((CompleteStr(""), Token::Number{value: i64::MAX})));
+
     // Test an invalid one (missing the #)
     let result = integer_operand(CompleteStr("10"));
     assert_eq!(result.is_ok(), false);
 }
 
+#[test]
+fn test_parse_float_operand() {
+    assert_eq!(float_operand(CompleteStr("#12.75")), Ok((CompleteStr(""), Token::Float{value: 12.75})));
+    assert_eq!(float_operand(CompleteStr("#2.5e-3")), Ok((CompleteStr(""), Token::Float{value: 0.0025})));
+    assert_eq!(float_operand(CompleteStr("#1e10")), Ok((CompleteStr(""), Token::Float{value: 1e10})));
+    assert_eq!(float_operand(CompleteStr("#-0.5")), Ok((CompleteStr(""), Token::Float{value: -0.5})));
+    // Without a point or an exponent it's an integer
+    assert_eq!(float_operand(CompleteStr("#3")).is_ok(), false);
+    assert_eq!(operand(CompleteStr("#3")), Ok((CompleteStr(""), Token::Number{value: 3})));
+}
+
 #[test]
 fn test_parse_string_operand() {
     let result = irstring(CompleteStr("'This is a test'"));
     assert_eq!(result.is_ok(), true);
+    let result = irstring(CompleteStr("\"it's a test\""));
+    assert_eq!(result, Ok((CompleteStr(""), Token::IrString{ literal: "it's a test".to_string() })));
+    assert_eq!(irstring(CompleteStr("\"unterminated'")).is_ok(), false);
 }
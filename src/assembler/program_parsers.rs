@@ -1,31 +1,71 @@
+#![allow(clippy::bool_assert_comparison)]
+
 use nom::types::CompleteStr;
+use nom::IResult;
 
+use crate::assembler::diagnostics::{LineIndex, SourceLocation};
 use crate::assembler::instruction_parsers::{AssemblerInstruction, instruction};
-use crate::assembler::SymbolTable;
+use crate::assembler::{Address, AssemblerError, SymbolTable};
 
 #[derive(Debug, PartialEq)]
 pub struct Program {
-    pub instructions: Vec<AssemblerInstruction>
+    pub instructions: Vec<AssemblerInstruction>,
+    // Where each instruction came from, in the same order
+    pub locations: Vec<SourceLocation>,
+    // Where each comment is, from its `;` to the end of the line. The assembler ignores them, but anything that writes
+    // the source back out needs them
+    pub comments: Vec<SourceLocation>
+}
+
+// Something found in the input, as how much input was left when it started and how long it was. A parser only knows
+// how much input is left, but `program` has the whole input and can turn these into real locations
+type Span = (usize, usize);
+
+/// Skips whitespace and comments, which run from a `;` to the end of the line, remembering where each comment was
+pub fn trivia(input: CompleteStr) -> (CompleteStr, Vec<Span>) {
+    let mut rest = input.0.trim_start();
+    let mut comments = vec![];
+    while rest.starts_with(';') {
+        let length = rest.find('\n').unwrap_or(rest.len());
+        comments.push((rest.len(), rest[..length].trim_end().len()));
+        rest = rest[length..].trim_start();
+    }
+    (CompleteStr(rest), comments)
 }
 
-named!(pub program<CompleteStr, Program>,
-    do_parse!(
-        instructions: many1!(instruction) >>
-        (
-            Program {
-                instructions: instructions
-            }
-        )
-    )
-);
+// Parses an instruction, remembering where it was along with any comments before it
+fn located_instruction(input: CompleteStr) -> IResult<CompleteStr, (Vec<Span>, Span, AssemblerInstruction)> {
+    let (trimmed, comments) = trivia(input);
+    let (rest, instruction) = instruction(trimmed)?;
+    let length = trimmed[..trimmed.len() - rest.len()].trim_end().len();
+    Ok((rest, (comments, (trimmed.len(), length), instruction)))
+}
+
+pub fn program(input: CompleteStr) -> IResult<CompleteStr, Program> {
+    let (rest, located) = many1!(input, located_instruction)?;
+    // Comments after the last instruction aren't before anything, so they have to be picked up separately
+    let (rest, trailing) = trivia(rest);
+    let lines = LineIndex::new(&input);
+    let mut instructions = vec![];
+    let mut locations: Vec<SourceLocation> = vec![];
+    let mut comments: Vec<SourceLocation> = vec![];
+    for (before, (remaining, length), instruction) in located {
+        comments.extend(before.iter().map(|(remaining, length)| lines.location(input.len() - remaining, *length)));
+        locations.push(lines.location(input.len() - remaining, length));
+        instructions.push(instruction);
+    }
+    comments.extend(trailing.iter().map(|(remaining, length)| lines.location(input.len() - remaining, *length)));
+    Ok((rest, Program { instructions, locations, comments }))
+}
 
 impl Program {
-    pub fn to_bytes(&self, symbols: &SymbolTable) -> Vec<u8> {
+    /// Assembles every instruction, with the first one at `address` in the program
+    pub fn to_bytes(&self, symbols: &SymbolTable, address: Address) -> Result<Vec<u8>, AssemblerError> {
         let mut program = vec![];
         for instruction in &self.instructions {
-            program.append(&mut instruction.to_bytes(symbols));
+            program.append(&mut instruction.to_bytes(symbols, address + program.len() as Address)?);
         }
-        program
+        Ok(program)
     }
 }
 
@@ -35,7 +75,7 @@ fn test_program_to_bytes() {
     assert_eq!(result.is_ok(), true);
     let (_, program) = result.unwrap();
     let symbols = SymbolTable::new();
-    let bytecode = program.to_bytes(&symbols);
+    let bytecode = program.to_bytes(&symbols, 0).unwrap();
     assert_eq!(bytecode.len(), 4);
     println!("{:?}", bytecode);
 }
@@ -53,6 +93,27 @@ fn test_parse_program() {
     // TODO: Figure out an ergonomic way to test the AssemblerInstruction returned
 }
 
+#[test]
+fn test_program_locations() {
+    let (_, p) = program(CompleteStr(".code\n  load $0 #100\nhlt\n")).unwrap();
+    assert_eq!(p.locations.len(), 3);
+    assert_eq!((p.locations[1].line, p.locations[1].column, p.locations[1].length), (2, 3, 12));
+    assert_eq!((p.locations[2].line, p.locations[2].column, p.locations[2].length), (3, 1, 3));
+}
+
+#[test]
+fn test_program_comments() {
+    let (rest, p) = program(CompleteStr("; Counts up\n.code\nload $0 #1 ; start at one\n;; and then\nhlt\n; done\n")).unwrap();
+    assert_eq!(rest, CompleteStr(""));
+    assert_eq!(p.instructions.len(), 3);
+    let comments: Vec<(usize, usize, usize)> = p.comments.iter().map(|c| (c.line, c.column, c.length)).collect();
+    assert_eq!(comments, vec![(1, 1, 11), (3, 12, 14), (4, 1, 11), (6, 1, 6)]);
+    // A `;` in a string or character isn't a comment
+    let (_, p) = program(CompleteStr("hi: .asciiz 'a;b'\nload $0 #';'")).unwrap();
+    assert_eq!(p.instructions[0].get_string_constant(), Some("a;b".to_string()));
+    assert!(p.comments.is_empty());
+}
+
 #[test]
 fn test_complete_program() {
     let test_program = CompleteStr(".data\nhello: .asciiz 'Hello everyone!'\n.code\nhlt");
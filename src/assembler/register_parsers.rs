@@ -1,16 +1,19 @@
+#![allow(clippy::bool_assert_comparison)]
+
 use nom::types::CompleteStr;
 use nom::digit;
 
 use crate::assembler::Token;
 
+// Register numbers that don't fit in a byte aren't registers at all, so they fail to parse rather than wrapping
 named!(pub register <CompleteStr, Token>,
     ws!(
         do_parse!(
             tag!("$") >>
-            reg_num: digit >>
+            reg_num: map_res!(digit, |digits: CompleteStr| digits.parse::<u8>()) >>
             (
                 Token::Register{
-                  reg_num: reg_num.parse::<u8>().unwrap()
+                  reg_num
                 }
             )
         )
@@ -27,4 +30,6 @@ fn test_parse_register() {
     assert_eq!(result.is_ok(), false);
     let result = register(CompleteStr("$a"));
     assert_eq!(result.is_ok(), false);
+    let result = register(CompleteStr("$300"));
+    assert_eq!(result.is_ok(), false);
 }
@@ -0,0 +1,82 @@
+use crate::assembler::diagnostics::SourceLocation;
+use crate::assembler::Address;
+
+/// Remembers which line of source every instruction in an assembled program came from
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct SourceMap {
+    // Sorted by address. A line that assembles to more than one instruction, like a wide load, only has an entry for
+    // the first of them
+    entries: Vec<(Address, SourceLocation)>,
+    // Where the code ends, so the last line doesn't take in everything after it
+    end: Address,
+}
+
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap { entries: vec![], end: 0 }
+    }
+
+    /// Records that the instructions from `address` up to the next entry came from `location`. Entries have to be
+    /// added in address order
+    pub fn add(&mut self, address: Address, location: SourceLocation) {
+        self.entries.push((address, location));
+    }
+
+    pub fn entries(&self) -> &[(Address, SourceLocation)] {
+        &self.entries
+    }
+
+    /// Records where the last instruction ends
+    pub fn set_end(&mut self, end: Address) {
+        self.end = end;
+    }
+
+    pub fn end(&self) -> Address {
+        self.end
+    }
+
+    /// Where the instruction at an address came from. Addresses outside the code have no location
+    pub fn location(&self, address: Address) -> Option<&SourceLocation> {
+        if address >= self.end {
+            return None;
+        }
+        let index = match self.entries.binary_search_by_key(&address, |(a, _)| *a) {
+            Ok(index) => index,
+            Err(0) => { return None; },
+            Err(next) => next - 1,
+        };
+        Some(&self.entries[index].1)
+    }
+
+    /// The first instruction on a line, or on the closest line after it that has one, along with the line it's really
+    /// on. This is where a debugger puts a breakpoint someone asked for on a line with no code
+    pub fn address_for_line(&self, line: usize) -> Option<(Address, usize)> {
+        self.entries.iter()
+            .filter(|(_, location)| location.line >= line)
+            .min_by_key(|(address, location)| (location.line, *address))
+            .map(|(address, location)| (*address, location.line))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{Assembler, PIE_HEADER_LENGTH};
+
+    #[test]
+    fn test_source_map() {
+        let mut asm = Assembler::new();
+        asm.assemble(".code\nload $0 #100000\n\nloop: inc $0\njmp @loop").unwrap();
+        let code = PIE_HEADER_LENGTH as Address;
+        let lines: Vec<(Address, usize)> = asm.source_map.entries().iter().map(|(a, l)| (*a, l.line)).collect();
+        assert_eq!(lines, vec![(code, 2), (code + 8, 4), (code + 12, 5)]);
+
+        // The second half of the wide load belongs to the same line
+        assert_eq!(asm.source_map.location(code + 4).map(|l| l.line), Some(2));
+        assert_eq!(asm.source_map.location(code - 1), None);
+        assert_eq!(asm.source_map.location(code + 15).map(|l| l.line), Some(5));
+        assert_eq!(asm.source_map.location(code + 16), None);
+        assert_eq!(asm.source_map.address_for_line(3), Some((code + 8, 4)));
+        assert_eq!(asm.source_map.address_for_line(6), None);
+    }
+}
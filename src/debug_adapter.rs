@@ -0,0 +1,486 @@
+use std::cell::RefCell;
+use std::io;
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+use serde_json::{json, Value};
+
+use crate::assembler::diagnostics;
+use crate::assembler::source_map::SourceMap;
+use crate::assembler::{Address, Assembler, INSTRUCTION_LENGTH};
+use crate::debugger::{Debugger, Stop};
+use crate::vm::{VmError, VM};
+
+/// The only thread a VM has, as far as the client is concerned
+const THREAD_ID: i64 = 1;
+
+// What `variables` requests ask for to list each scope
+const REGISTERS_REFERENCE: i64 = 1;
+const FLOAT_REGISTERS_REFERENCE: i64 = 2;
+const STACK_REFERENCE: i64 = 3;
+const HEAP_REFERENCE: i64 = 4;
+
+/// How many bytes of the heap are shown on each line of the heap view
+const HEAP_ROW_LENGTH: usize = 16;
+
+/// Collects what the program prints, so it can be sent on as output events instead of getting mixed up with the
+/// protocol on stdout
+struct CapturedOutput(Rc<RefCell<Vec<u8>>>);
+
+impl Write for CapturedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads one message, which is a `Content-Length` header, a blank line and then that many bytes of JSON. Nothing is
+/// returned once the client hangs up
+pub fn read_message(input: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let length = length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "message has no Content-Length"))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body).map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+pub fn write_message(output: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+/// Lets an editor debug a `.iasm` file through the Debug Adapter Protocol. The program runs on the same thread that
+/// reads requests, so a program that never stops can't be paused
+pub struct DebugAdapter {
+    vm: VM,
+    debugger: Debugger,
+    source_map: SourceMap,
+    source_path: String,
+    program_output: Rc<RefCell<Vec<u8>>>,
+    stop_on_entry: bool,
+    // Sequence number of the last message sent
+    seq: i64,
+    // Waiting to be sent, in order
+    outgoing: Vec<Value>,
+    // Events raised while handling a request, which go out after its response
+    events: Vec<Value>,
+    disconnected: bool,
+}
+
+impl Default for DebugAdapter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DebugAdapter {
+    pub fn new() -> DebugAdapter {
+        DebugAdapter {
+            vm: VM::new(),
+            debugger: Debugger::new(),
+            source_map: SourceMap::new(),
+            source_path: String::new(),
+            program_output: Rc::new(RefCell::new(vec![])),
+            stop_on_entry: false,
+            seq: 0,
+            outgoing: vec![],
+            events: vec![],
+            disconnected: false,
+        }
+    }
+
+    /// Handles requests until the client disconnects or hangs up
+    pub fn serve(&mut self, mut input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        while !self.disconnected {
+            let request = match read_message(&mut input)? {
+                Some(request) => request,
+                None => { break; }
+            };
+            debug!("dap: {}", request);
+            self.handle(&request);
+            for message in self.outgoing.drain(..) {
+                write_message(&mut output, &message)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Answers a request, queueing the response and then any events it caused
+    pub fn handle(&mut self, request: &Value) {
+        let command = request["command"].as_str().unwrap_or("");
+        let arguments = &request["arguments"];
+        let result = match command {
+            "initialize" => Ok(json!({ "supportsConfigurationDoneRequest": true })),
+            "launch" => self.launch(arguments),
+            "setBreakpoints" => Ok(self.set_breakpoints(arguments)),
+            "configurationDone" => {
+                if self.stop_on_entry {
+                    self.stopped("entry", None);
+                } else {
+                    self.resume(Debugger::continue_execution, "breakpoint");
+                }
+                Ok(Value::Null)
+            },
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] })),
+            "continue" => {
+                self.resume(Debugger::continue_execution, "breakpoint");
+                Ok(json!({ "allThreadsContinued": true }))
+            },
+            "next" => {
+                self.resume(Debugger::step_over, "step");
+                Ok(Value::Null)
+            },
+            "stepIn" => {
+                self.resume(Debugger::step, "step");
+                Ok(Value::Null)
+            },
+            "stepOut" => {
+                self.resume(Debugger::step_out, "step");
+                Ok(Value::Null)
+            },
+            // Nothing runs between requests, so the program is always already paused
+            "pause" => {
+                self.stopped("pause", None);
+                Ok(Value::Null)
+            },
+            "stackTrace" => Ok(self.stack_trace()),
+            "scopes" => Ok(self.scopes()),
+            "variables" => self.variables(arguments),
+            "disconnect" => {
+                self.disconnected = true;
+                Ok(Value::Null)
+            },
+            _ => Err(format!("{} is not supported", command)),
+        };
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": command,
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(Value::Null) => {},
+            Ok(body) => { response["body"] = body; },
+            Err(message) => { response["message"] = json!(message); }
+        }
+        self.send(response);
+        let events: Vec<Value> = self.events.drain(..).collect();
+        for event in events {
+            self.send(event);
+        }
+    }
+
+    /// Everything waiting to go to the client
+    pub fn take_outgoing(&mut self) -> Vec<Value> {
+        self.outgoing.drain(..).collect()
+    }
+
+    fn send(&mut self, mut message: Value) {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        self.outgoing.push(message);
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.events.push(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    fn launch(&mut self, arguments: &Value) -> Result<Value, String> {
+        let path = arguments["program"].as_str().ok_or_else(|| "launch needs the program to debug".to_string())?;
+        let source = std::fs::read_to_string(path).map_err(|e| format!("Unable to read {}: {}", path, e))?;
+        self.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+        self.load(path, &source)?;
+        Ok(Value::Null)
+    }
+
+    /// Assembles a program and gets it ready to run from its entry point. Configuration can start once it's loaded
+    fn load(&mut self, path: &str, source: &str) -> Result<(), String> {
+        let mut asm = Assembler::new();
+        asm.set_file_name(path);
+        let mut image = match asm.assemble(source) {
+            Ok(image) => image,
+            Err(errors) => {
+                let rendered: String = errors.iter().map(|error| diagnostics::render(error, source)).collect();
+                self.event("output", json!({ "category": "stderr", "output": rendered }));
+                return Err(format!("{} has {} error(s)", path, errors.len()));
+            }
+        };
+        let mut vm = VM::new();
+        vm.add_bytes(&mut image).map_err(|e| format!("Unable to load program: {}", e))?;
+        vm.restart();
+        vm.set_output(Box::new(CapturedOutput(self.program_output.clone())));
+        self.vm = vm;
+        self.debugger.set_symbols(asm.symbols);
+        self.source_map = asm.source_map;
+        self.source_path = path.to_string();
+        self.event("initialized", json!({}));
+        Ok(())
+    }
+
+    fn set_breakpoints(&mut self, arguments: &Value) -> Value {
+        self.debugger.clear_breakpoints();
+        let path = arguments["source"]["path"].as_str().unwrap_or("");
+        let same_file = same_file(path, &self.source_path);
+        let requested = arguments["breakpoints"].as_array().cloned().unwrap_or_default();
+        let mut breakpoints = vec![];
+        for breakpoint in requested {
+            let line = breakpoint["line"].as_u64().unwrap_or(0) as usize;
+            match self.source_map.address_for_line(line).filter(|_| same_file) {
+                Some((address, line)) => {
+                    self.debugger.add_breakpoint_at(address as usize);
+                    breakpoints.push(json!({ "verified": true, "line": line }));
+                },
+                None => {
+                    breakpoints.push(json!({ "verified": false, "line": line, "message": "No code at or after this line" }));
+                }
+            }
+        }
+        json!({ "breakpoints": breakpoints })
+    }
+
+    /// Runs the program with one of the debugger's ways of running and tells the client where it ended up. `reason`
+    /// is what to call an ordinary stop
+    fn resume(&mut self, run: fn(&mut Debugger, &mut VM) -> Result<Stop, VmError>, reason: &str) {
+        let result = run(&mut self.debugger, &mut self.vm);
+        let printed: Vec<u8> = self.program_output.borrow_mut().drain(..).collect();
+        if !printed.is_empty() {
+            self.event("output", json!({ "category": "stdout", "output": String::from_utf8_lossy(&printed) }));
+        }
+        match result {
+            Ok(Stop::Stepped) => { self.stopped(reason, None); },
+            Ok(Stop::Breakpoint{..}) => { self.stopped("breakpoint", None); },
+            Ok(Stop::Watchpoint{watchpoint, old, new}) => {
+                self.stopped("data breakpoint", Some(format!("{} changed from {} to {}", watchpoint, old, new)));
+            },
+            Ok(Stop::Exited{..}) => {
+                self.event("exited", json!({ "exitCode": 0 }));
+                self.event("terminated", json!({}));
+            },
+            Err(e) => {
+                // Show the instruction that trapped rather than the one after it
+                self.vm.set_pc(e.pc);
+                self.stopped("exception", Some(e.to_string()));
+            }
+        }
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<String>) {
+        let mut body = json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true });
+        if let Some(text) = text {
+            body["text"] = json!(text);
+            body["description"] = json!(text);
+        }
+        self.event("stopped", body);
+    }
+
+    /// A frame for where the VM is, then one for each CALL it's inside
+    fn stack_trace(&self) -> Value {
+        let mut addresses = vec![self.vm.pc()];
+        addresses.extend(self.vm.return_addresses().iter().map(|address| address - INSTRUCTION_LENGTH));
+        let frames: Vec<Value> = addresses.iter().enumerate().map(|(id, address)| {
+            let name = self.debugger.label_before(*address).unwrap_or("<entry>");
+            let mut frame = json!({
+                "id": id,
+                "name": name,
+                "line": 0,
+                "column": 0,
+                "instructionPointerReference": address.to_string(),
+            });
+            if let Some(location) = self.source_map.location(*address as Address) {
+                frame["source"] = self.source();
+                frame["line"] = json!(location.line);
+                frame["column"] = json!(location.column);
+            }
+            frame
+        }).collect();
+        json!({ "stackFrames": frames, "totalFrames": addresses.len() })
+    }
+
+    fn source(&self) -> Value {
+        let name = Path::new(&self.source_path).file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        json!({ "name": name, "path": self.source_path })
+    }
+
+    /// Every frame sees the same registers and memory, so the scopes don't depend on which was asked about
+    fn scopes(&self) -> Value {
+        let heap_rows = self.vm.heap().len().div_ceil(HEAP_ROW_LENGTH);
+        json!({ "scopes": [
+            { "name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false },
+            { "name": "Float Registers", "variablesReference": FLOAT_REGISTERS_REFERENCE, "expensive": false },
+            { "name": "Stack", "variablesReference": STACK_REFERENCE, "indexedVariables": self.vm.stack().len(), "expensive": false },
+            { "name": "Heap", "variablesReference": HEAP_REFERENCE, "indexedVariables": heap_rows, "expensive": true },
+        ]})
+    }
+
+    fn variables(&self, arguments: &Value) -> Result<Value, String> {
+        let variable = |name: String, value: String| json!({ "name": name, "value": value, "variablesReference": 0 });
+        let mut variables: Vec<Value> = match arguments["variablesReference"].as_i64() {
+            Some(REGISTERS_REFERENCE) => {
+                let mut registers: Vec<Value> = self.vm.registers.iter().enumerate()
+                    .map(|(register, value)| variable(format!("${}", register), value.to_string()))
+                    .collect();
+                registers.push(variable("pc".to_string(), self.vm.pc().to_string()));
+                registers.push(variable("flags".to_string(), self.vm.flags().to_string()));
+                registers
+            },
+            Some(FLOAT_REGISTERS_REFERENCE) => {
+                self.vm.float_registers.iter().enumerate()
+                    .map(|(register, value)| variable(format!("${}f", register), format!("{:?}", value)))
+                    .collect()
+            },
+            Some(STACK_REFERENCE) => {
+                self.vm.stack().iter().enumerate()
+                    .map(|(index, value)| variable(format!("[{}]", index), value.to_string()))
+                    .collect()
+            },
+            Some(HEAP_REFERENCE) => {
+                self.vm.heap().chunks(HEAP_ROW_LENGTH).enumerate().map(|(row, bytes)| {
+                    let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+                    variable(format!("{:#06x}", row * HEAP_ROW_LENGTH), hex.join(" "))
+                }).collect()
+            },
+            _ => { return Err("there are no variables with that reference".to_string()); }
+        };
+        // The stack and heap can be long, so the client may ask for them a page at a time
+        let start = arguments["start"].as_u64().unwrap_or(0) as usize;
+        let count = arguments["count"].as_u64().map(|count| count as usize).filter(|count| *count > 0);
+        variables = variables.into_iter().skip(start).take(count.unwrap_or(usize::MAX)).collect();
+        Ok(json!({ "variables": variables }))
+    }
+}
+
+/// Whether two paths name the same file, even if they're written differently
+fn same_file(a: &str, b: &str) -> bool {
+    match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn request(seq: i64, command: &str, arguments: Value) -> Value {
+        json!({ "seq": seq, "type": "request", "command": command, "arguments": arguments })
+    }
+
+    /// An adapter with a program loaded, as if launch had been sent for `counter.iasm`
+    fn adapter(source: &str) -> DebugAdapter {
+        let mut adapter = DebugAdapter::new();
+        adapter.load("counter.iasm", source).unwrap();
+        adapter.take_outgoing();
+        adapter.events.clear();
+        adapter
+    }
+
+    fn events(messages: &[Value]) -> Vec<String> {
+        messages.iter().filter(|m| m["type"] == "event").map(|m| m["event"].as_str().unwrap().to_string()).collect()
+    }
+
+    const COUNTER: &str = ".data\nhello: .asciiz 'Hi'\n.code\nload $0 #0\nload $1 #2\n\nloop: inc $0\nneq $0 $1\njmpe @loop\nprts @hello\nhlt";
+
+    #[test]
+    fn test_message_framing() {
+        let mut output = vec![];
+        write_message(&mut output, &json!({ "a": 1 })).unwrap();
+        assert_eq!(output, b"Content-Length: 7\r\n\r\n{\"a\":1}");
+        let mut input = Cursor::new(output);
+        assert_eq!(read_message(&mut input).unwrap(), Some(json!({ "a": 1 })));
+        assert_eq!(read_message(&mut input).unwrap(), None);
+    }
+
+    #[test]
+    fn test_breakpoints_by_line() {
+        let mut adapter = adapter(COUNTER);
+        adapter.handle(&request(1, "setBreakpoints", json!({
+            "source": { "path": "counter.iasm" },
+            "breakpoints": [{ "line": 6 }, { "line": 12 }],
+        })));
+        let response = &adapter.take_outgoing()[0];
+        assert_eq!(response["success"], true);
+        assert_eq!(response["request_seq"], 1);
+        assert_eq!(response["body"]["breakpoints"][0], json!({ "verified": true, "line": 7 }));
+        assert_eq!(response["body"]["breakpoints"][1]["verified"], false);
+
+        adapter.handle(&request(2, "configurationDone", json!({})));
+        let messages = adapter.take_outgoing();
+        assert_eq!(events(&messages), vec!["stopped"]);
+        assert_eq!(messages[1]["body"]["reason"], "breakpoint");
+
+        adapter.handle(&request(3, "stackTrace", json!({ "threadId": 1 })));
+        let frame = &adapter.take_outgoing()[0]["body"]["stackFrames"][0];
+        assert_eq!(frame["line"], 7);
+        assert_eq!(frame["name"], "loop");
+        assert_eq!(frame["source"]["path"], "counter.iasm");
+    }
+
+    #[test]
+    fn test_stepping_and_output() {
+        let mut adapter = adapter(COUNTER);
+        adapter.stop_on_entry = true;
+        adapter.handle(&request(1, "configurationDone", json!({})));
+        assert_eq!(adapter.take_outgoing()[1]["body"]["reason"], "entry");
+        adapter.handle(&request(2, "next", json!({ "threadId": 1 })));
+        adapter.handle(&request(3, "variables", json!({ "variablesReference": REGISTERS_REFERENCE, "start": 1, "count": 1 })));
+        let messages = adapter.take_outgoing();
+        assert_eq!(messages[1]["body"]["reason"], "step");
+        assert_eq!(messages[2]["body"]["variables"], json!([{ "name": "$1", "value": "0", "variablesReference": 0 }]));
+
+        adapter.handle(&request(4, "continue", json!({ "threadId": 1 })));
+        let messages = adapter.take_outgoing();
+        assert_eq!(events(&messages), vec!["output", "exited", "terminated"]);
+        assert_eq!(messages[1]["body"]["output"], "Hi");
+    }
+
+    #[test]
+    fn test_stop_on_trap() {
+        let mut adapter = adapter(".code\nload $0 #8\naloc $0\npush $0\nload $1 #0\ndiv $0 $1 $2\nhlt");
+        adapter.handle(&request(1, "configurationDone", json!({})));
+        let messages = adapter.take_outgoing();
+        assert_eq!(messages[1]["body"]["reason"], "exception");
+        assert_eq!(messages[1]["body"]["text"], "divide by zero at pc 80");
+
+        adapter.handle(&request(2, "stackTrace", json!({ "threadId": 1 })));
+        adapter.handle(&request(3, "scopes", json!({ "frameId": 0 })));
+        adapter.handle(&request(4, "variables", json!({ "variablesReference": STACK_REFERENCE })));
+        adapter.handle(&request(5, "variables", json!({ "variablesReference": HEAP_REFERENCE })));
+        let messages = adapter.take_outgoing();
+        assert_eq!(messages[0]["body"]["stackFrames"][0]["line"], 6);
+        assert_eq!(messages[1]["body"]["scopes"][3]["indexedVariables"], 1);
+        assert_eq!(messages[2]["body"]["variables"][0]["value"], "8");
+        assert_eq!(messages[3]["body"]["variables"][0], json!({ "name": "0x0000", "value": "00 00 00 00 00 00 00 00", "variablesReference": 0 }));
+    }
+
+    #[test]
+    fn test_assembly_errors_fail_launch() {
+        let mut adapter = DebugAdapter::new();
+        assert_eq!(adapter.load("bad.iasm", ".code\nload $0"), Err("bad.iasm has 1 error(s)".to_string()));
+        assert!(adapter.events[0]["body"]["output"].as_str().unwrap().contains("bad.iasm:2:1"));
+
+        adapter.handle(&request(1, "evaluate", json!({})));
+        let response = &adapter.take_outgoing()[0];
+        assert_eq!(response["success"], false);
+        assert_eq!(response["message"], "evaluate is not supported");
+    }
+}
@@ -0,0 +1,194 @@
+use std::io;
+use std::io::{Cursor, Read};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::assembler::diagnostics::SourceLocation;
+use crate::assembler::source_map::SourceMap;
+use crate::assembler::{Address, SymbolTable};
+use crate::pie::HeaderError;
+
+/// What the assembler knew about a program that the bytes alone don't say: where each instruction came from and what
+/// its labels and constants were called. The assembler can put this in the image's debug info section, so anything
+/// given the image can talk about `loop.iasm:12` and `@loop` rather than raw addresses.
+///
+/// The section is laid out as, with every number little-endian:
+///
+/// | Field     | Contents                                                                  |
+/// |-----------|---------------------------------------------------------------------------|
+/// | Files     | u16 count, then each name as a u16 length and UTF-8                      |
+/// | Lines     | u32 end of the code, u32 count, then u32 address, u16 file, u32 line,    |
+/// |           | column, offset, length                                                    |
+/// | Labels    | u32 count, then u32 address and the name as a u16 length and UTF-8        |
+/// | Constants | u32 count, then u32 read-only offset and the name like labels             |
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct DebugInfo {
+    pub source_map: SourceMap,
+    // Both sorted by address, then name
+    labels: Vec<(Address, String)>,
+    constants: Vec<(Address, String)>,
+}
+
+impl DebugInfo {
+    pub fn new(source_map: SourceMap, symbols: &SymbolTable) -> DebugInfo {
+        let mut labels: Vec<(Address, String)> = symbols.labels().map(|(name, offset)| (offset, name.to_string())).collect();
+        let mut constants: Vec<(Address, String)> = symbols.constants().map(|(name, offset)| (offset, name.to_string())).collect();
+        labels.sort();
+        constants.sort();
+        DebugInfo { source_map, labels, constants }
+    }
+
+    /// Where the instruction at an address came from
+    pub fn location(&self, address: Address) -> Option<&SourceLocation> {
+        self.source_map.location(address)
+    }
+
+    /// The name of the code label at an address, if there is one
+    pub fn label_at(&self, address: Address) -> Option<&str> {
+        find(&self.labels, address)
+    }
+
+    /// The name of the constant at an offset into the read-only section, if there is one
+    pub fn constant_at(&self, offset: Address) -> Option<&str> {
+        find(&self.constants, offset)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut files: Vec<&str> = vec![];
+        for (_, location) in self.source_map.entries() {
+            if !files.contains(&location.file.as_str()) {
+                files.push(&location.file);
+            }
+        }
+
+        // Writing into a Vec can't fail
+        let mut bytes = vec![];
+        bytes.write_u16::<LittleEndian>(files.len() as u16).unwrap();
+        for file in &files {
+            write_name(&mut bytes, file);
+        }
+        bytes.write_u32::<LittleEndian>(self.source_map.end()).unwrap();
+        bytes.write_u32::<LittleEndian>(self.source_map.entries().len() as u32).unwrap();
+        for (address, location) in self.source_map.entries() {
+            bytes.write_u32::<LittleEndian>(*address).unwrap();
+            let file = files.iter().position(|f| *f == location.file).unwrap();
+            bytes.write_u16::<LittleEndian>(file as u16).unwrap();
+            for field in [location.line, location.column, location.offset, location.length] {
+                bytes.write_u32::<LittleEndian>(field as u32).unwrap();
+            }
+        }
+        for symbols in [&self.labels, &self.constants] {
+            bytes.write_u32::<LittleEndian>(symbols.len() as u32).unwrap();
+            for (address, name) in symbols {
+                bytes.write_u32::<LittleEndian>(*address).unwrap();
+                write_name(&mut bytes, name);
+            }
+        }
+        bytes
+    }
+
+    /// Reads a debug info section written by `to_bytes`
+    pub fn parse(bytes: &[u8]) -> Result<DebugInfo, HeaderError> {
+        let mut rdr = Cursor::new(bytes);
+        let debug_info = DebugInfo::read(&mut rdr).map_err(|_| HeaderError::CorruptDebugInfo)?;
+        // Anything after the last constant means this isn't the layout we think it is
+        if rdr.position() as usize != bytes.len() {
+            return Err(HeaderError::CorruptDebugInfo);
+        }
+        Ok(debug_info)
+    }
+
+    fn read(rdr: &mut Cursor<&[u8]>) -> io::Result<DebugInfo> {
+        let mut files = vec![];
+        for _ in 0..rdr.read_u16::<LittleEndian>()? {
+            files.push(read_name(rdr)?);
+        }
+        let mut source_map = SourceMap::new();
+        source_map.set_end(rdr.read_u32::<LittleEndian>()?);
+        for _ in 0..rdr.read_u32::<LittleEndian>()? {
+            let address = rdr.read_u32::<LittleEndian>()?;
+            let file = match files.get(rdr.read_u16::<LittleEndian>()? as usize) {
+                Some(file) => file.clone(),
+                None => { return Err(io::ErrorKind::InvalidData.into()); }
+            };
+            let mut fields = [0; 4];
+            for field in fields.iter_mut() {
+                *field = rdr.read_u32::<LittleEndian>()? as usize;
+            }
+            let [line, column, offset, length] = fields;
+            source_map.add(address, SourceLocation { file, line, column, offset, length });
+        }
+        let mut symbols = [vec![], vec![]];
+        for table in symbols.iter_mut() {
+            for _ in 0..rdr.read_u32::<LittleEndian>()? {
+                let address = rdr.read_u32::<LittleEndian>()?;
+                table.push((address, read_name(rdr)?));
+            }
+        }
+        let [labels, constants] = symbols;
+        Ok(DebugInfo { source_map, labels, constants })
+    }
+}
+
+fn find(symbols: &[(Address, String)], address: Address) -> Option<&str> {
+    let index = symbols.partition_point(|(a, _)| *a < address);
+    symbols.get(index).filter(|(a, _)| *a == address).map(|(_, name)| name.as_str())
+}
+
+fn write_name(bytes: &mut Vec<u8>, name: &str) {
+    bytes.write_u16::<LittleEndian>(name.len() as u16).unwrap();
+    bytes.extend_from_slice(name.as_bytes());
+}
+
+fn read_name(rdr: &mut Cursor<&[u8]>) -> io::Result<String> {
+    let mut name = vec![0; rdr.read_u16::<LittleEndian>()? as usize];
+    rdr.read_exact(&mut name)?;
+    String::from_utf8(name).map_err(|_| io::ErrorKind::InvalidData.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::pie::{PieHeader, PIE_HEADER_LENGTH};
+
+    fn assemble(source: &str) -> Vec<u8> {
+        let mut asm = Assembler::new();
+        asm.set_file_name("loop.iasm");
+        asm.set_debug_info(true);
+        asm.assemble(source).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let image = assemble(".data\nhello: .asciiz 'Hi'\n.code\nload $0 #3\nloop: dec $0\nprts @hello\njmp @loop");
+        let header = PieHeader::parse(&image).unwrap();
+        let debug_info = DebugInfo::parse(header.debug_section(&image).unwrap()).unwrap();
+        assert_eq!(DebugInfo::parse(&debug_info.to_bytes()), Ok(debug_info.clone()));
+
+        let code = header.code_offset;
+        assert_eq!(debug_info.label_at(code + 4), Some("loop"));
+        assert_eq!(debug_info.label_at(code), None);
+        assert_eq!(debug_info.constant_at(0), Some("hello"));
+        let location = debug_info.location(code + 8).unwrap();
+        assert_eq!((location.file.as_str(), location.line, location.column), ("loop.iasm", 6, 1));
+        assert_eq!(debug_info.location(PIE_HEADER_LENGTH as Address), None);
+    }
+
+    #[test]
+    fn test_left_out_by_default() {
+        let image = Assembler::new().assemble(".code\nhlt").unwrap();
+        assert_eq!(PieHeader::parse(&image).unwrap().debug_section(&image), None);
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let bytes = DebugInfo::new(SourceMap::new(), &SymbolTable::new()).to_bytes();
+        assert_eq!(DebugInfo::parse(&bytes[..bytes.len() - 1]), Err(HeaderError::CorruptDebugInfo));
+        let mut extra = bytes.clone();
+        extra.push(0);
+        assert_eq!(DebugInfo::parse(&extra), Err(HeaderError::CorruptDebugInfo));
+        // A line entry pointing at a file that isn't in the table
+        assert_eq!(DebugInfo::parse(&[0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 64, 0, 0, 0, 0, 0]), Err(HeaderError::CorruptDebugInfo));
+    }
+}
@@ -0,0 +1,381 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::assembler::{Address, SymbolTable, SymbolType, INSTRUCTION_LENGTH};
+use crate::instruction::{Instruction, Opcode};
+use crate::vm::{ExitReason, VmError, VM};
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum DebuggerError {
+    UnknownLabel{name: String},
+    NotACodeLabel{name: String},
+    BadLocation{location: String},
+    BadWatchpoint{watchpoint: String},
+    NoSuchBreakpoint{address: usize},
+}
+
+impl fmt::Display for DebuggerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DebuggerError::UnknownLabel{name} => write!(f, "there is no label called {}", name),
+            DebuggerError::NotACodeLabel{name} => write!(f, "{} is a constant, not a label in the code", name),
+            DebuggerError::BadLocation{location} => write!(f, "{} is neither an address nor a @label", location),
+            DebuggerError::BadWatchpoint{watchpoint} => write!(f, "{} is neither a register like $3 or $3f nor heap <address> [length]", watchpoint),
+            DebuggerError::NoSuchBreakpoint{address} => write!(f, "there is no breakpoint at {}", address),
+        }
+    }
+}
+
+/// Something the debugger can keep an eye on, stopping whenever it changes
+#[derive(Debug, PartialEq, Clone)]
+pub enum Watchpoint {
+    Register(usize),
+    FloatRegister(usize),
+    Heap{address: usize, length: usize},
+}
+
+impl Watchpoint {
+    /// Reads a watchpoint written like `$3`, `$3f` or `heap 8 4`. Heap watchpoints cover a word if no length is given
+    pub fn parse(text: &str) -> Result<Watchpoint, DebuggerError> {
+        let bad = || DebuggerError::BadWatchpoint{watchpoint: text.to_string()};
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let watchpoint = match words.as_slice() {
+            [register] if register.starts_with('$') && register.ends_with('f') => {
+                Watchpoint::FloatRegister(register[1..register.len() - 1].parse().map_err(|_| bad())?)
+            },
+            [register] if register.starts_with('$') => {
+                Watchpoint::Register(register[1..].parse().map_err(|_| bad())?)
+            },
+            ["heap", address] => {
+                Watchpoint::Heap{address: address.parse().map_err(|_| bad())?, length: 4}
+            },
+            ["heap", address, length] => {
+                Watchpoint::Heap{address: address.parse().map_err(|_| bad())?, length: length.parse().map_err(|_| bad())?}
+            },
+            _ => { return Err(bad()); }
+        };
+        match watchpoint {
+            Watchpoint::Register(register) | Watchpoint::FloatRegister(register) if register >= 32 => Err(bad()),
+            Watchpoint::Heap{length: 0, ..} => Err(bad()),
+            Watchpoint::Heap{address, length} if address.checked_add(length).is_none() => Err(bad()),
+            _ => Ok(watchpoint),
+        }
+    }
+
+    fn read(&self, vm: &VM) -> Value {
+        match *self {
+            Watchpoint::Register(register) => Value::Integer(vm.registers[register]),
+            Watchpoint::FloatRegister(register) => Value::Float(vm.float_registers[register]),
+            Watchpoint::Heap{address, length} => {
+                match address.checked_add(length).and_then(|end| vm.heap().get(address..end)) {
+                    Some(bytes) => Value::Bytes(bytes.to_vec()),
+                    None => Value::Unallocated,
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Watchpoint::Register(register) => write!(f, "${}", register),
+            Watchpoint::FloatRegister(register) => write!(f, "${}f", register),
+            Watchpoint::Heap{address, length} => write!(f, "heap {} {}", address, length),
+        }
+    }
+}
+
+/// What a watchpoint saw
+#[derive(Debug, PartialEq, Clone)]
+pub enum Value {
+    Integer(i32),
+    Float(f64),
+    Bytes(Vec<u8>),
+    /// The heap hasn't grown far enough to cover the watched bytes
+    Unallocated,
+}
+
+impl Value {
+    // Floats are compared by their bits, so a NaN staying a NaN isn't a change
+    fn same_as(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Float(a), Value::Float(b)) => a.to_bits() == b.to_bits(),
+            _ => self == other,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Integer(value) => write!(f, "{}", value),
+            Value::Float(value) => write!(f, "{:?}", value),
+            Value::Bytes(bytes) => write!(f, "{:?}", bytes),
+            Value::Unallocated => write!(f, "unallocated"),
+        }
+    }
+}
+
+/// Why the debugger handed control back
+#[derive(Debug, PartialEq, Clone)]
+pub enum Stop {
+    /// The instruction or call being stepped over finished
+    Stepped,
+    /// The next instruction to execute has a breakpoint on it
+    Breakpoint{address: usize},
+    Watchpoint{watchpoint: Watchpoint, old: Value, new: Value},
+    /// The program stopped by itself
+    Exited{reason: ExitReason},
+}
+
+/// Runs a VM an instruction at a time, stopping at breakpoints and whenever a watched value changes
+pub struct Debugger {
+    breakpoints: BTreeSet<usize>,
+    watchpoints: Vec<Watchpoint>,
+    // Used to turn labels into addresses and back, when the program came from the assembler
+    symbols: SymbolTable,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: BTreeSet::new(),
+            watchpoints: vec![],
+            symbols: SymbolTable::new(),
+        }
+    }
+
+    /// Forgets every breakpoint and watchpoint and resolves labels with a new program's symbols
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.breakpoints.clear();
+        self.watchpoints.clear();
+        self.symbols = symbols;
+    }
+
+    /// Turns `@label` or a decimal address into an address
+    pub fn resolve(&self, location: &str) -> Result<usize, DebuggerError> {
+        if let Some(name) = location.strip_prefix('@') {
+            // Constants live in the read-only section, so their offsets aren't anywhere in the code
+            return match (self.symbols.symbol_type(name), self.symbols.symbol_value(name)) {
+                (Some(SymbolType::Label), Some(address)) => Ok(address as usize),
+                (Some(SymbolType::Constant), _) => Err(DebuggerError::NotACodeLabel{name: name.to_string()}),
+                _ => Err(DebuggerError::UnknownLabel{name: name.to_string()}),
+            };
+        }
+        location.parse().map_err(|_| DebuggerError::BadLocation{location: location.to_string()})
+    }
+
+    /// The label at an address, if the program has one there
+    pub fn label_at(&self, address: usize) -> Option<&str> {
+        self.symbols.label_at(address as Address)
+    }
+
+    /// The closest label at or before an address, which is usually the routine it's in
+    pub fn label_before(&self, address: usize) -> Option<&str> {
+        self.symbols.label_before(address as Address)
+    }
+
+    /// Sets a breakpoint at a location `resolve` understands and returns its address
+    pub fn add_breakpoint(&mut self, location: &str) -> Result<usize, DebuggerError> {
+        let address = self.resolve(location)?;
+        self.breakpoints.insert(address);
+        Ok(address)
+    }
+
+    /// Sets a breakpoint at an address that's already been worked out, like from a source line
+    pub fn add_breakpoint_at(&mut self, address: usize) {
+        self.breakpoints.insert(address);
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn remove_breakpoint(&mut self, location: &str) -> Result<usize, DebuggerError> {
+        let address = self.resolve(location)?;
+        if !self.breakpoints.remove(&address) {
+            return Err(DebuggerError::NoSuchBreakpoint{address});
+        }
+        Ok(address)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = &usize> {
+        self.breakpoints.iter()
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Executes exactly one instruction
+    pub fn step(&mut self, vm: &mut VM) -> Result<Stop, VmError> {
+        self.run(vm, |_| true)
+    }
+
+    /// Like `step`, except a CALL runs until it returns, unless something inside it stops the debugger first
+    pub fn step_over(&mut self, vm: &mut VM) -> Result<Stop, VmError> {
+        let pc = vm.pc();
+        // A pc near the top of the address space, after a jump to a negative address, can't be a CALL
+        let next = pc.checked_add(INSTRUCTION_LENGTH);
+        let is_call = next.and_then(|next| vm.program.get(pc..next))
+            .map(|bytes| Instruction::decode(bytes).opcode == Opcode::CALL)
+            .unwrap_or(false);
+        let next = match next {
+            Some(next) if is_call => next,
+            _ => { return self.step(vm); }
+        };
+        let depth = vm.call_depth();
+        // Recursion can come back through the same return address, so it's only the return from this CALL once the
+        // depth is back where it started
+        self.run(vm, |vm| vm.pc() == next && vm.call_depth() <= depth)
+    }
+
+    /// Runs until the routine the VM is in returns. Outside of any CALL that's the same as continuing
+    pub fn step_out(&mut self, vm: &mut VM) -> Result<Stop, VmError> {
+        let depth = vm.call_depth();
+        self.run(vm, |vm| vm.call_depth() < depth)
+    }
+
+    /// Runs until a breakpoint, a watchpoint or the end of the program. The first instruction always runs, so
+    /// continuing from a breakpoint doesn't stop at it again straight away
+    pub fn continue_execution(&mut self, vm: &mut VM) -> Result<Stop, VmError> {
+        self.run(vm, |_| false)
+    }
+
+    /// Executes instructions until `done` says to stop or something else does, checking watchpoints after each one
+    /// and breakpoints before all but the first
+    fn run(&mut self, vm: &mut VM, done: impl Fn(&VM) -> bool) -> Result<Stop, VmError> {
+        loop {
+            let before: Vec<Value> = self.watchpoints.iter().map(|w| w.read(vm)).collect();
+            match vm.run_once()? {
+                ExitReason::Stepped => {},
+                reason => { return Ok(Stop::Exited{reason}); }
+            }
+            for (watchpoint, old) in self.watchpoints.iter().zip(before) {
+                let new = watchpoint.read(vm);
+                if !new.same_as(&old) {
+                    return Ok(Stop::Watchpoint{watchpoint: watchpoint.clone(), old, new});
+                }
+            }
+            if done(vm) {
+                return Ok(Stop::Stepped);
+            }
+            if self.breakpoints.contains(&vm.pc()) {
+                return Ok(Stop::Breakpoint{address: vm.pc()});
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    /// Loads a program into a VM, ready to debug it
+    fn debug(source: &str) -> (Debugger, VM) {
+        let mut asm = Assembler::new();
+        let mut program = asm.assemble(source).unwrap();
+        let mut vm = VM::new();
+        vm.add_bytes(&mut program).unwrap();
+        vm.restart();
+        let mut debugger = Debugger::new();
+        debugger.set_symbols(asm.symbols);
+        (debugger, vm)
+    }
+
+    const COUNTER: &str = ".code\nload $0 #0\nload $1 #3\nloop: inc $0\nneq $0 $1\njmpe @loop\nhlt";
+
+    #[test]
+    fn test_breakpoint_at_label() {
+        let (mut debugger, mut vm) = debug(COUNTER);
+        let address = debugger.add_breakpoint("@loop").unwrap();
+        assert_eq!(debugger.label_at(address), Some("loop"));
+        assert_eq!(debugger.continue_execution(&mut vm), Ok(Stop::Breakpoint{address}));
+        assert_eq!(vm.registers[0], 0);
+        assert_eq!(debugger.continue_execution(&mut vm), Ok(Stop::Breakpoint{address}));
+        assert_eq!(vm.registers[0], 1);
+
+        debugger.remove_breakpoint("@loop").unwrap();
+        assert_eq!(debugger.continue_execution(&mut vm), Ok(Stop::Exited{reason: ExitReason::Halted}));
+        assert_eq!(vm.registers[0], 3);
+    }
+
+    #[test]
+    fn test_bad_locations() {
+        let (mut debugger, _) = debug(COUNTER);
+        assert_eq!(debugger.add_breakpoint("@nowhere"), Err(DebuggerError::UnknownLabel{name: "nowhere".to_string()}));
+        assert_eq!(debugger.add_breakpoint("loop"), Err(DebuggerError::BadLocation{location: "loop".to_string()}));
+        assert_eq!(debugger.remove_breakpoint("72"), Err(DebuggerError::NoSuchBreakpoint{address: 72}));
+
+        let (mut debugger, _) = debug(".data\nhello: .asciiz 'Hi'\n.code\nprts @hello\nhlt");
+        assert_eq!(debugger.add_breakpoint("@hello"), Err(DebuggerError::NotACodeLabel{name: "hello".to_string()}));
+        assert_eq!(debugger.breakpoints().count(), 0);
+    }
+
+    #[test]
+    fn test_step_and_step_over() {
+        let (mut debugger, mut vm) = debug(".code\nload $0 #2\ncall @double\nhlt\ndouble: add $0 $0 $0\nret");
+        let start = vm.pc();
+        assert_eq!(debugger.step(&mut vm), Ok(Stop::Stepped));
+        assert_eq!(vm.pc(), start + 4);
+        assert_eq!(debugger.step_over(&mut vm), Ok(Stop::Stepped));
+        assert_eq!(vm.pc(), start + 8);
+        assert_eq!(vm.registers[0], 4);
+
+        // Stepping into the call instead
+        vm.restart();
+        debugger.step(&mut vm).unwrap();
+        debugger.step(&mut vm).unwrap();
+        assert_eq!(Some(vm.pc()), debugger.resolve("@double").ok());
+        assert_eq!(debugger.step_out(&mut vm), Ok(Stop::Stepped));
+        assert_eq!(vm.pc(), start + 8);
+    }
+
+    #[test]
+    fn test_step_over_stops_at_breakpoint_inside_call() {
+        let (mut debugger, mut vm) = debug(".code\ncall @sub\nhlt\nsub: inc $0\nret");
+        let address = debugger.add_breakpoint("@sub").unwrap();
+        assert_eq!(debugger.step_over(&mut vm), Ok(Stop::Breakpoint{address}));
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let (mut debugger, mut vm) = debug(".code\nload $0 #8\naloc $0\nload $1 #7\nload $2 #0\nsw $1 $2 #4\nhlt");
+        debugger.add_watchpoint(Watchpoint::parse("heap 4").unwrap());
+        debugger.add_watchpoint(Watchpoint::parse("$1").unwrap());
+        assert_eq!(debugger.continue_execution(&mut vm), Ok(Stop::Watchpoint{
+            watchpoint: Watchpoint::Heap{address: 4, length: 4}, old: Value::Unallocated, new: Value::Bytes(vec![0, 0, 0, 0]),
+        }));
+        assert_eq!(debugger.continue_execution(&mut vm), Ok(Stop::Watchpoint{
+            watchpoint: Watchpoint::Register(1), old: Value::Integer(0), new: Value::Integer(7),
+        }));
+        assert_eq!(debugger.continue_execution(&mut vm), Ok(Stop::Watchpoint{
+            watchpoint: Watchpoint::Heap{address: 4, length: 4}, old: Value::Bytes(vec![0, 0, 0, 0]), new: Value::Bytes(vec![7, 0, 0, 0]),
+        }));
+        assert_eq!(debugger.continue_execution(&mut vm), Ok(Stop::Exited{reason: ExitReason::Halted}));
+    }
+
+    #[test]
+    fn test_parse_watchpoint() {
+        assert_eq!(Watchpoint::parse("$3f"), Ok(Watchpoint::FloatRegister(3)));
+        assert_eq!(Watchpoint::parse("heap 16 2"), Ok(Watchpoint::Heap{address: 16, length: 2}));
+        assert!(Watchpoint::parse("$32").is_err());
+        assert!(Watchpoint::parse("heap 16 0").is_err());
+        assert!(Watchpoint::parse("heap 18446744073709551615 4").is_err());
+        assert!(Watchpoint::parse("heap").is_err());
+    }
+}
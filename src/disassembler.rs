@@ -0,0 +1,242 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::assembler::INSTRUCTION_LENGTH;
+use crate::debug_info::DebugInfo;
+use crate::instruction::{Instruction, Opcode, OperandKind};
+use crate::pie::{HeaderError, PieHeader};
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum DisassemblerError {
+    InvalidHeader{error: HeaderError},
+    TruncatedInstruction{address: usize},
+}
+
+impl fmt::Display for DisassemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DisassemblerError::InvalidHeader{error} => write!(f, "{}", error),
+            DisassemblerError::TruncatedInstruction{address} => write!(f, "instruction at {} runs past the end of the code", address),
+        }
+    }
+}
+
+impl From<HeaderError> for DisassemblerError {
+    fn from(error: HeaderError) -> Self {
+        DisassemblerError::InvalidHeader{error}
+    }
+}
+
+/// Splits an instruction into its opcode and the kind and value of each operand
+fn decode(bytes: &[u8]) -> (Opcode, Vec<(OperandKind, u32)>) {
+    let instruction = Instruction::decode(bytes);
+    let kinds = instruction.opcode.operands();
+    (instruction.opcode, kinds.iter().cloned().zip(instruction.operands).collect())
+}
+
+/// What the disassembler calls the things operands point at
+#[derive(Default)]
+struct Names {
+    labels: BTreeMap<u32, String>,
+    strings: BTreeMap<u32, String>,
+    // Floats are written out as literals rather than given names
+    floats: BTreeMap<u32, f64>,
+}
+
+/// Where an operand sends execution, if it's part of a jump. `address` is where the instruction is
+fn jump_target(opcode: Opcode, kind: OperandKind, value: u32, address: u32) -> Option<u32> {
+    let next = address + INSTRUCTION_LENGTH as u32;
+    match (opcode, kind) {
+        (_, OperandKind::CodeAddress) => Some(value),
+        (Opcode::DJMPF, OperandKind::Displacement) => next.checked_add(value),
+        (Opcode::DJMPB, OperandKind::Displacement) => next.checked_sub(value),
+        _ => None
+    }
+}
+
+/// Turns a single instruction back into assembly, without any labels
+pub fn format_instruction(bytes: &[u8]) -> String {
+    format_with_names(bytes, 0, &Names::default())
+}
+
+fn format_with_names(bytes: &[u8], address: u32, names: &Names) -> String {
+    let (opcode, operands) = decode(bytes);
+    let mut line = opcode.mnemonic().to_string();
+    for (kind, value) in operands {
+        let name = match kind {
+            OperandKind::CodeAddress | OperandKind::Displacement => {
+                jump_target(opcode, kind, value, address).and_then(|target| names.labels.get(&target))
+            },
+            OperandKind::StringOffset => names.strings.get(&value),
+            _ => None
+        };
+        let operand = match (kind, name) {
+            (OperandKind::Register, _) | (OperandKind::FloatRegister, _) => format!("${}", value),
+            (_, Some(name)) => format!("@{}", name),
+            (OperandKind::ConstantOffset, None) if names.floats.contains_key(&value) => format!("#{:?}", names.floats[&value]),
+            (_, None) => format!("#{}", value),
+        };
+        line.push(' ');
+        line.push_str(&operand);
+    }
+    line
+}
+
+/// Turns an image produced by the assembler back into source that assembles to the same bytes. If the image has
+/// debug info, labels and constants keep the names they were given in the source
+pub fn disassemble(image: &[u8]) -> Result<String, DisassemblerError> {
+    let header = PieHeader::parse(image)?;
+    let debug_info = match header.debug_section(image) {
+        Some(section) => Some(DebugInfo::parse(section)?),
+        None => None,
+    };
+    disassemble_with_debug_info(header.ro_section(image), header.code_section(image), header.code_offset as usize, debug_info.as_ref())
+}
+
+/// Disassembles a read-only section and code that starts at `code_offset` in the program
+pub fn disassemble_sections(ro: &[u8], code: &[u8], code_offset: usize) -> Result<String, DisassemblerError> {
+    disassemble_with_debug_info(ro, code, code_offset, None)
+}
+
+fn disassemble_with_debug_info(ro: &[u8], code: &[u8], code_offset: usize, debug_info: Option<&DebugInfo>) -> Result<String, DisassemblerError> {
+    if !code.len().is_multiple_of(INSTRUCTION_LENGTH) {
+        return Err(DisassemblerError::TruncatedInstruction{address: code_offset + code.len() - code.len() % INSTRUCTION_LENGTH});
+    }
+    let mut names = Names::default();
+
+    // Anything jumped to or called gets a label, as long as an instruction actually starts there, and anything loaded
+    // as a float is one
+    let mut targets = vec![];
+    for (index, bytes) in code.chunks(INSTRUCTION_LENGTH).enumerate() {
+        let (opcode, operands) = decode(bytes);
+        for (kind, value) in operands {
+            if let Some(target) = jump_target(opcode, kind, value, (code_offset + index * INSTRUCTION_LENGTH) as u32) {
+                let relative = (target as usize).wrapping_sub(code_offset);
+                if relative < code.len() && relative.is_multiple_of(INSTRUCTION_LENGTH) {
+                    targets.push(target);
+                }
+            }
+            if kind == OperandKind::ConstantOffset && value as usize + 8 <= ro.len() {
+                names.floats.insert(value, LittleEndian::read_f64(&ro[value as usize..]));
+            }
+        }
+    }
+    targets.sort();
+    targets.dedup();
+    // Labels that were named in the source keep their names, even if nothing jumps to them, and the rest are numbered
+    // around them
+    if let Some(debug_info) = debug_info {
+        for index in 0..code.len() / INSTRUCTION_LENGTH {
+            let address = (code_offset + index * INSTRUCTION_LENGTH) as u32;
+            if let Some(name) = debug_info.label_at(address) {
+                names.labels.insert(address, name.to_string());
+            }
+        }
+    }
+    let mut next_label = 0;
+    for target in targets {
+        if names.labels.contains_key(&target) {
+            continue;
+        }
+        while names.labels.values().any(|name| *name == format!("label{}", next_label)) {
+            next_label += 1;
+        }
+        names.labels.insert(target, format!("label{}", next_label));
+        next_label += 1;
+    }
+
+    // Every other null-terminated run of bytes in the read-only section is a string
+    let mut start = 0;
+    let mut offset = 0;
+    while offset < ro.len() {
+        if names.floats.contains_key(&(offset as u32)) {
+            offset += 8;
+            start = offset;
+            continue;
+        }
+        if ro[offset] == 0 {
+            let name = debug_info.and_then(|debug_info| debug_info.constant_at(start as u32));
+            let name = name.map(|name| name.to_string()).unwrap_or_else(|| format!("str{}", names.strings.len()));
+            names.strings.insert(start as u32, name);
+            start = offset + 1;
+        }
+        offset += 1;
+    }
+
+    let mut source = String::new();
+    if !names.strings.is_empty() {
+        source.push_str(".data\n");
+        for (offset, name) in &names.strings {
+            let end = offset + ro[*offset as usize..].iter().position(|b| *b == 0).unwrap() as u32;
+            let literal = String::from_utf8_lossy(&ro[*offset as usize..end as usize]);
+            source.push_str(&format!("{}: .asciiz '{}'\n", name, literal));
+        }
+    }
+    source.push_str(".code\n");
+    for (index, bytes) in code.chunks(INSTRUCTION_LENGTH).enumerate() {
+        let address = (code_offset + index * INSTRUCTION_LENGTH) as u32;
+        if let Some(label) = names.labels.get(&address) {
+            source.push_str(&format!("{}: ", label));
+        }
+        source.push_str(&format_with_names(bytes, address, &names));
+        source.push('\n');
+    }
+    Ok(source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    #[test]
+    fn test_format_instruction() {
+        assert_eq!(format_instruction(&[0, 1, 1, 244]), "load $1 #500");
+        assert_eq!(format_instruction(&[1, 0, 1, 2]), "add $0 $1 $2");
+        assert_eq!(format_instruction(&[28, 3, 2, 4]), "lw $3 $2 #4");
+        assert_eq!(format_instruction(&[5, 0, 0, 0]), "hlt");
+        assert_eq!(format_instruction(&[200, 0, 0, 0]), "igl");
+    }
+
+    #[test]
+    fn test_disassemble_program() {
+        let source = ".data\nhello: .asciiz 'Hello'\nbye: .asciiz 'Bye'\n.code\nload $0 #0\nload $1 #5\nloop: inc $0\nprts @bye\nneq $0 $1\njmpe @loop\njmpf @done\njmpb @loop\ndone: hlt";
+        let program = Assembler::new().assemble(source).unwrap();
+        let disassembly = disassemble(&program).unwrap();
+        assert_eq!(disassembly, ".data\nstr0: .asciiz 'Hello'\nstr1: .asciiz 'Bye'\n.code\nload $0 #0\nload $1 #5\nlabel0: inc $0\nprts @str1\nneq $0 $1\ndjmpe @label0\ndjmpf @label1\ndjmpb @label0\nlabel1: hlt\n");
+    }
+
+    #[test]
+    fn test_disassembly_reassembles() {
+        let source = ".data\ngreeting: .asciiz 'Hi there'\n.code\nload $0 #8\naloc $0\nload $2 #0\nsw $0 $2 #4\ntop: lw $3 $2 #4\nprts @greeting\ncall @sub\njmp @top\nsub: push $1\npop $1\nret";
+        let program = Assembler::new().assemble(source).unwrap();
+        let disassembly = disassemble(&program).unwrap();
+        let reassembled = Assembler::new().assemble(&disassembly).unwrap();
+        assert_eq!(reassembled, program);
+    }
+
+    #[test]
+    fn test_disassemble_floats() {
+        let source = ".data\nhello: .asciiz 'Hi'\n.code\nloadf $0 #2.5\nloadf $1 #1e-7\nprts @hello\nloadf $2 #2.5\nhlt";
+        let program = Assembler::new().assemble(source).unwrap();
+        let disassembly = disassemble(&program).unwrap();
+        assert_eq!(disassembly, ".data\nstr0: .asciiz 'Hi'\n.code\nloadf $0 #2.5\nloadf $1 #1e-7\nprts @str0\nloadf $2 #2.5\nhlt\n");
+    }
+
+    #[test]
+    fn test_disassemble_with_debug_info() {
+        let source = ".data\nhello: .asciiz 'Hi'\n.code\nload $0 #3\nlabel0: dec $0\nprts @hello\njmp @label0\njmp @skip\nunused: hlt\nskip: hlt";
+        let mut asm = Assembler::new();
+        asm.set_debug_info(true);
+        let program = asm.assemble(source).unwrap();
+        let disassembly = disassemble(&program).unwrap();
+        assert_eq!(disassembly, ".data\nhello: .asciiz 'Hi'\n.code\nload $0 #3\nlabel0: dec $0\nprts @hello\ndjmp @label0\ndjmp @skip\nunused: hlt\nskip: hlt\n");
+    }
+
+    #[test]
+    fn test_truncated_code() {
+        assert_eq!(disassemble_sections(&[], &[5, 0, 0, 0, 5], 64), Err(DisassemblerError::TruncatedInstruction { address: 68 }));
+    }
+}
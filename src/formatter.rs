@@ -0,0 +1,212 @@
+use nom::types::CompleteStr;
+
+use crate::assembler::diagnostics::{LineIndex, SourceLocation};
+use crate::assembler::instruction_parsers::AssemblerInstruction;
+use crate::assembler::program_parsers::{program, trivia};
+use crate::assembler::{AssemblerError, Token};
+
+/// The least the label column is indented by, so instructions stand out from section headers even when there are no
+/// labels
+const MIN_LABEL_WIDTH: usize = 4;
+
+/// An instruction split into the columns it's laid out in
+struct Columns {
+    label: String,
+    head: String,
+    operands: String,
+    // A directive like `.code` on its own, which goes at the start of the line
+    section: bool,
+}
+
+impl Columns {
+    fn new(instruction: &AssemblerInstruction) -> Columns {
+        let label = instruction.label_name().map(|name| format!("{}:", name)).unwrap_or_default();
+        // Mnemonics the parser doesn't know are kept as written, so they're left for the assembler to point out
+        let head = instruction.directive.as_ref().or(instruction.opcode.as_ref()).map(Token::to_string).unwrap_or_default();
+        let operands: Vec<String> = [&instruction.operand1, &instruction.operand2, &instruction.operand3].iter()
+            .filter_map(|operand| operand.as_ref().map(Token::to_string))
+            .collect();
+        let section = instruction.is_directive() && !instruction.is_label() && operands.is_empty();
+        Columns { label, head, operands: operands.join(" "), section }
+    }
+}
+
+/// Something that goes on a line of its own, and the lines of source it came from
+enum Item {
+    Instruction{columns: Columns, first_line: usize, last_line: usize, comment: Option<String>},
+    Comment{text: String, line: usize, indented: bool},
+}
+
+/// Rewrites a program in the canonical layout: labels, mnemonics and operands lined up in columns, mnemonics in
+/// lower case, every literal written the way the disassembler writes it, and comments after instructions lined up
+/// too. Comments are kept, and runs of blank lines become one. Programs that don't parse are left alone, and the
+/// error is returned instead
+pub fn format(source: &str, file_name: &str) -> Result<String, AssemblerError> {
+    let located = |offset: usize| {
+        let mut location = SourceLocation::rest_of_line(source, offset);
+        location.file = file_name.to_string();
+        location
+    };
+    let p = match program(CompleteStr(source)) {
+        Ok((remainder, p)) => {
+            if !remainder.trim().is_empty() {
+                let offset = source.len() - remainder.trim_start().len();
+                return Err(AssemblerError::ParseError{ error: "expected an instruction or directive".to_string(), location: located(offset) });
+            }
+            p
+        },
+        Err(_) => {
+            // A file with nothing but comments in it has no instructions to line them up with, so it's left as it is
+            let (rest, _) = trivia(CompleteStr(source));
+            if rest.is_empty() {
+                return Ok(source.to_string());
+            }
+            let offset = source.len() - rest.len();
+            return Err(AssemblerError::ParseError{ error: "expected an instruction or directive".to_string(), location: located(offset) });
+        }
+    };
+
+    // Everything is put back in the order it was written, with each comment that shares a line with the end of an
+    // instruction kept on that line
+    let lines = LineIndex::new(source);
+    let mut items: Vec<Item> = vec![];
+    let mut comments = p.comments.iter().peekable();
+    for (instruction, location) in p.instructions.iter().zip(&p.locations) {
+        while let Some(comment) = comments.next_if(|comment| comment.offset < location.offset) {
+            items.push(standalone_comment(source, comment));
+        }
+        let last_line = lines.location(location.offset + location.length, 0).line;
+        let comment = comments.next_if(|comment| comment.line == last_line)
+            .map(|comment| source[comment.offset..comment.offset + comment.length].to_string());
+        let columns = Columns::new(instruction);
+        items.push(Item::Instruction{columns, first_line: location.line, last_line, comment});
+    }
+    for comment in comments {
+        items.push(standalone_comment(source, comment));
+    }
+
+    let instructions = || items.iter().filter_map(|item| match item {
+        Item::Instruction{columns, ..} if !columns.section => Some(columns),
+        _ => None,
+    });
+    let label_width = instructions().map(|columns| columns.label.len() + 1).max().unwrap_or(0).max(MIN_LABEL_WIDTH);
+    let head_width = instructions().map(|columns| columns.head.len() + 1).max().unwrap_or(0);
+    let code = |columns: &Columns| match columns.section {
+        true => columns.head.clone(),
+        false => format!("{:<label$}{:<head$}{}", columns.label, columns.head, columns.operands, label = label_width, head = head_width).trim_end().to_string(),
+    };
+    let comment_column = items.iter().filter_map(|item| match item {
+        Item::Instruction{columns, comment: Some(_), ..} => Some(code(columns).len() + 1),
+        _ => None,
+    }).max().unwrap_or(0);
+
+    let mut formatted = String::new();
+    let mut previous_line = None;
+    for item in &items {
+        let (first_line, last_line) = match item {
+            Item::Instruction{first_line, last_line, ..} => (*first_line, *last_line),
+            Item::Comment{line, ..} => (*line, *line),
+        };
+        if previous_line.is_some_and(|previous| first_line > previous + 1) {
+            formatted.push('\n');
+        }
+        previous_line = Some(last_line);
+        let line = match item {
+            Item::Instruction{columns, comment: Some(comment), ..} => format!("{:<width$}{}", code(columns), comment, width = comment_column),
+            Item::Instruction{columns, comment: None, ..} => code(columns),
+            Item::Comment{text, indented: true, ..} => format!("{:<width$}{}", "", text, width = label_width),
+            Item::Comment{text, indented: false, ..} => text.clone(),
+        };
+        formatted.push_str(&line);
+        formatted.push('\n');
+    }
+    Ok(formatted)
+}
+
+fn standalone_comment(source: &str, comment: &SourceLocation) -> Item {
+    Item::Comment{
+        text: source[comment.offset..comment.offset + comment.length].to_string(),
+        line: comment.line,
+        indented: comment.column > 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    const MESSY: &str = "; Counts to ten\n.data\n  greeting:   .asciiz 'Hi; there'\n.code\nLOAD $0 #0x0A ; ten\n\n\n\n    top:    dec   $0\n  ; say hello\n    prts @greeting\n    ADDF $1 $2 $3   ;floats\nneq $0 $5\njmpe @top\nlf: loadf $1 #2.50\nhlt\n";
+
+    #[test]
+    fn test_format() {
+        let formatted = format(MESSY, "count.iasm").unwrap();
+        assert_eq!(formatted, concat!(
+            "; Counts to ten\n",
+            ".data\n",
+            "greeting: .asciiz 'Hi; there'\n",
+            ".code\n",
+            "          load    $0 #10   ; ten\n",
+            "\n",
+            "top:      dec     $0\n",
+            "          ; say hello\n",
+            "          prts    @greeting\n",
+            "          addf    $1 $2 $3 ;floats\n",
+            "          neq     $0 $5\n",
+            "          jmpe    @top\n",
+            "lf:       loadf   $1 #2.5\n",
+            "          hlt\n",
+        ));
+    }
+
+    #[test]
+    fn test_format_is_stable() {
+        let formatted = format(MESSY, "count.iasm").unwrap();
+        assert_eq!(format(&formatted, "count.iasm").unwrap(), formatted);
+    }
+
+    #[test]
+    fn test_format_keeps_the_program() {
+        let source = ".data\nhello: .asciiz 'Hi'\n.code\n  LOAD $1 #'a' ; a\nload $2 #-2\nlOOp: INC $1\nprts @hello\nJMP @lOOp";
+        let formatted = format(source, "loop.iasm").unwrap();
+        assert_eq!(Assembler::new().assemble(&formatted).unwrap(), Assembler::new().assemble(source).unwrap());
+    }
+
+    #[test]
+    fn test_double_quoted_strings() {
+        let source = ".data\ns: .asciiz \"a\"\nq: .asciiz \"it's\"\n.code\nhlt\n";
+        let formatted = format(source, "strings.iasm").unwrap();
+        assert_eq!(formatted, ".data\ns:  .asciiz 'a'\nq:  .asciiz \"it's\"\n.code\n    hlt\n");
+        assert_eq!(Assembler::new().assemble(&formatted).unwrap(), Assembler::new().assemble(source).unwrap());
+    }
+
+    #[test]
+    fn test_unknown_mnemonic_is_kept() {
+        assert_eq!(format(".code\nLAOD $0 #1", "typo.iasm").unwrap(), ".code\n    laod $0 #1\n");
+    }
+
+    #[test]
+    fn test_parse_error() {
+        match format(".code\nhlt\n!!", "bad.iasm") {
+            Err(AssemblerError::ParseError{location, ..}) => {
+                assert_eq!((location.file.as_str(), location.line, location.column), ("bad.iasm", 3, 1));
+            },
+            other => panic!("Expected a parse error, got {:?}", other.map(|_| ())),
+        }
+        match format("; nothing yet\n!!", "bad.iasm") {
+            Err(AssemblerError::ParseError{error, location}) => {
+                assert_eq!(error, "expected an instruction or directive");
+                assert_eq!((location.line, location.column), (2, 1));
+            },
+            other => panic!("Expected a parse error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_format_without_instructions() {
+        assert_eq!(format("", "empty.iasm").unwrap(), "");
+        assert_eq!(format("\n\n", "empty.iasm").unwrap(), "\n\n");
+        assert_eq!(format("; just a comment\n", "notes.iasm").unwrap(), "; just a comment\n");
+        assert_eq!(format("  ; one\n\n; two", "notes.iasm").unwrap(), "  ; one\n\n; two");
+    }
+}
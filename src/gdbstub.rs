@@ -0,0 +1,546 @@
+use std::collections::BTreeSet;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::tracer::Flags;
+use crate::vm::{ExitReason, Trap, VM};
+
+/// Registers in the order `g` packets and the target description list them: the integer registers, then pc and the
+/// flags, then the float registers
+const INTEGER_REGISTERS: usize = 32;
+const PC_REGISTER: usize = 32;
+const FLAGS_REGISTER: usize = 33;
+const FLOAT_REGISTERS: usize = 34;
+const REGISTER_COUNT: usize = FLOAT_REGISTERS + 32;
+
+/// How many instructions run between checks for the debugger asking to interrupt a `c`
+const INTERRUPT_CHECK_INTERVAL: u64 = 4096;
+
+// Signals reported in stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGFPE: u8 = 8;
+const SIGSEGV: u8 = 11;
+
+/// The architecture GDB is told it's debugging. GDB can only use register layouts for architectures it was built
+/// with, and 32-bit RISC-V happens to have exactly the registers the VM does: 32 integer registers and a pc, plus 32
+/// double-precision float registers. Only the registers are borrowed. Instructions are the VM's own, so GDB's
+/// disassembly and backtraces are meaningless, but registers, memory, breakpoints and stepping all work
+const GDB_ARCHITECTURE: &str = "riscv:rv32";
+
+/// Describes the registers to GDB, which asks for it with `qXfer:features:read:target.xml`. The integer registers
+/// and pc are GDB's standard RISC-V core feature, x0 to x31, and the float registers its standard FPU feature, f0 to
+/// f31. The flags register has no RISC-V equivalent, so it's in a feature of its own, which GDB shows as an extra
+/// register
+pub fn target_description() -> String {
+    let mut xml = String::from("<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n");
+    xml.push_str(&format!("  <architecture>{}</architecture>\n", GDB_ARCHITECTURE));
+    xml.push_str("  <feature name=\"org.gnu.gdb.riscv.cpu\">\n");
+    for register in 0..INTEGER_REGISTERS {
+        xml.push_str(&format!("    <reg name=\"x{}\" bitsize=\"32\" type=\"int\" regnum=\"{}\"/>\n", register, register));
+    }
+    xml.push_str(&format!("    <reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\" regnum=\"{}\"/>\n", PC_REGISTER));
+    xml.push_str("  </feature>\n  <feature name=\"org.gnu.gdb.riscv.fpu\">\n");
+    for register in 0..32 {
+        xml.push_str(&format!("    <reg name=\"f{}\" bitsize=\"64\" type=\"ieee_double\" regnum=\"{}\"/>\n", register, FLOAT_REGISTERS + register));
+    }
+    xml.push_str("  </feature>\n  <feature name=\"org.iridium.flags\">\n");
+    xml.push_str(&format!("    <reg name=\"flags\" bitsize=\"32\" type=\"int\" regnum=\"{}\"/>\n", FLAGS_REGISTER));
+    xml.push_str("  </feature>\n</target>\n");
+    xml
+}
+
+/// What came in from the debugger
+#[derive(Debug, PartialEq)]
+enum Incoming {
+    Packet(String),
+    /// The packet's checksum was wrong, so it needs sending again
+    Corrupt,
+    /// A bare Ctrl-C, which asks a running program to stop
+    Interrupt,
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0, |sum, byte| sum.wrapping_add(byte))
+}
+
+/// Frames a reply as `$data#checksum`, escaping the characters the protocol reserves
+fn frame(data: &str) -> String {
+    let mut escaped = String::new();
+    for c in data.chars() {
+        match c {
+            '$' | '#' | '}' | '*' => {
+                escaped.push('}');
+                escaped.push((c as u8 ^ 0x20) as char);
+            },
+            _ => { escaped.push(c); }
+        }
+    }
+    format!("${}#{:02x}", escaped, checksum(&escaped))
+}
+
+/// Reads up to the next packet or interrupt, skipping acknowledgements. Nothing is returned once the debugger hangs up
+fn read_incoming(reader: &mut impl BufRead) -> io::Result<Option<Incoming>> {
+    let mut byte = [0];
+    loop {
+        if reader.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        match byte[0] {
+            b'$' => { break; },
+            0x03 => { return Ok(Some(Incoming::Interrupt)); },
+            _ => {}
+        }
+    }
+    let mut data = vec![];
+    if reader.read_until(b'#', &mut data)? == 0 || data.pop() != Some(b'#') {
+        return Ok(None);
+    }
+    let mut sum = [0; 2];
+    reader.read_exact(&mut sum)?;
+    let data = String::from_utf8_lossy(&data).to_string();
+    let expected = std::str::from_utf8(&sum).ok().and_then(|sum| u8::from_str_radix(sum, 16).ok());
+    if expected != Some(checksum(&data)) {
+        return Ok(Some(Incoming::Corrupt));
+    }
+    Ok(Some(Incoming::Packet(data)))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// Reads a pair of hex numbers like the `addr,length` in memory packets
+fn parse_pair(text: &str, separator: char) -> Option<(usize, usize)> {
+    let (first, second) = text.split_once(separator)?;
+    Some((usize::from_str_radix(first, 16).ok()?, usize::from_str_radix(second, 16).ok()?))
+}
+
+/// Which signal GDB is told stopped the program when an instruction traps
+fn signal_for(trap: &Trap) -> u8 {
+    match trap {
+        Trap::IllegalOpcode{..} => SIGILL,
+        Trap::DivideByZero | Trap::ArithmeticOverflow | Trap::InvalidConversion{..} => SIGFPE,
+        Trap::OutputFailed => SIGTRAP,
+        _ => SIGSEGV,
+    }
+}
+
+/// Lets GDB control a VM over the remote serial protocol. Memory is laid out as `VM::read_memory` describes it. A
+/// stock `gdb-multiarch` connects with `gdb-multiarch -ex 'target remote localhost:PORT'` and picks the architecture up
+/// from the target description. GDBs too old to read `<architecture>` from it need `set architecture riscv:rv32`
+/// before `target remote`
+pub struct GdbStub {
+    vm: VM,
+    breakpoints: BTreeSet<usize>,
+}
+
+impl GdbStub {
+    /// Takes over a VM that has a program loaded, stopped wherever it should start
+    pub fn new(vm: VM) -> GdbStub {
+        GdbStub {
+            vm,
+            breakpoints: BTreeSet::new(),
+        }
+    }
+
+    /// Waits for a debugger to connect and serves it until it detaches or hangs up
+    pub fn serve(&mut self, address: impl ToSocketAddrs) -> io::Result<()> {
+        let listener = TcpListener::bind(address)?;
+        info!("waiting for a debugger on {}", listener.local_addr()?);
+        let (stream, peer) = listener.accept()?;
+        info!("debugger connected from {}", peer);
+        self.session(stream)
+    }
+
+    fn session(&mut self, stream: TcpStream) -> io::Result<()> {
+        // Every packet is acknowledged and answered in separate small writes, which Nagle's algorithm would hold back
+        stream.set_nodelay(true)?;
+        let mut writer = stream.try_clone()?;
+        let poller = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+        // Anything arriving while a program runs can only be an interrupt, so a single byte is all that's peeked at
+        let mut interrupted = || {
+            let mut byte = [0];
+            if poller.set_nonblocking(true).is_err() {
+                return false;
+            }
+            let ready = matches!(poller.peek(&mut byte), Ok(1));
+            let _ = poller.set_nonblocking(false);
+            if ready && byte[0] == 0x03 {
+                let _ = (&poller).read(&mut byte);
+                return true;
+            }
+            false
+        };
+        while let Some(incoming) = read_incoming(&mut reader)? {
+            let packet = match incoming {
+                Incoming::Packet(packet) => packet,
+                Incoming::Corrupt => {
+                    writer.write_all(b"-")?;
+                    continue;
+                },
+                // Nothing is running, so there is nothing to interrupt, but GDB still wants to hear that it stopped
+                Incoming::Interrupt => {
+                    writer.write_all(frame(&format!("S{:02x}", SIGINT)).as_bytes())?;
+                    continue;
+                },
+            };
+            writer.write_all(b"+")?;
+            debug!("gdb: {}", packet);
+            match self.handle(&packet, &mut interrupted) {
+                Some(reply) => { writer.write_all(frame(&reply).as_bytes())?; },
+                None => {
+                    writer.write_all(frame("OK").as_bytes())?;
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Works out the reply to one packet. An empty reply tells GDB the packet isn't supported, and nothing at all means
+    /// the debugger is done with the VM
+    fn handle(&mut self, packet: &str, interrupted: &mut dyn FnMut() -> bool) -> Option<String> {
+        let error = "E01".to_string();
+        let (command, rest) = packet.split_at(packet.chars().next().map(|c| c.len_utf8()).unwrap_or(0));
+        let reply = match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => {
+                let registers: Vec<String> = (0..REGISTER_COUNT).map(|register| to_hex(&self.read_register(register))).collect();
+                registers.concat()
+            },
+            "G" => {
+                match from_hex(rest) {
+                    Some(bytes) => {
+                        let mut offset = 0;
+                        for register in 0..REGISTER_COUNT {
+                            let size = register_size(register);
+                            if let Some(value) = bytes.get(offset..offset + size) {
+                                self.write_register(register, value);
+                            }
+                            offset += size;
+                        }
+                        "OK".to_string()
+                    },
+                    None => error,
+                }
+            },
+            "p" => {
+                match usize::from_str_radix(rest, 16) {
+                    Ok(register) if register < REGISTER_COUNT => to_hex(&self.read_register(register)),
+                    _ => error,
+                }
+            },
+            "P" => {
+                let write = rest.split_once('=').and_then(|(register, value)| {
+                    let register = usize::from_str_radix(register, 16).ok().filter(|r| *r < REGISTER_COUNT)?;
+                    let value = from_hex(value).filter(|v| v.len() == register_size(register))?;
+                    Some((register, value))
+                });
+                match write {
+                    Some((register, value)) => {
+                        self.write_register(register, &value);
+                        "OK".to_string()
+                    },
+                    None => error,
+                }
+            },
+            "m" => {
+                match parse_pair(rest, ',').and_then(|(address, length)| self.vm.read_memory(address, length)) {
+                    Some(bytes) => to_hex(bytes),
+                    None => error,
+                }
+            },
+            "M" => {
+                let write = rest.split_once(':').and_then(|(range, data)| {
+                    let (address, length) = parse_pair(range, ',')?;
+                    Some((address, from_hex(data).filter(|bytes| bytes.len() == length)?))
+                });
+                match write {
+                    Some((address, bytes)) if self.vm.write_memory(address, &bytes) => "OK".to_string(),
+                    _ => error,
+                }
+            },
+            // Software and hardware breakpoints work the same way here. Watchpoints aren't supported
+            "Z" | "z" => {
+                match rest.split(',').collect::<Vec<&str>>().as_slice() {
+                    [kind, address, _] if *kind == "0" || *kind == "1" => {
+                        match usize::from_str_radix(address, 16) {
+                            Ok(address) => {
+                                if command == "Z" {
+                                    self.breakpoints.insert(address);
+                                } else {
+                                    self.breakpoints.remove(&address);
+                                }
+                                "OK".to_string()
+                            },
+                            Err(_) => error,
+                        }
+                    },
+                    _ => String::new(),
+                }
+            },
+            "s" => {
+                if let Some(address) = parse_address(rest) {
+                    self.vm.set_pc(address);
+                }
+                self.resume(true, interrupted)
+            },
+            "c" => {
+                if let Some(address) = parse_address(rest) {
+                    self.vm.set_pc(address);
+                }
+                self.resume(false, interrupted)
+            },
+            "H" => "OK".to_string(),
+            "D" => { return None; },
+            "k" => { return None; },
+            "q" => self.query(rest),
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    fn query(&self, query: &str) -> String {
+        if query.starts_with("Supported") {
+            return "PacketSize=4000;qXfer:features:read+;swbreak+".to_string();
+        }
+        if let Some(request) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            let xml = target_description();
+            return match parse_pair(request, ',') {
+                Some((offset, length)) => {
+                    let start = offset.min(xml.len());
+                    let end = start.saturating_add(length).min(xml.len());
+                    format!("{}{}", if end < xml.len() { "m" } else { "l" }, &xml[start..end])
+                },
+                None => "E01".to_string(),
+            };
+        }
+        match query {
+            "Attached" => "1".to_string(),
+            "C" => "QC1".to_string(),
+            "fThreadInfo" => "m1".to_string(),
+            "sThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    /// Runs one instruction, or until a breakpoint if not stepping, and reports why it stopped. The instruction at the
+    /// pc always runs, so continuing from a breakpoint doesn't stop there again straight away
+    fn resume(&mut self, step: bool, interrupted: &mut dyn FnMut() -> bool) -> String {
+        let mut executed: u64 = 0;
+        loop {
+            let pc = self.vm.pc();
+            match self.vm.run_once() {
+                Ok(ExitReason::Stepped) => {},
+                Ok(ExitReason::OutOfFuel) => { return format!("S{:02x}", SIGINT); },
+                Ok(_) => { return "W00".to_string(); },
+                Err(e) => {
+                    // Leave the pc on the instruction that trapped, so the debugger shows where it went wrong
+                    self.vm.set_pc(pc);
+                    return format!("S{:02x}", signal_for(&e.trap));
+                }
+            }
+            executed += 1;
+            if self.breakpoints.contains(&self.vm.pc()) {
+                return format!("T{:02x}swbreak:;", SIGTRAP);
+            }
+            if step {
+                return format!("S{:02x}", SIGTRAP);
+            }
+            if executed.is_multiple_of(INTERRUPT_CHECK_INTERVAL) && interrupted() {
+                return format!("S{:02x}", SIGINT);
+            }
+        }
+    }
+
+    fn read_register(&self, register: usize) -> Vec<u8> {
+        let mut bytes = vec![0; register_size(register)];
+        match register {
+            PC_REGISTER => LittleEndian::write_u32(&mut bytes, self.vm.pc() as u32),
+            FLAGS_REGISTER => LittleEndian::write_u32(&mut bytes, self.vm.flags().to_byte() as u32),
+            r if r >= FLOAT_REGISTERS => LittleEndian::write_f64(&mut bytes, self.vm.float_registers[r - FLOAT_REGISTERS]),
+            r => LittleEndian::write_i32(&mut bytes, self.vm.registers[r]),
+        }
+        bytes
+    }
+
+    fn write_register(&mut self, register: usize, value: &[u8]) {
+        match register {
+            PC_REGISTER => self.vm.set_pc(LittleEndian::read_u32(value) as usize),
+            FLAGS_REGISTER => self.vm.set_flags(Flags::from_byte(LittleEndian::read_u32(value) as u8)),
+            r if r >= FLOAT_REGISTERS => self.vm.float_registers[r - FLOAT_REGISTERS] = LittleEndian::read_f64(value),
+            r => self.vm.registers[r] = LittleEndian::read_i32(value),
+        }
+    }
+}
+
+fn register_size(register: usize) -> usize {
+    if register >= FLOAT_REGISTERS { 8 } else { 4 }
+}
+
+/// The optional address `s` and `c` packets resume from
+fn parse_address(text: &str) -> Option<usize> {
+    usize::from_str_radix(text, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::assembler::Assembler;
+    use crate::vm::HEAP_BASE;
+
+    fn stub(source: &str) -> GdbStub {
+        let mut program = Assembler::new().assemble(source).unwrap();
+        let mut vm = VM::new();
+        vm.add_bytes(&mut program).unwrap();
+        vm.restart();
+        GdbStub::new(vm)
+    }
+
+    fn send(stub: &mut GdbStub, packet: &str) -> String {
+        stub.handle(packet, &mut || false).unwrap()
+    }
+
+    #[test]
+    fn test_framing() {
+        assert_eq!(frame("OK"), "$OK#9a");
+        assert_eq!(frame("a$b"), "$a}\u{4}b#44");
+        let mut input = Cursor::new(b"+$g#67$m0,4#00\x03".to_vec());
+        assert_eq!(read_incoming(&mut input).unwrap(), Some(Incoming::Packet("g".to_string())));
+        assert_eq!(read_incoming(&mut input).unwrap(), Some(Incoming::Corrupt));
+        assert_eq!(read_incoming(&mut input).unwrap(), Some(Incoming::Interrupt));
+        assert_eq!(read_incoming(&mut input).unwrap(), None);
+    }
+
+    #[test]
+    fn test_registers() {
+        let mut stub = stub(".code\nload $0 #3\nhlt");
+        let registers = send(&mut stub, "g");
+        assert_eq!(registers.len(), (34 * 4 + 32 * 8) * 2);
+        assert_eq!(&registers[32 * 8..33 * 8], "40000000");
+
+        assert_eq!(send(&mut stub, "P1=2a000000"), "OK");
+        assert_eq!(stub.vm.registers[1], 42);
+        assert_eq!(send(&mut stub, "p1"), "2a000000");
+        assert_eq!(send(&mut stub, "P21=05000000"), "OK");
+        assert_eq!(stub.vm.flags(), Flags { equal: true, comparison: Some(std::cmp::Ordering::Equal) });
+        assert_eq!(send(&mut stub, "P22=000000000000f83f"), "OK");
+        assert_eq!(stub.vm.float_registers[0], 1.5);
+        assert_eq!(send(&mut stub, "P1=2a"), "E01");
+        assert_eq!(send(&mut stub, "p99"), "E01");
+    }
+
+    #[test]
+    fn test_memory() {
+        let mut stub = stub(".data\nhi: .asciiz 'Hi'\n.code\nload $0 #4\naloc $0\nhlt");
+        assert_eq!(send(&mut stub, "m40,2"), "4869");
+        assert_eq!(send(&mut stub, "M40,1:4a"), "OK");
+        assert_eq!(stub.vm.read_memory(0x40, 2), Some(&b"Ji"[..]));
+        assert_eq!(send(&mut stub, "c"), "W00");
+        let heap = format!("{:x}", HEAP_BASE);
+        assert_eq!(send(&mut stub, &format!("M{},2:0102", heap)), "OK");
+        assert_eq!(send(&mut stub, &format!("m{},4", heap)), "01020000");
+        assert_eq!(send(&mut stub, &format!("m{},5", heap)), "E01");
+    }
+
+    #[test]
+    fn test_breakpoints_and_stepping() {
+        let mut stub = stub(".code\nload $0 #0\nloop: inc $0\njmp @loop");
+        assert_eq!(send(&mut stub, "s"), "S05");
+        assert_eq!(stub.vm.registers[0], 0);
+        assert_eq!(send(&mut stub, "Z0,44,4"), "OK");
+        assert_eq!(send(&mut stub, "c"), "T05swbreak:;");
+        assert_eq!(stub.vm.pc(), 0x44);
+        assert_eq!(stub.vm.registers[0], 1);
+        assert_eq!(send(&mut stub, "c"), "T05swbreak:;");
+        assert_eq!(stub.vm.registers[0], 2);
+        assert_eq!(send(&mut stub, "z0,44,4"), "OK");
+        assert_eq!(send(&mut stub, "Z2,44,4"), "");
+        assert_eq!(stub.handle("c", &mut || true), Some("S02".to_string()));
+    }
+
+    #[test]
+    fn test_trap_leaves_pc_on_instruction() {
+        let mut stub = stub(".code\nload $0 #1\ndiv $0 $1 $2\nhlt");
+        assert_eq!(send(&mut stub, "c"), "S08");
+        assert_eq!(stub.vm.pc(), 0x44);
+    }
+
+    #[test]
+    fn test_queries() {
+        let mut stub = stub(".code\nhlt");
+        assert!(send(&mut stub, "qSupported:multiprocess+").contains("qXfer:features:read+"));
+        let xml = target_description();
+        let first = send(&mut stub, "qXfer:features:read:target.xml:0,10");
+        assert_eq!(first, format!("m{}", &xml[..16]));
+        let rest = send(&mut stub, &format!("qXfer:features:read:target.xml:10,{:x}", xml.len()));
+        assert_eq!(rest, format!("l{}", &xml[16..]));
+        assert!(xml.contains("<architecture>riscv:rv32</architecture>"));
+        assert!(xml.contains("<reg name=\"x31\" bitsize=\"32\" type=\"int\" regnum=\"31\"/>"));
+        assert!(xml.contains("<reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\" regnum=\"32\"/>"));
+        assert!(xml.contains("<reg name=\"f31\" bitsize=\"64\" type=\"ieee_double\" regnum=\"65\"/>"));
+        assert_eq!(send(&mut stub, "qXfer:features:read:target.xml:10,ffffffffffffffff"), format!("l{}", &xml[16..]));
+        assert_eq!(send(&mut stub, "vMustReplyEmpty"), "");
+        assert_eq!(stub.handle("D", &mut || false), None);
+    }
+
+    /// Plays the packets `gdb-multiarch` sends for `target remote`, `info registers`, `x`, `break`, `continue`,
+    /// `stepi` and `detach` over a real socket
+    #[test]
+    fn test_gdb_session() {
+        let mut stub = stub(".code\nload $0 #3\nloop: dec $0\nneq $0 $1\njmpe @loop\nhlt");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let gdb = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.set_nodelay(true).unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let packets = [
+                "qSupported:multiprocess+;swbreak+;hwbreak+;qRelocInsn+;vContSupported+;QThreadEvents+;no-resumed+",
+                "vMustReplyEmpty", "QStartNoAckMode", "Hg0", "qXfer:features:read:target.xml:0,3ffb", "qTStatus", "?",
+                "qfThreadInfo", "qsThreadInfo", "qC", "qAttached", "g", "m40,4", "Z0,44,4", "vCont?", "c", "p20",
+                "z0,44,4", "s", "p20", "p0", "D",
+            ];
+            let mut replies = vec![];
+            for packet in packets {
+                stream.write_all(frame(packet).as_bytes()).unwrap();
+                match read_incoming(&mut reader).unwrap() {
+                    Some(Incoming::Packet(reply)) => { replies.push(reply); },
+                    other => panic!("Expected a reply to {}, got {:?}", packet, other),
+                }
+                stream.write_all(b"+").unwrap();
+            }
+            replies
+        });
+        let (stream, _) = listener.accept().unwrap();
+        stub.session(stream).unwrap();
+        let replies = gdb.join().unwrap();
+
+        assert!(replies[0].contains("qXfer:features:read+"));
+        assert_eq!(replies[4], format!("l{}", target_description()));
+        assert_eq!(replies[6], "S05");
+        // 32 integer registers, pc and flags, then 32 doubles
+        assert_eq!(replies[11].len(), (34 * 4 + 32 * 8) * 2);
+        assert_eq!(replies[12], "00000003");
+        assert_eq!(replies[15], "T05swbreak:;");
+        assert_eq!(replies[16], "44000000");
+        assert_eq!(replies[18], "S05");
+        assert_eq!(replies[19], "48000000");
+        assert_eq!(replies[20], "02000000");
+        assert_eq!(replies[21], "OK");
+    }
+}
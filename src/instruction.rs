@@ -1,92 +1,249 @@
+#![allow(clippy::redundant_field_names)]
+
+use std::fmt;
+
 use nom::types::CompleteStr;
 
+/// Every instruction is padded out to the same number of bytes
+pub const INSTRUCTION_LENGTH: usize = 4;
+
+/// How many registers there are of each kind, integer and float
+pub const REGISTER_COUNT: usize = 32;
+
+/// Declares the instruction set. Each entry gives the opcode's number, its mnemonic, the operands that follow it and a
+/// description, and everything else about the opcode is generated from that, so the assembler, the VM and the
+/// disassembler can't disagree about it
+macro_rules! isa {
+  ($($name:ident = $code:literal, $mnemonic:literal, [$($operand:ident),*], $description:literal;)*) => {
+    #[derive(Debug, PartialEq, Copy, Clone)]
+    pub enum Opcode {
+      $(
+        #[doc = $description]
+        $name = $code,
+      )*
+    }
+
+    /// Every opcode, in numeric order
+    pub const OPCODES: &[Opcode] = &[$(Opcode::$name),*];
+
+    impl From<u8> for Opcode {
+      fn from(v: u8) -> Self {
+        match v {
+          $($code => Opcode::$name,)*
+          _ => Opcode::IGL,
+        }
+      }
+    }
+
+    impl<'a> From<CompleteStr<'a>> for Opcode {
+      fn from(v: CompleteStr<'a>) -> Self {
+        Opcode::from_mnemonic(v.0).unwrap_or(Opcode::IGL)
+      }
+    }
+
+    impl Opcode {
+      /// The opcode the assembler knows by a name, if there is one
+      pub fn from_mnemonic(mnemonic: &str) -> Option<Opcode> {
+        match mnemonic {
+          $($mnemonic => Some(Opcode::$name),)*
+          _ => None,
+        }
+      }
+
+      /// The name the assembler knows this opcode by
+      pub fn mnemonic(&self) -> &'static str {
+        match self {
+          $(Opcode::$name => $mnemonic,)*
+        }
+      }
+
+      /// What each operand of this opcode is, in order. Whatever bytes they leave in the instruction are padding
+      pub fn operands(&self) -> &'static [OperandKind] {
+        match self {
+          $(Opcode::$name => &[$(OperandKind::$operand),*],)*
+        }
+      }
+
+      /// A one line summary of what the opcode does
+      pub fn description(&self) -> &'static str {
+        match self {
+          $(Opcode::$name => $description,)*
+        }
+      }
+    }
+  };
+}
+
+isa! {
+  LOAD = 0, "load", [Register, Immediate16], "Sets a register to an unsigned 16-bit number";
+  ADD = 1, "add", [Register, Register, Register], "Adds two registers into a third";
+  SUB = 2, "sub", [Register, Register, Register], "Subtracts the second register from the first into a third";
+  MUL = 3, "mul", [Register, Register, Register], "Multiplies two registers into a third";
+  DIV = 4, "div", [Register, Register, Register], "Divides the first register by the second into a third, keeping the remainder";
+  HLT = 5, "hlt", [], "Stops the program";
+  JMP = 6, "jmp", [Register], "Jumps to the address in a register";
+  JMPF = 7, "jmpf", [Register], "Jumps forward from the next instruction by the number of bytes in a register";
+  JMPB = 8, "jmpb", [Register], "Jumps backward from the next instruction by the number of bytes in a register";
+  IGL = 9, "igl", [], "Raises an illegal opcode trap";
+  EQ = 10, "eq", [Register, Register], "Sets the equal flag if two registers are equal";
+  NEQ = 11, "neq", [Register, Register], "Sets the equal flag if two registers differ";
+  GT = 12, "gt", [Register, Register], "Sets the equal flag if the first register is greater than the second";
+  LT = 13, "lt", [Register, Register], "Sets the equal flag if the first register is less than the second";
+  GTE = 14, "gte", [Register, Register], "Sets the equal flag if the first register is at least the second";
+  LTE = 15, "lte", [Register, Register], "Sets the equal flag if the first register is at most the second";
+  JMPE = 16, "jmpe", [Register], "Jumps to the address in a register if the equal flag is set";
+  INC = 17, "inc", [Register], "Adds one to a register";
+  DEC = 18, "dec", [Register], "Subtracts one from a register";
+  ALOC = 19, "aloc", [Register], "Grows the heap by the number of bytes in a register";
+  DJMP = 20, "djmp", [CodeAddress], "Jumps to an address";
+  DJMPE = 21, "djmpe", [CodeAddress], "Jumps to an address if the equal flag is set";
+  CALL = 22, "call", [CodeAddress], "Calls the subroutine at an address";
+  RET = 23, "ret", [], "Returns from the current subroutine";
+  PUSH = 24, "push", [Register], "Pushes a register onto the stack";
+  POP = 25, "pop", [Register], "Pops the top of the stack into a register";
+  LB = 26, "lb", [Register, Register, Immediate8], "Loads a byte from the heap at a base register plus an offset";
+  LH = 27, "lh", [Register, Register, Immediate8], "Loads a halfword from the heap at a base register plus an offset";
+  LW = 28, "lw", [Register, Register, Immediate8], "Loads a word from the heap at a base register plus an offset";
+  SB = 29, "sb", [Register, Register, Immediate8], "Stores a byte to the heap at a base register plus an offset";
+  SH = 30, "sh", [Register, Register, Immediate8], "Stores a halfword to the heap at a base register plus an offset";
+  SW = 31, "sw", [Register, Register, Immediate8], "Stores a word to the heap at a base register plus an offset";
+  PRTS = 32, "prts", [StringOffset], "Prints the string at an offset into the read-only section";
+  MOD = 33, "mod", [Register, Register, Register], "Takes the remainder of dividing the first register by the second into a third, with the sign of the first";
+  NEG = 34, "neg", [Register, Register], "Negates the first register into the second";
+  AND = 35, "and", [Register, Register, Register], "Bitwise ands two registers into a third";
+  OR = 36, "or", [Register, Register, Register], "Bitwise ors two registers into a third";
+  XOR = 37, "xor", [Register, Register, Register], "Bitwise exclusive ors two registers into a third";
+  NOT = 38, "not", [Register, Register], "Flips every bit of the first register into the second";
+  SHL = 39, "shl", [Register, Register, Register], "Shifts the first register left by the second, modulo 32, into a third";
+  SHR = 40, "shr", [Register, Register, Register], "Shifts the first register right by the second, modulo 32, filling with zeroes, into a third";
+  SAR = 41, "sar", [Register, Register, Register], "Shifts the first register right by the second, modulo 32, filling with the sign bit, into a third";
+  RMDR = 42, "rmdr", [Register], "Copies the remainder of the last DIV into a register";
+  LOADF = 43, "loadf", [FloatRegister, ConstantOffset], "Sets a float register to the 64-bit float at an offset into the read-only section";
+  ADDF = 44, "addf", [FloatRegister, FloatRegister, FloatRegister], "Adds two float registers into a third";
+  SUBF = 45, "subf", [FloatRegister, FloatRegister, FloatRegister], "Subtracts the second float register from the first into a third";
+  MULF = 46, "mulf", [FloatRegister, FloatRegister, FloatRegister], "Multiplies two float registers into a third";
+  DIVF = 47, "divf", [FloatRegister, FloatRegister, FloatRegister], "Divides the first float register by the second into a third";
+  EQF = 48, "eqf", [FloatRegister, FloatRegister], "Sets the equal flag if two float registers are equal";
+  NEQF = 49, "neqf", [FloatRegister, FloatRegister], "Sets the equal flag if two float registers differ";
+  GTF = 50, "gtf", [FloatRegister, FloatRegister], "Sets the equal flag if the first float register is greater than the second";
+  LTF = 51, "ltf", [FloatRegister, FloatRegister], "Sets the equal flag if the first float register is less than the second";
+  GTEF = 52, "gtef", [FloatRegister, FloatRegister], "Sets the equal flag if the first float register is at least the second";
+  LTEF = 53, "ltef", [FloatRegister, FloatRegister], "Sets the equal flag if the first float register is at most the second";
+  ITOF = 54, "itof", [Register, FloatRegister], "Converts a register into a float register";
+  FTOI = 55, "ftoi", [FloatRegister, Register], "Converts a float register into a register, rounding toward zero";
+  LOADHI = 56, "loadhi", [Register, Immediate16], "Sets the top 16 bits of a register, keeping the bottom 16";
+  JNEQ = 57, "jneq", [CodeAddress], "Jumps to an address if the equal flag is clear";
+  JGT = 58, "jgt", [CodeAddress], "Jumps to an address if the last comparison found the first operand greater";
+  JLT = 59, "jlt", [CodeAddress], "Jumps to an address if the last comparison found the first operand less";
+  JGTE = 60, "jgte", [CodeAddress], "Jumps to an address if the last comparison found the first operand greater or equal";
+  JLTE = 61, "jlte", [CodeAddress], "Jumps to an address if the last comparison found the first operand less or equal";
+  DJMPF = 62, "djmpf", [Displacement], "Jumps forward from the next instruction by a number of bytes";
+  DJMPB = 63, "djmpb", [Displacement], "Jumps backward from the next instruction by a number of bytes";
+}
+
+impl fmt::Display for Opcode {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.mnemonic())
+  }
+}
+
+/// The kinds of operand an instruction can carry after its opcode
 #[derive(Debug, PartialEq, Copy, Clone)]
-pub enum Opcode {
-  LOAD,
-  ADD,
-  SUB,
-  MUL,
-  DIV,
-  HLT,
-  JMP,
-  JMPF, // 7
-  JMPB,
-  IGL,
-  EQ, // 9
-  NEQ,
-  GT,
-  LT,
-  GTQ,
-  LTQ,
-  JEQ,
-  INC,
-  DEC,
-  ALOC
+pub enum OperandKind {
+  Register,
+  FloatRegister,
+  // A number, or a label whose address fits in 16 bits
+  Immediate16,
+  Immediate8,
+  // An absolute address in the code
+  CodeAddress,
+  // An offset into the read-only section
+  StringOffset,
+  // A 16-bit offset into the read-only section where a float is kept
+  ConstantOffset,
+  // How far a relative jump goes from the start of the next instruction
+  Displacement,
 }
 
-impl From<u8> for Opcode {
-  fn from(v: u8) -> Self {
-    match v {
-      0 => return Opcode::LOAD,
-      1 => return Opcode::ADD,
-      2 => return Opcode::SUB,
-      3 => return Opcode::MUL,
-      4 => return Opcode::DIV,
-      5 => return Opcode::HLT,
-      6 => return Opcode::JMP,
-      7 => return Opcode::JMPF,
-      8 => return Opcode::JMPB,
-      9 => return Opcode::IGL,
-      10 => return Opcode::EQ,
-      11 => return Opcode::NEQ,
-      12 => return Opcode::GT,
-      13 => return Opcode::LT,
-      14 => return Opcode::GTQ,
-      15 => return Opcode::LTQ,
-      16 => return Opcode::JEQ,
-      17 => return Opcode::INC,
-      18 => return Opcode::DEC,
-      19 => return Opcode::ALOC,
-      _ => return Opcode::IGL,
+impl OperandKind {
+  /// How many bytes of the instruction this operand takes up
+  pub fn width(&self) -> usize {
+    match self {
+      OperandKind::Register | OperandKind::FloatRegister | OperandKind::Immediate8 => 1,
+      OperandKind::Immediate16 | OperandKind::ConstantOffset => 2,
+      OperandKind::CodeAddress | OperandKind::StringOffset | OperandKind::Displacement => 3,
+    }
+  }
+
+  /// The smallest and largest numbers that can be written for this operand. A 16-bit immediate is only ever a bit
+  /// pattern, so it can be written signed or unsigned
+  pub fn range(&self) -> (i64, i64) {
+    match self {
+      OperandKind::Immediate16 => (i16::MIN as i64, u16::MAX as i64),
+      _ => (0, (1 << (self.width() * 8)) - 1),
     }
   }
 }
 
-impl<'a> From<CompleteStr<'a>> for Opcode {
-  fn from(v: CompleteStr<'a>) -> Self {
-    match v {
-      CompleteStr("load") => Opcode::LOAD,
-      CompleteStr("add") => Opcode::ADD,
-      CompleteStr("sub") => Opcode::SUB,
-      CompleteStr("mul") => Opcode::MUL,
-      CompleteStr("div") => Opcode::DIV,
-      CompleteStr("hlt") => Opcode::HLT,
-      CompleteStr("jmp") => Opcode::JMP,
-      CompleteStr("jmpf") => Opcode::JMPF,
-      CompleteStr("jmpb") => Opcode::JMPB,
-      CompleteStr("eq") => Opcode::EQ,
-      CompleteStr("neq") => Opcode::NEQ,
-      CompleteStr("gte") => Opcode::GTQ,
-      CompleteStr("gt") => Opcode::GT,
-      CompleteStr("lte") => Opcode::LTQ,
-      CompleteStr("lt") => Opcode::LT,
-      CompleteStr("jmpe") => Opcode::JEQ,
-      CompleteStr("aloc") => Opcode::ALOC,
-      CompleteStr("inc") => Opcode::INC,
-      CompleteStr("dec") => Opcode::DEC,
-      _ => Opcode::IGL,
+impl fmt::Display for OperandKind {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      OperandKind::Register => write!(f, "a register"),
+      OperandKind::FloatRegister => write!(f, "a float register"),
+      OperandKind::Immediate16 => write!(f, "a 16-bit number or label"),
+      OperandKind::Immediate8 => write!(f, "an 8-bit number"),
+      OperandKind::CodeAddress => write!(f, "a code label"),
+      OperandKind::StringOffset => write!(f, "a string label"),
+      OperandKind::ConstantOffset => write!(f, "a float or a constant label"),
+      OperandKind::Displacement => write!(f, "a code label or a distance"),
     }
   }
 }
 
-#[derive(Debug, PartialEq)]
+/// An opcode along with the values of its operands
+#[derive(Debug, PartialEq, Clone)]
 pub struct Instruction {
-  opcode: Opcode,
+  pub opcode: Opcode,
+  pub operands: Vec<u32>,
 }
 
 impl Instruction {
   pub fn new(opcode: Opcode) -> Instruction {
-    Instruction { opcode: opcode }
+    Instruction { opcode: opcode, operands: vec![] }
+  }
+
+  pub fn add_operand(&mut self, value: u32) {
+    self.operands.push(value);
+  }
+
+  /// Reads an instruction, which must be at least `INSTRUCTION_LENGTH` bytes. Anything past the operands is ignored
+  pub fn decode(bytes: &[u8]) -> Instruction {
+    let mut instruction = Instruction::new(Opcode::from(bytes[0]));
+    let mut position = 1;
+    for kind in instruction.opcode.operands() {
+      let mut value = 0;
+      for byte in &bytes[position..position + kind.width()] {
+        value = (value << 8) | *byte as u32;
+      }
+      instruction.add_operand(value);
+      position += kind.width();
+    }
+    instruction
+  }
+
+  /// Lays the instruction out as the VM expects, with every operand big-endian. Operands too wide for their place in
+  /// the instruction are truncated
+  pub fn encode(&self) -> [u8; INSTRUCTION_LENGTH] {
+    let mut bytes = [0; INSTRUCTION_LENGTH];
+    bytes[0] = self.opcode as u8;
+    let mut position = 1;
+    for (kind, value) in self.opcode.operands().iter().zip(&self.operands) {
+      for shift in (0..kind.width()).rev() {
+        bytes[position] = (value >> (shift * 8)) as u8;
+        position += 1;
+      }
+    }
+    bytes
   }
 }
 
@@ -114,6 +271,45 @@ mod tests {
         assert_eq!(opcode, Opcode::IGL);
     }
 
+    #[test]
+    fn test_mnemonic_round_trip() {
+        for byte in 0..=255 {
+            let opcode = Opcode::from(byte);
+            assert_eq!(Opcode::from(CompleteStr(opcode.mnemonic())), opcode);
+        }
+    }
+
+    #[test]
+    fn test_operands_fit_in_instruction() {
+        for opcode in OPCODES {
+            let width: usize = opcode.operands().iter().map(|o| o.width()).sum();
+            assert!(width < INSTRUCTION_LENGTH, "{:?} has {} bytes of operands", opcode, width);
+        }
+    }
+
+    #[test]
+    fn test_opcode_numbers() {
+        for (number, opcode) in OPCODES.iter().enumerate() {
+            assert_eq!(*opcode as usize, number);
+            assert_eq!(Opcode::from(number as u8), *opcode);
+        }
+        assert_eq!(Opcode::from(200), Opcode::IGL);
+        assert_eq!(Opcode::GTE.to_string(), "gte");
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let mut instruction = Instruction::new(Opcode::LOAD);
+        instruction.add_operand(3);
+        instruction.add_operand(500);
+        assert_eq!(instruction.encode(), [0, 3, 1, 244]);
+        assert_eq!(Instruction::decode(&instruction.encode()), instruction);
+        let mut instruction = Instruction::new(Opcode::CALL);
+        instruction.add_operand(70000);
+        assert_eq!(Instruction::decode(&instruction.encode()), instruction);
+        assert_eq!(Instruction::decode(&[5, 0, 0, 0]), Instruction::new(Opcode::HLT));
+    }
+
     #[test]
     fn test_str_to_opcode_numeric() {
         let opcode = Opcode::from(CompleteStr("inc"));
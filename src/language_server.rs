@@ -0,0 +1,658 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::{BufRead, Write};
+
+use nom::types::CompleteStr;
+use serde_json::{json, Value};
+
+use crate::assembler::diagnostics::{LineIndex, SourceLocation};
+use crate::assembler::instruction_parsers::AssemblerInstruction;
+use crate::assembler::program_parsers::{program, Program};
+use crate::assembler::source_map::SourceMap;
+use crate::assembler::{Assembler, AssemblerError, SymbolTable, Token, INSTRUCTION_LENGTH};
+use crate::debug_adapter::{read_message, write_message};
+use crate::instruction::{Opcode, OperandKind, OPCODES};
+
+// JSON-RPC error codes
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+// The protocol's numbers for the kinds of symbol and completion we hand out
+const SYMBOL_FUNCTION: i64 = 12;
+const SYMBOL_CONSTANT: i64 = 14;
+const COMPLETION_VARIABLE: i64 = 6;
+const COMPLETION_KEYWORD: i64 = 14;
+const COMPLETION_REFERENCE: i64 = 18;
+
+/// Every directive the assembler understands, along with what it does
+const DIRECTIVES: &[(&str, &str)] = &[
+    ("data", "Starts the read-only section"),
+    ("code", "Starts the code section"),
+    ("asciiz", "Declares a null-terminated string in the read-only section"),
+];
+
+/// How an operand is written in an opcode's signature, and what fills each of its bytes in the encoding
+fn placeholder(kind: OperandKind) -> (&'static str, &'static str) {
+    match kind {
+        OperandKind::Register => ("$reg", "rr"),
+        OperandKind::FloatRegister => ("$freg", "ff"),
+        OperandKind::Immediate16 => ("#imm16", "ii"),
+        OperandKind::Immediate8 => ("#imm8", "ii"),
+        OperandKind::CodeAddress => ("@label", "aa"),
+        OperandKind::StringOffset => ("@string", "ss"),
+        OperandKind::ConstantOffset => ("#float", "cc"),
+        OperandKind::Displacement => ("@label", "dd"),
+    }
+}
+
+/// How an opcode is written, like `lw $reg $reg #imm8`
+fn signature(code: Opcode) -> String {
+    let mut signature = code.mnemonic().to_string();
+    for kind in code.operands() {
+        signature.push(' ');
+        signature.push_str(placeholder(*kind).0);
+    }
+    signature
+}
+
+/// The signature, description and byte layout of an opcode, as Markdown
+fn describe_opcode(code: Opcode) -> String {
+    let mut encoding = vec![format!("{:02x}", code as u8)];
+    for kind in code.operands() {
+        encoding.extend((0..kind.width()).map(|_| placeholder(*kind).1.to_string()));
+    }
+    encoding.resize(INSTRUCTION_LENGTH, "00".to_string());
+    format!("```iasm\n{}\n```\n{}\n\nEncoding: `{}`", signature(code), code.description(), encoding.join(" "))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<String>>().join(" ")
+}
+
+/// Where a label is declared or used. `start` and `end` cover just its name
+#[derive(Debug, PartialEq)]
+struct Occurrence {
+    name: String,
+    start: usize,
+    end: usize,
+    declaration: bool,
+}
+
+/// A file the client has open, along with everything worked out from it when it last changed
+struct Document {
+    text: String,
+    // Whatever of the file parses, which is everything up to the first thing that doesn't
+    program: Option<Program>,
+    symbols: SymbolTable,
+    errors: Vec<AssemblerError>,
+    // The image and where each of its instructions came from, if it assembled
+    image: Option<(Vec<u8>, SourceMap)>,
+}
+
+impl Document {
+    fn new(uri: &str, text: String) -> Document {
+        let program = program(CompleteStr(&text)).ok().map(|(_, program)| program);
+        let mut asm = Assembler::new();
+        asm.set_file_name(uri);
+        let (image, errors) = match asm.assemble(&text) {
+            Ok(image) => (Some((image, asm.source_map)), vec![]),
+            Err(errors) => (None, errors),
+        };
+        Document { text, program, symbols: asm.symbols, errors, image }
+    }
+
+    fn instructions(&self) -> impl Iterator<Item = (&AssemblerInstruction, &SourceLocation)> {
+        self.program.iter().flat_map(|program| program.instructions.iter().zip(&program.locations))
+    }
+
+    /// The instruction the byte at `offset` is part of
+    fn instruction_at(&self, offset: usize) -> Option<(&AssemblerInstruction, &SourceLocation)> {
+        self.instructions().find(|(_, location)| location.offset <= offset && offset <= location.offset + location.length)
+    }
+
+    fn span(&self, location: &SourceLocation) -> &str {
+        &self.text[location.offset..location.offset + location.length]
+    }
+
+    /// Every label declaration and use, in the order they appear
+    fn occurrences(&self) -> Vec<Occurrence> {
+        let mut occurrences = vec![];
+        for (instruction, location) in self.instructions() {
+            let span = self.span(location);
+            // Instructions are located from their first character, which is the label if there is one
+            if let Some(name) = instruction.label_name().filter(|name| span.starts_with(name.as_str())) {
+                occurrences.push(Occurrence { start: location.offset, end: location.offset + name.len(), name, declaration: true });
+            }
+            let mut used: Vec<&String> = vec![];
+            for operand in [&instruction.operand1, &instruction.operand2, &instruction.operand3] {
+                if let Some(Token::LabelUsage { name }) = operand {
+                    if !used.contains(&name) {
+                        used.push(name);
+                    }
+                }
+            }
+            for name in used {
+                let usage = format!("@{}", name);
+                for (index, _) in span.match_indices(&usage) {
+                    // `@loop` shouldn't be found inside `@loop2`
+                    let end = index + usage.len();
+                    if span[end..].starts_with(|c: char| c.is_alphanumeric()) {
+                        continue;
+                    }
+                    let start = location.offset + index + 1;
+                    occurrences.push(Occurrence { name: name.clone(), start, end: start + name.len(), declaration: false });
+                }
+            }
+        }
+        occurrences
+    }
+
+    fn occurrence_at(&self, offset: usize) -> Option<Occurrence> {
+        self.occurrences().into_iter().find(|o| o.start <= offset && offset <= o.end)
+    }
+
+    /// The address an instruction was assembled to and the bytes it became, if the file assembled
+    fn assembled_bytes(&self, instruction: &AssemblerInstruction, location: &SourceLocation) -> Option<(usize, &[u8])> {
+        let (image, source_map) = self.image.as_ref()?;
+        let (address, _) = source_map.entries().iter().find(|(_, l)| l.offset == location.offset)?;
+        let address = *address as usize;
+        image.get(address..address + instruction.instruction_count() * INSTRUCTION_LENGTH).map(|bytes| (address, bytes))
+    }
+}
+
+/// Where a position in the protocol, which counts lines and characters from 0, is in some text. Characters are
+/// counted as chars rather than UTF-16 units, which only matters outside the Basic Multilingual Plane
+fn offset_at(text: &str, position: &Value) -> Option<usize> {
+    let line = position["line"].as_u64()? as usize;
+    let character = position["character"].as_u64()? as usize;
+    let line_start = match line {
+        0 => 0,
+        _ => text.match_indices('\n').nth(line - 1)?.0 + 1,
+    };
+    let line_text = text[line_start..].split('\n').next().unwrap_or("");
+    // Past the end of the line means the end of the line
+    Some(line_start + line_text.char_indices().nth(character).map(|(i, _)| i).unwrap_or(line_text.len()))
+}
+
+fn position(text: &str, offset: usize) -> Value {
+    let location = LineIndex::new(text).location(offset, 0);
+    json!({ "line": location.line - 1, "character": location.column - 1 })
+}
+
+fn range(text: &str, start: usize, end: usize) -> Value {
+    json!({ "start": position(text, start), "end": position(text, end) })
+}
+
+/// Where the word ending at `offset` starts, counting any `$`, `@`, `.` or `#` in front of it
+fn word_start(text: &str, offset: usize) -> usize {
+    text[..offset].rfind(|c: char| !(c.is_alphanumeric() || "$@.#".contains(c))).map(|i| i + 1).unwrap_or(0)
+}
+
+fn word_end(text: &str, offset: usize) -> usize {
+    text[offset..].find(|c: char| !c.is_alphanumeric()).map(|i| offset + i).unwrap_or(text.len())
+}
+
+/// Checks and navigates `.iasm` files for an editor through the Language Server Protocol. Every file is reassembled
+/// whenever it changes, which is cheap enough for anything written by hand
+pub struct LanguageServer {
+    documents: HashMap<String, Document>,
+    // Waiting to be sent, in order
+    outgoing: Vec<Value>,
+    shutting_down: bool,
+    exited: bool,
+}
+
+impl Default for LanguageServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LanguageServer {
+    pub fn new() -> LanguageServer {
+        LanguageServer {
+            documents: HashMap::new(),
+            outgoing: vec![],
+            shutting_down: false,
+            exited: false,
+        }
+    }
+
+    /// Handles messages until the client says to exit or hangs up
+    pub fn serve(&mut self, mut input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        while !self.exited {
+            let message = match read_message(&mut input)? {
+                Some(message) => message,
+                None => { break; }
+            };
+            debug!("lsp: {}", message);
+            self.handle(&message);
+            for message in self.outgoing.drain(..) {
+                write_message(&mut output, &message)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Answers a request or acts on a notification, queueing anything that should go back to the client
+    pub fn handle(&mut self, message: &Value) {
+        let method = message["method"].as_str().unwrap_or("");
+        let params = &message["params"];
+        // Only requests have an id, and notifications never get a response
+        let id = match message.get("id") {
+            Some(id) => id.clone(),
+            None => {
+                self.notification(method, params);
+                return;
+            }
+        };
+        let result = if self.shutting_down {
+            Err((INVALID_REQUEST, "the server is shutting down".to_string()))
+        } else {
+            match method {
+                "initialize" => Ok(json!({
+                    "capabilities": {
+                        // Whole files are sent on every change
+                        "textDocumentSync": 1,
+                        "definitionProvider": true,
+                        "referencesProvider": true,
+                        "hoverProvider": true,
+                        "completionProvider": { "triggerCharacters": ["$", "@", "."] },
+                        "documentSymbolProvider": true,
+                    },
+                    "serverInfo": { "name": "iridium" },
+                })),
+                "shutdown" => {
+                    self.shutting_down = true;
+                    Ok(Value::Null)
+                },
+                "textDocument/definition" => self.definition(params),
+                "textDocument/references" => self.references(params),
+                "textDocument/hover" => self.hover(params),
+                "textDocument/completion" => self.completion(params),
+                "textDocument/documentSymbol" => self.document_symbols(params),
+                _ => Err((METHOD_NOT_FOUND, format!("{} is not supported", method))),
+            }
+        };
+        let mut response = json!({ "jsonrpc": "2.0", "id": id });
+        match result {
+            Ok(result) => { response["result"] = result; },
+            Err((code, message)) => { response["error"] = json!({ "code": code, "message": message }); }
+        }
+        self.outgoing.push(response);
+    }
+
+    /// Everything waiting to go to the client
+    pub fn take_outgoing(&mut self) -> Vec<Value> {
+        self.outgoing.drain(..).collect()
+    }
+
+    fn notification(&mut self, method: &str, params: &Value) {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("").to_string();
+        match method {
+            "exit" => { self.exited = true; },
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or("").to_string();
+                self.documents.insert(uri.clone(), Document::new(&uri, text));
+                self.publish_diagnostics(&uri);
+            },
+            "textDocument/didChange" => {
+                // With whole files being sent, the last change is the only one that matters
+                let changes = params["contentChanges"].as_array();
+                if let Some(text) = changes.and_then(|changes| changes.last()).and_then(|change| change["text"].as_str()) {
+                    self.documents.insert(uri.clone(), Document::new(&uri, text.to_string()));
+                    self.publish_diagnostics(&uri);
+                }
+            },
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                self.publish_diagnostics(&uri);
+            },
+            _ => {}
+        }
+    }
+
+    /// Tells the client about every error in a file, or that there are none once it's closed
+    fn publish_diagnostics(&mut self, uri: &str) {
+        let diagnostics: Vec<Value> = match self.documents.get(uri) {
+            Some(document) => document.errors.iter().map(|error| {
+                let location = error.location();
+                json!({
+                    "range": range(&document.text, location.offset, location.offset + location.length),
+                    "severity": 1,
+                    "source": "iridium",
+                    "message": error.to_string(),
+                })
+            }).collect(),
+            None => vec![],
+        };
+        self.outgoing.push(json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics },
+        }));
+    }
+
+    /// The document a request is about and where in it the cursor is
+    fn document<'a>(&'a self, params: &'a Value) -> Result<(&'a str, &'a Document), (i64, String)> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+        match self.documents.get(uri) {
+            Some(document) => Ok((uri, document)),
+            None => Err((INVALID_PARAMS, format!("{} is not open", uri))),
+        }
+    }
+
+    fn cursor(document: &Document, params: &Value) -> Result<usize, (i64, String)> {
+        offset_at(&document.text, &params["position"]).ok_or_else(|| (INVALID_PARAMS, "position is not in the document".to_string()))
+    }
+
+    fn definition(&self, params: &Value) -> Result<Value, (i64, String)> {
+        let (uri, document) = self.document(params)?;
+        let name = match document.occurrence_at(LanguageServer::cursor(document, params)?) {
+            Some(occurrence) => occurrence.name,
+            None => { return Ok(Value::Null); }
+        };
+        let declaration = document.occurrences().into_iter().find(|o| o.declaration && o.name == name);
+        Ok(match declaration {
+            Some(o) => json!({ "uri": uri, "range": range(&document.text, o.start, o.end) }),
+            None => Value::Null,
+        })
+    }
+
+    fn references(&self, params: &Value) -> Result<Value, (i64, String)> {
+        let (uri, document) = self.document(params)?;
+        let name = match document.occurrence_at(LanguageServer::cursor(document, params)?) {
+            Some(occurrence) => occurrence.name,
+            None => { return Ok(json!([])); }
+        };
+        let include_declaration = params["context"]["includeDeclaration"].as_bool().unwrap_or(true);
+        let locations: Vec<Value> = document.occurrences().into_iter()
+            .filter(|o| o.name == name && (include_declaration || !o.declaration))
+            .map(|o| json!({ "uri": uri, "range": range(&document.text, o.start, o.end) }))
+            .collect();
+        Ok(json!(locations))
+    }
+
+    /// Describes the opcode or label under the cursor
+    fn hover(&self, params: &Value) -> Result<Value, (i64, String)> {
+        let (_, document) = self.document(params)?;
+        let offset = LanguageServer::cursor(document, params)?;
+        let text = &document.text;
+        let hover = |contents: String, start: usize, end: usize| {
+            json!({ "contents": { "kind": "markdown", "value": contents }, "range": range(text, start, end) })
+        };
+
+        if let Some(occurrence) = document.occurrence_at(offset) {
+            let name = &occurrence.name;
+            let contents = if let Some((_, address)) = document.symbols.labels().find(|(n, _)| n == name) {
+                format!("Code label `{}` at address {}", name, address)
+            } else if let Some((_, offset)) = document.symbols.constants().find(|(n, _)| n == name) {
+                format!("Constant `{}` at offset {} of the read-only section", name, offset)
+            } else {
+                format!("`{}` is not declared", name)
+            };
+            return Ok(hover(contents, occurrence.start, occurrence.end));
+        }
+
+        let (instruction, location) = match document.instruction_at(offset) {
+            Some(found) => found,
+            None => { return Ok(Value::Null); }
+        };
+        let (start, end) = (word_start(text, offset), word_end(text, offset));
+        match &instruction.opcode {
+            Some(Token::Op { code }) if text[start..end].eq_ignore_ascii_case(code.mnemonic()) => {
+                let mut contents = describe_opcode(*code);
+                if let Some((address, bytes)) = document.assembled_bytes(instruction, location) {
+                    let groups: Vec<String> = bytes.chunks(INSTRUCTION_LENGTH).map(|bytes| format!("`{}`", hex(bytes))).collect();
+                    contents.push_str(&format!("\n\nAssembles to {} at address {}", groups.join(" "), address));
+                }
+                Ok(hover(contents, start, end))
+            },
+            _ => Ok(Value::Null),
+        }
+    }
+
+    /// Suggests whatever can go where the cursor is, going by how the word being typed starts
+    fn completion(&self, params: &Value) -> Result<Value, (i64, String)> {
+        let (_, document) = self.document(params)?;
+        let offset = LanguageServer::cursor(document, params)?;
+        let text = &document.text;
+        let start = word_start(text, offset);
+        let prefix = &text[start..offset];
+        let item = |label: String, kind: i64, detail: String, documentation: &str| json!({
+            "label": label,
+            "kind": kind,
+            "detail": detail,
+            "documentation": documentation,
+            "textEdit": { "range": range(text, start, offset), "newText": label },
+        });
+
+        let mut items = vec![];
+        if prefix.starts_with('$') {
+            for register in 0..32 {
+                items.push(item(format!("${}", register), COMPLETION_VARIABLE, format!("register {}", register), ""));
+            }
+        } else if prefix.starts_with('@') {
+            for declared in document.occurrences().iter().filter(|o| o.declaration) {
+                items.push(item(format!("@{}", declared.name), COMPLETION_REFERENCE, "label".to_string(), ""));
+            }
+        } else {
+            if !prefix.starts_with('.') {
+                for code in OPCODES {
+                    items.push(item(code.mnemonic().to_string(), COMPLETION_KEYWORD, signature(*code), code.description()));
+                }
+            }
+            for (name, description) in DIRECTIVES {
+                items.push(item(format!(".{}", name), COMPLETION_KEYWORD, "directive".to_string(), description));
+            }
+        }
+        // Mnemonics and directives are offered whatever case they're typed in, but labels are case sensitive
+        let lowered = prefix.to_ascii_lowercase();
+        items.retain(|item| {
+            let label = item["label"].as_str().unwrap();
+            match prefix.starts_with('@') {
+                true => label.starts_with(prefix),
+                false => label.to_ascii_lowercase().starts_with(&lowered),
+            }
+        });
+        Ok(json!(items))
+    }
+
+    /// Every label, as a function if it's in the code and a constant if it isn't
+    fn document_symbols(&self, params: &Value) -> Result<Value, (i64, String)> {
+        let (_, document) = self.document(params)?;
+        let text = &document.text;
+        let mut symbols = vec![];
+        for (instruction, location) in document.instructions() {
+            if let Some(name) = instruction.label_name() {
+                let (kind, detail) = match instruction.is_opcode() {
+                    true => (SYMBOL_FUNCTION, "label"),
+                    false => (SYMBOL_CONSTANT, "constant"),
+                };
+                symbols.push(json!({
+                    "name": name,
+                    "kind": kind,
+                    "detail": detail,
+                    "range": range(text, location.offset, location.offset + location.length),
+                    "selectionRange": range(text, location.offset, location.offset + name.len()),
+                }));
+            }
+        }
+        Ok(json!(symbols))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const URI: &str = "file:///counter.iasm";
+    const COUNTER: &str = ".data\nhello: .asciiz 'Hi'\n.code\nload $0 #0\nloop: inc $0\nprts @hello\njmp @loop\nhlt";
+
+    fn request(id: i64, method: &str, params: Value) -> Value {
+        json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params })
+    }
+
+    fn at(line: usize, character: usize) -> Value {
+        json!({ "textDocument": { "uri": URI }, "position": { "line": line, "character": character } })
+    }
+
+    /// A server with `source` open, and whatever it said about it already taken
+    fn server(source: &str) -> LanguageServer {
+        let mut server = LanguageServer::new();
+        server.handle(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": URI, "languageId": "iasm", "version": 1, "text": source } },
+        }));
+        server.take_outgoing();
+        server
+    }
+
+    fn result(server: &mut LanguageServer, message: Value) -> Value {
+        server.handle(&message);
+        server.take_outgoing().remove(0)["result"].clone()
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let mut server = server(COUNTER);
+        server.handle(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didChange",
+            "params": { "textDocument": { "uri": URI, "version": 2 }, "contentChanges": [{ "text": ".code\nload $0 #0\n  jmp @nowhere" }] },
+        }));
+        let messages = server.take_outgoing();
+        assert_eq!(messages[0]["method"], "textDocument/publishDiagnostics");
+        let diagnostic = &messages[0]["params"]["diagnostics"][0];
+        assert_eq!(diagnostic["message"], "symbol `nowhere` is not declared");
+        assert_eq!(diagnostic["range"], json!({ "start": { "line": 2, "character": 2 }, "end": { "line": 2, "character": 14 } }));
+
+        server.handle(&json!({ "jsonrpc": "2.0", "method": "textDocument/didClose", "params": { "textDocument": { "uri": URI } } }));
+        assert_eq!(server.take_outgoing()[0]["params"]["diagnostics"], json!([]));
+    }
+
+    #[test]
+    fn test_register_out_of_range() {
+        let mut server = LanguageServer::new();
+        server.handle(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": URI, "languageId": "iasm", "version": 1, "text": ".code\nload $300 #1\nhlt" } },
+        }));
+        let messages = server.take_outgoing();
+        let diagnostics = messages[0]["params"]["diagnostics"].as_array().unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0]["range"]["start"]["line"], 1);
+    }
+
+    #[test]
+    fn test_unknown_mnemonic() {
+        let mut server = LanguageServer::new();
+        server.handle(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": URI, "languageId": "iasm", "version": 1, "text": ".code\nloop: laod $1 #1" } },
+        }));
+        let diagnostic = &server.take_outgoing()[0]["params"]["diagnostics"][0];
+        assert_eq!(diagnostic["message"], "unknown instruction `laod`");
+        assert_eq!(diagnostic["range"], json!({ "start": { "line": 1, "character": 6 }, "end": { "line": 1, "character": 10 } }));
+    }
+
+    #[test]
+    fn test_definition_and_references() {
+        let mut server = server(COUNTER);
+        let definition = result(&mut server, request(1, "textDocument/definition", at(6, 7)));
+        assert_eq!(definition, json!({ "uri": URI, "range": { "start": { "line": 4, "character": 0 }, "end": { "line": 4, "character": 4 } } }));
+        assert_eq!(result(&mut server, request(2, "textDocument/definition", at(3, 1))), Value::Null);
+
+        let mut params = at(4, 2);
+        params["context"] = json!({ "includeDeclaration": false });
+        let references = result(&mut server, request(3, "textDocument/references", params));
+        assert_eq!(references, json!([{ "uri": URI, "range": { "start": { "line": 6, "character": 5 }, "end": { "line": 6, "character": 9 } } }]));
+        let references = result(&mut server, request(4, "textDocument/references", at(5, 7)));
+        assert_eq!(references.as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_hover() {
+        let mut server = server(COUNTER);
+        let hover = result(&mut server, request(1, "textDocument/hover", at(3, 2)));
+        let contents = hover["contents"]["value"].as_str().unwrap();
+        assert!(contents.starts_with("```iasm\nload $reg #imm16\n```\nSets a register"), "{}", contents);
+        assert!(contents.contains("Encoding: `00 rr ii ii`"), "{}", contents);
+        assert!(contents.contains("Assembles to `00 00 00 00` at address 67"), "{}", contents);
+
+        let hover = result(&mut server, request(2, "textDocument/hover", at(6, 6)));
+        assert_eq!(hover["contents"]["value"], "Code label `loop` at address 71");
+        assert_eq!(result(&mut server, request(3, "textDocument/hover", at(3, 6))), Value::Null);
+    }
+
+    #[test]
+    fn test_completion() {
+        let mut typing = server(".code\nlo\nadd $1");
+        let labels: Vec<String> = result(&mut typing, request(1, "textDocument/completion", at(1, 2))).as_array().unwrap()
+            .iter().map(|item| item["label"].as_str().unwrap().to_string()).collect();
+        assert_eq!(labels, vec!["load", "loadf", "loadhi"]);
+
+        let items = result(&mut typing, request(2, "textDocument/completion", at(2, 6)));
+        assert_eq!(items.as_array().unwrap().len(), 11);
+        assert_eq!(items[0]["textEdit"], json!({
+            "range": { "start": { "line": 2, "character": 4 }, "end": { "line": 2, "character": 6 } },
+            "newText": "$1",
+        }));
+
+        let mut directive = server(".data\nhi: .asciiz 'Hi'\n.c");
+        let items = result(&mut directive, request(3, "textDocument/completion", at(2, 2)));
+        assert_eq!(items.as_array().unwrap().iter().map(|item| item["label"].clone()).collect::<Vec<Value>>(), vec![json!(".code")]);
+
+        let mut shouting = server(".CO
+LO
+jmp @LO
+loop: hlt");
+        let labels = |server: &mut LanguageServer, id: i64, line: usize, character: usize| -> Vec<String> {
+            result(server, request(id, "textDocument/completion", at(line, character))).as_array().unwrap()
+                .iter().map(|item| item["label"].as_str().unwrap().to_string()).collect()
+        };
+        assert_eq!(labels(&mut shouting, 4, 0, 3), vec![".code"]);
+        assert_eq!(labels(&mut shouting, 5, 1, 2), vec!["load", "loadf", "loadhi"]);
+        assert!(labels(&mut shouting, 6, 2, 7).is_empty());
+    }
+
+    #[test]
+    fn test_document_symbols() {
+        let mut server = server(COUNTER);
+        let symbols = result(&mut server, request(1, "textDocument/documentSymbol", json!({ "textDocument": { "uri": URI } })));
+        assert_eq!(symbols[0]["name"], "hello");
+        assert_eq!(symbols[0]["kind"], SYMBOL_CONSTANT);
+        assert_eq!(symbols[1]["name"], "loop");
+        assert_eq!(symbols[1]["kind"], SYMBOL_FUNCTION);
+        assert_eq!(symbols[1]["selectionRange"]["end"], json!({ "line": 4, "character": 4 }));
+    }
+
+    #[test]
+    fn test_session() {
+        let mut input = vec![];
+        write_message(&mut input, &request(1, "initialize", json!({ "capabilities": {} }))).unwrap();
+        write_message(&mut input, &request(2, "textDocument/formatting", json!({}))).unwrap();
+        write_message(&mut input, &request(3, "shutdown", Value::Null)).unwrap();
+        write_message(&mut input, &request(4, "textDocument/hover", at(0, 0))).unwrap();
+        write_message(&mut input, &json!({ "jsonrpc": "2.0", "method": "exit" })).unwrap();
+        write_message(&mut input, &request(5, "shutdown", Value::Null)).unwrap();
+        let mut output = vec![];
+        LanguageServer::new().serve(Cursor::new(input), &mut output).unwrap();
+
+        let mut output = Cursor::new(output);
+        let mut responses = vec![];
+        while let Some(message) = read_message(&mut output).unwrap() {
+            responses.push(message);
+        }
+        assert_eq!(responses.len(), 4);
+        assert_eq!(responses[0]["result"]["capabilities"]["hoverProvider"], true);
+        assert_eq!(responses[1]["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(responses[2]["result"], Value::Null);
+        assert_eq!(responses[3]["error"]["code"], INVALID_REQUEST);
+    }
+}
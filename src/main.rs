@@ -1,5 +1,6 @@
 use std::path::Path;
 use std::fs::File;
+use std::io::BufWriter;
 use std::io::prelude::*;
 
 #[macro_use]
@@ -7,6 +8,9 @@ extern crate nom;
 
 #[macro_use]
 extern crate clap;
+
+#[macro_use]
+extern crate log;
 // use clap::{Arg, App, SubCommand};
 use clap::{App};
 
@@ -14,24 +18,142 @@ pub mod vm;
 pub mod instruction;
 pub mod repl;
 pub mod assembler;
+pub mod pie;
+pub mod disassembler;
+pub mod tracer;
+pub mod debugger;
+pub mod gdbstub;
+pub mod debug_adapter;
+pub mod debug_info;
+pub mod language_server;
+pub mod formatter;
 
 fn main() {
+    env_logger::init();
     let yaml = load_yaml!("cli.yml");
     let matches = App::from_yaml(yaml).get_matches();
+    if matches.subcommand_matches("debug-adapter").is_some() {
+        let stdin = std::io::stdin();
+        if let Err(e) = debug_adapter::DebugAdapter::new().serve(stdin.lock(), std::io::stdout()) {
+            eprintln!("Debug adapter failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if matches.subcommand_matches("language-server").is_some() {
+        let stdin = std::io::stdin();
+        if let Err(e) = language_server::LanguageServer::new().serve(stdin.lock(), std::io::stdout()) {
+            eprintln!("Language server failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(fmt) = matches.subcommand_matches("fmt") {
+        let files: Vec<&str> = fmt.values_of("FILES").unwrap().collect();
+        format_files(&files, fmt.is_present("CHECK"));
+        return;
+    }
+    if let Some(mut traces) = matches.values_of("DIFF_TRACES") {
+        let expected = read_trace_file(traces.next().unwrap());
+        let actual = read_trace_file(traces.next().unwrap());
+        match tracer::first_divergence(&expected, &actual) {
+            Some(divergence) => {
+                print!("{}", divergence);
+                std::process::exit(1);
+            },
+            None => { println!("Traces match ({} steps)", expected.len()); }
+        }
+        return;
+    }
     let target_file = matches.value_of("INPUT_FILE");
     match target_file {
+        Some(filename) if matches.is_present("DISASSEMBLE") => {
+            disassemble_file(filename);
+        },
         Some(filename) => {
-            let program = read_file(filename);
+            let source = read_file(filename);
             let mut asm = assembler::Assembler::new();
+            asm.set_file_name(filename);
+            // Costs nothing at run time, and lets traps say where in the source they happened
+            asm.set_debug_info(true);
             let mut vm = vm::VM::new();
-            let program = asm.assemble(&program);
+            if let Some(fuel) = matches.value_of("FUEL") {
+                match fuel.parse::<u64>() {
+                    Ok(fuel) => { vm.set_fuel(Some(fuel)); },
+                    Err(_) => {
+                        println!("--fuel needs a number of instructions, not {}", fuel);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            if let Some(trace_file) = matches.value_of("TRACE") {
+                match File::create(trace_file) {
+                    Ok(file) => { vm.set_tracer(Some(Box::new(tracer::WriteTracer::new(Box::new(BufWriter::new(file)))))); },
+                    Err(e) => {
+                        println!("Unable to create trace file {}: {}", trace_file, e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            if let Some(record_file) = matches.value_of("RECORD") {
+                let recorder = File::create(record_file).and_then(|file| tracer::TraceRecorder::new(Box::new(BufWriter::new(file))));
+                match recorder {
+                    Ok(recorder) => { vm.set_tracer(Some(Box::new(recorder))); },
+                    Err(e) => {
+                        println!("Unable to create trace file {}: {}", record_file, e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            // Replaying records this run in memory and compares it with the recording once the program stops
+            let replay = matches.value_of("REPLAY").map(|replay_file| (read_trace_file(replay_file), tracer::MemoryTracer::default()));
+            if let Some((_, memory)) = &replay {
+                vm.set_tracer(Some(Box::new(memory.clone())));
+            }
+            let program = asm.assemble(&source);
             match program {
                 Ok(mut p) => {
-                    vm.add_bytes(&mut p);
-                    vm.run();
-                    std::process::exit(0);
+                    if let Err(e) = vm.add_bytes(&mut p) {
+                        println!("Unable to load program: {}", e);
+                        std::process::exit(1);
+                    }
+                    if let Some(port) = matches.value_of("GDB") {
+                        debug_with_gdb(vm, port);
+                        return;
+                    }
+                    let mut code = match vm.run() {
+                        Ok(vm::ExitReason::OutOfFuel) => {
+                            println!("Ran out of fuel after {} instructions", vm.instructions_executed());
+                            1
+                        },
+                        Ok(_) => { 0 },
+                        Err(e) => {
+                            println!("Trap: {}", vm.describe_error(&e));
+                            1
+                        }
+                    };
+                    // Exiting doesn't drop the tracer, so nothing else would write out what it has buffered
+                    if let Err(e) = vm.flush_tracer() {
+                        println!("Unable to write trace: {}", e);
+                        code = 1;
+                    }
+                    if let Some((expected, memory)) = replay {
+                        match tracer::first_divergence(&expected, &memory.steps.borrow()) {
+                            Some(divergence) => {
+                                print!("{}", divergence);
+                                code = 1;
+                            },
+                            None => { println!("Execution matches the recorded trace"); }
+                        }
+                    }
+                    std::process::exit(code);
                 },
-                Err(..) => {}
+                Err(errors) => {
+                    for error in &errors {
+                        print!("{}", assembler::diagnostics::render(error, &source));
+                    }
+                    std::process::exit(1);
+                }
             }
         },
         None => {
@@ -40,13 +162,114 @@ fn main() {
     }
 }
 
+// Hands a loaded program over to GDB on a port of localhost, stopped at its entry point
+fn debug_with_gdb(mut vm: vm::VM, port: &str) {
+    let port = match port.parse::<u16>() {
+        Ok(port) => port,
+        Err(_) => {
+            println!("--gdb needs a port number, not {}", port);
+            std::process::exit(1);
+        }
+    };
+    vm.restart();
+    println!("Waiting for GDB on localhost:{}, connect with: gdb-multiarch -ex 'target remote localhost:{}'", port, port);
+    if let Err(e) = gdbstub::GdbStub::new(vm).serve(("127.0.0.1", port)) {
+        println!("GDB session failed: {}", e);
+        std::process::exit(1);
+    }
+}
+
 // Starts a REPL that will run until the user kills it
 fn start_repl() {
     let mut repl = repl::REPL::new();
     repl.run();
 }
 
+// Prints the disassembly of either a program image or a source file, which is assembled first
+fn disassemble_file(filename: &str) {
+    let contents = read_file_bytes(filename);
+    let image = if contents.starts_with(&pie::PIE_HEADER_PREFIX) {
+        contents
+    } else {
+        let source = String::from_utf8_lossy(&contents);
+        let mut asm = assembler::Assembler::new();
+        asm.set_file_name(filename);
+        asm.set_debug_info(true);
+        match asm.assemble(&source) {
+            Ok(image) => { image },
+            Err(errors) => {
+                for error in &errors {
+                    print!("{}", assembler::diagnostics::render(error, &source));
+                }
+                std::process::exit(1);
+            }
+        }
+    };
+    match disassembler::disassemble(&image) {
+        Ok(source) => { print!("{}", source); },
+        Err(e) => {
+            println!("Unable to disassemble {}: {}", filename, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Formats each file in place, or with `check` just says which ones would change. Exits with an error if any file
+// can't be formatted, or needs to be when checking
+fn format_files(files: &[&str], check: bool) {
+    let mut failed = false;
+    for filename in files {
+        let source = read_file(filename);
+        let formatted = match formatter::format(&source, filename) {
+            Ok(formatted) => formatted,
+            Err(error) => {
+                print!("{}", assembler::diagnostics::render(&error, &source));
+                failed = true;
+                continue;
+            }
+        };
+        if formatted == source {
+            continue;
+        }
+        if check {
+            println!("{} is not formatted", filename);
+            failed = true;
+        } else if let Err(e) = std::fs::write(filename, formatted) {
+            println!("Unable to write {}: {}", filename, e);
+            failed = true;
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+// Loads a trace written by --record. Exits if it can't be read or isn't a valid trace.
+fn read_trace_file(filename: &str) -> Vec<tracer::Step> {
+    match tracer::read_trace(&read_file_bytes(filename)) {
+        Ok(steps) => { steps },
+        Err(e) => {
+            println!("Unable to load trace {}: {}", filename, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Attempts to read a file and return the raw bytes. Exits if unable to read the file for any reason.
+fn read_file_bytes(tmp: &str) -> Vec<u8> {
+    match std::fs::read(Path::new(tmp)) {
+      Ok(contents) => {
+        contents
+      },
+      Err(e) => {
+        println!("There was an error reading file: {:?}", e);
+        std::process::exit(1);
+      }
+    }
+}
+
 // Attempts to read a file and return the contents. Exits if unable to read the file for any reason.
+#[allow(clippy::needless_return)]
 fn read_file(tmp: &str) -> String {
     let filename = Path::new(tmp);
     match File::open(Path::new(&filename)) {
@@ -0,0 +1,258 @@
+use std::fmt;
+use std::io::Cursor;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+pub const PIE_HEADER_PREFIX: [u8; 4] = [45, 50, 49, 45];
+pub const PIE_HEADER_LENGTH: usize = 64;
+/// Bumped whenever the layout of the header or the sections changes
+pub const PIE_VERSION: u16 = 2;
+
+/// Ways a program image can fail to load
+#[derive(Debug, PartialEq, Clone)]
+pub enum HeaderError {
+    TooShort{length: usize},
+    BadPrefix,
+    UnsupportedVersion{version: u16},
+    SectionOutOfBounds{section: String},
+    EntryPointOutOfBounds{entry_point: u32},
+    ChecksumMismatch{expected: u32, actual: u32},
+    CorruptDebugInfo,
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeaderError::TooShort{length} => write!(f, "image is only {} bytes, which is too short for a header", length),
+            HeaderError::BadPrefix => write!(f, "image does not start with the PIE prefix"),
+            HeaderError::UnsupportedVersion{version} => write!(f, "unsupported PIE version {}", version),
+            HeaderError::SectionOutOfBounds{section} => write!(f, "{} section runs past the end of the image", section),
+            HeaderError::EntryPointOutOfBounds{entry_point} => write!(f, "entry point {} is outside the code section", entry_point),
+            HeaderError::ChecksumMismatch{expected, actual} => write!(f, "checksum is {:#010x} but the header says {:#010x}", actual, expected),
+            HeaderError::CorruptDebugInfo => write!(f, "debug info section is corrupt"),
+        }
+    }
+}
+
+/// The header at the front of every program image. It is laid out as follows, with every field little-endian:
+///
+/// | Bytes  | Field                                   |
+/// |--------|-----------------------------------------|
+/// | 0..4   | `PIE_HEADER_PREFIX`                     |
+/// | 4..6   | Format version                          |
+/// | 6..8   | Reserved                                |
+/// | 8..16  | Read-only section offset and length     |
+/// | 16..24 | Code section offset and length          |
+/// | 24..28 | Entry point                             |
+/// | 28..32 | Checksum of everything after the header |
+/// | 32..40 | Debug info section offset and length    |
+///
+/// The rest is padded with zeroes up to `PIE_HEADER_LENGTH`. The debug info section is optional, so its length is
+/// zero when there isn't one. When there is, it comes straight after the code so it can be cut off without moving
+/// anything the code refers to.
+#[derive(Debug, PartialEq, Clone)]
+pub struct PieHeader {
+    pub version: u16,
+    pub ro_offset: u32,
+    pub ro_length: u32,
+    pub code_offset: u32,
+    pub code_length: u32,
+    pub entry_point: u32,
+    pub checksum: u32,
+    pub debug_offset: u32,
+    pub debug_length: u32,
+}
+
+impl PieHeader {
+    /// Creates a header for an image where the read-only section follows the header and the code follows that
+    pub fn new(ro_length: u32, code_length: u32) -> PieHeader {
+        let ro_offset = PIE_HEADER_LENGTH as u32;
+        let code_offset = ro_offset + ro_length;
+        PieHeader {
+            version: PIE_VERSION,
+            ro_offset,
+            ro_length,
+            code_offset,
+            code_length,
+            entry_point: code_offset,
+            checksum: 0,
+            debug_offset: code_offset + code_length,
+            debug_length: 0,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut header = vec![];
+        for byte in PIE_HEADER_PREFIX {
+            header.push(byte);
+        }
+        // Writing into a Vec can't fail
+        header.write_u16::<LittleEndian>(self.version).unwrap();
+        header.write_u16::<LittleEndian>(0).unwrap();
+        for field in [self.ro_offset, self.ro_length, self.code_offset, self.code_length, self.entry_point, self.checksum,
+                      self.debug_offset, self.debug_length] {
+            header.write_u32::<LittleEndian>(field).unwrap();
+        }
+        while header.len() < PIE_HEADER_LENGTH {
+            header.push(0);
+        }
+        header
+    }
+
+    /// Reads the header off the front of an image and checks it actually describes that image
+    pub fn parse(image: &[u8]) -> Result<PieHeader, HeaderError> {
+        if image.len() < PIE_HEADER_LENGTH {
+            return Err(HeaderError::TooShort{length: image.len()});
+        }
+        if image[0..4] != PIE_HEADER_PREFIX {
+            return Err(HeaderError::BadPrefix);
+        }
+        let mut rdr = Cursor::new(&image[4..PIE_HEADER_LENGTH]);
+        // The slice is long enough for every field, so none of these reads can fail
+        let version = rdr.read_u16::<LittleEndian>().unwrap();
+        if version != PIE_VERSION {
+            return Err(HeaderError::UnsupportedVersion{version});
+        }
+        rdr.read_u16::<LittleEndian>().unwrap();
+        let header = PieHeader {
+            version,
+            ro_offset: rdr.read_u32::<LittleEndian>().unwrap(),
+            ro_length: rdr.read_u32::<LittleEndian>().unwrap(),
+            code_offset: rdr.read_u32::<LittleEndian>().unwrap(),
+            code_length: rdr.read_u32::<LittleEndian>().unwrap(),
+            entry_point: rdr.read_u32::<LittleEndian>().unwrap(),
+            checksum: rdr.read_u32::<LittleEndian>().unwrap(),
+            debug_offset: rdr.read_u32::<LittleEndian>().unwrap(),
+            debug_length: rdr.read_u32::<LittleEndian>().unwrap(),
+        };
+        header.validate(image)?;
+        Ok(header)
+    }
+
+    fn validate(&self, image: &[u8]) -> Result<(), HeaderError> {
+        let sections = [("read-only", self.ro_offset, self.ro_length), ("code", self.code_offset, self.code_length),
+                        ("debug info", self.debug_offset, self.debug_length)];
+        for (section, offset, length) in sections {
+            let end = offset as u64 + length as u64;
+            if (offset as usize) < PIE_HEADER_LENGTH || end > image.len() as u64 {
+                return Err(HeaderError::SectionOutOfBounds{section: section.to_string()});
+            }
+        }
+        if self.debug_length > 0 && self.debug_offset != self.code_offset + self.code_length {
+            return Err(HeaderError::SectionOutOfBounds{section: "debug info".to_string()});
+        }
+        if self.entry_point < self.code_offset || self.entry_point >= self.code_offset + self.code_length {
+            // An empty program has nowhere to enter, so let it point at the end of the code
+            if !(self.code_length == 0 && self.entry_point == self.code_offset) {
+                return Err(HeaderError::EntryPointOutOfBounds{entry_point: self.entry_point});
+            }
+        }
+        let actual = checksum(&image[PIE_HEADER_LENGTH..]);
+        if actual != self.checksum {
+            return Err(HeaderError::ChecksumMismatch{expected: self.checksum, actual});
+        }
+        Ok(())
+    }
+
+    pub fn ro_section<'a>(&self, image: &'a [u8]) -> &'a [u8] {
+        &image[self.ro_offset as usize..(self.ro_offset + self.ro_length) as usize]
+    }
+
+    pub fn code_section<'a>(&self, image: &'a [u8]) -> &'a [u8] {
+        &image[self.code_offset as usize..(self.code_offset + self.code_length) as usize]
+    }
+
+    /// Nothing if the image has no debug info
+    pub fn debug_section<'a>(&self, image: &'a [u8]) -> Option<&'a [u8]> {
+        if self.debug_length == 0 {
+            return None;
+        }
+        Some(&image[self.debug_offset as usize..(self.debug_offset + self.debug_length) as usize])
+    }
+}
+
+/// 32-bit FNV-1a hash, which is plenty to catch truncated or corrupted images
+pub fn checksum(bytes: &[u8]) -> u32 {
+    let mut hash: u32 = 0x811c_9dc5;
+    for byte in bytes {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash
+}
+
+/// Puts the header, read-only section and code together into an image the VM will accept
+pub fn build_image(ro: &[u8], code: &[u8]) -> Vec<u8> {
+    build_image_with_debug_info(ro, code, &[])
+}
+
+/// Like `build_image`, with a debug info section after the code, which is left out if it's empty
+pub fn build_image_with_debug_info(ro: &[u8], code: &[u8], debug_info: &[u8]) -> Vec<u8> {
+    let mut body = ro.to_vec();
+    body.extend_from_slice(code);
+    body.extend_from_slice(debug_info);
+    let mut header = PieHeader::new(ro.len() as u32, code.len() as u32);
+    header.debug_length = debug_info.len() as u32;
+    header.checksum = checksum(&body);
+    let mut image = header.to_bytes();
+    image.append(&mut body);
+    image
+}
+
+/// Builds a valid image around some code, with no read-only section
+pub fn prepend_header(b: Vec<u8>) -> Vec<u8> {
+    build_image(&[], &b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_round_trip() {
+        let image = build_image(b"Hi\0", &[5, 0, 0, 0]);
+        let header = PieHeader::parse(&image).unwrap();
+        assert_eq!(header.version, PIE_VERSION);
+        assert_eq!(header.ro_section(&image), b"Hi\0");
+        assert_eq!(header.code_section(&image), &[5, 0, 0, 0]);
+        assert_eq!(header.entry_point as usize, PIE_HEADER_LENGTH + 3);
+    }
+
+    #[test]
+    fn test_debug_section() {
+        let image = build_image_with_debug_info(b"Hi\0", &[5, 0, 0, 0], &[1, 2]);
+        let header = PieHeader::parse(&image).unwrap();
+        assert_eq!(header.code_section(&image), &[5, 0, 0, 0]);
+        assert_eq!(header.debug_section(&image), Some(&[1, 2][..]));
+        assert_eq!(PieHeader::parse(&build_image(b"", &[5, 0, 0, 0])).unwrap().debug_section(&image), None);
+        assert_eq!(PieHeader::parse(&image[..image.len() - 1]), Err(HeaderError::SectionOutOfBounds { section: "debug info".to_string() }));
+    }
+
+    #[test]
+    fn test_rejects_malformed_images() {
+        let good = build_image(b"Hi\0", &[5, 0, 0, 0]);
+        assert_eq!(PieHeader::parse(&good[..10]), Err(HeaderError::TooShort { length: 10 }));
+
+        let mut bad = good.clone();
+        bad[0] = 0;
+        assert_eq!(PieHeader::parse(&bad), Err(HeaderError::BadPrefix));
+
+        let mut bad = good.clone();
+        bad[4] = 99;
+        assert_eq!(PieHeader::parse(&bad), Err(HeaderError::UnsupportedVersion { version: 99 }));
+
+        let truncated = &good[..good.len() - 1];
+        assert_eq!(PieHeader::parse(truncated), Err(HeaderError::SectionOutOfBounds { section: "code".to_string() }));
+
+        let mut bad = good.clone();
+        bad[24] = 0;
+        assert_eq!(PieHeader::parse(&bad), Err(HeaderError::EntryPointOutOfBounds { entry_point: 0 }));
+
+        let mut bad = good.clone();
+        *bad.last_mut().unwrap() = 1;
+        match PieHeader::parse(&bad) {
+            Err(HeaderError::ChecksumMismatch { .. }) => {},
+            other => panic!("Expected a checksum mismatch, got {:?}", other)
+        }
+    }
+}
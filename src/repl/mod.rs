@@ -1,3 +1,5 @@
+#![allow(clippy::new_without_default, clippy::needless_return)]
+
 use std;
 use std::fs::File;
 use std::io;
@@ -8,11 +10,12 @@ use std::path::Path;
 
 use nom::types::CompleteStr;
 
-use crate::assembler::PIE_HEADER_LENGTH;
-use crate::assembler::PIE_HEADER_PREFIX;
 use crate::assembler::program_parsers::*;
-use crate::assembler::SymbolTable;
-use crate::vm::VM;
+use crate::assembler::{diagnostics, Address, Assembler, SymbolTable, INSTRUCTION_LENGTH};
+use crate::debugger::{Debugger, Stop, Watchpoint};
+use crate::disassembler::format_instruction;
+use crate::pie::{prepend_header, PIE_HEADER_LENGTH};
+use crate::vm::{VmError, VM};
 
 use crate::repl::system_operations::SystemOperations;
 use crate::repl::system_operations::SystemOperationsImpl;
@@ -22,36 +25,27 @@ pub struct REPL {
     command_buffer: Vec<String>,
     // The VM the REPL will use to execute code
     vm: VM,
+    // Breakpoints and watchpoints for whatever the VM is running
+    debugger: Debugger,
 }
 
 impl REPL {
     /// Creates and returns a new assembly REPL
     pub fn new() -> REPL {
         let mut repl_vm = VM::new();
-        repl_vm.program = Self::prepend_header(vec![]);
+        repl_vm.program = prepend_header(vec![]);
         REPL {
             vm: repl_vm,
             command_buffer: vec![],
+            debugger: Debugger::new(),
         }
     }
 
-    fn prepend_header(mut b: Vec<u8>) -> Vec<u8> {
-        let mut prepension = vec![];
-        for byte in PIE_HEADER_PREFIX.iter() {
-            prepension.push(byte.clone());
-        }
-        while prepension.len() < PIE_HEADER_LENGTH {
-            prepension.push(0);
-        }
-        prepension.append(&mut b);
-        prepension
-    }
-
     fn parse_hex(&mut self, i: &str) -> Result<Vec<u8>, ParseIntError> {
         let split = i.split(" ").collect::<Vec<&str>>();
         let mut results: Vec<u8> = vec![];
         for hex_string in split {
-            let byte = u8::from_str_radix(&hex_string, 16);
+            let byte = u8::from_str_radix(hex_string, 16);
             match byte {
                 Ok(result) => {
                     println!("{:#?}", result);
@@ -92,19 +86,96 @@ impl REPL {
         
         self.command_buffer.push(buffer.to_string());
 
-        match buffer {
+        // Some commands take an argument, like `.break @loop`
+        let (command, argument) = match buffer.find(' ') {
+            Some(index) => (&buffer[..index], buffer[index..].trim()),
+            None => (buffer, ""),
+        };
+
+        match command {
             ".program" => {
                 println!("Listing instructions currently in VM's program vector:");
-                for instruction in &self.vm.program {
-                    println!("{}", instruction);
+                // The REPL's header is never filled in, so everything after it is treated as code
+                let code = self.vm.program.get(PIE_HEADER_LENGTH..).unwrap_or(&[]);
+                let whole = code.len() - code.len() % INSTRUCTION_LENGTH;
+                for (index, instruction) in code[..whole].chunks(INSTRUCTION_LENGTH).enumerate() {
+                    println!("{:>6}: {}", PIE_HEADER_LENGTH + index * INSTRUCTION_LENGTH, format_instruction(instruction));
+                }
+                if whole < code.len() {
+                    println!("Partial instruction: {:?}", &code[whole..]);
                 }
                 println!("End of Program Listing");
             }
             ".registers" => {
                 println!("Listing registers and all contents:");
                 println!("{:#?}", self.vm.registers);
+                println!("{:#?}", self.vm.float_registers);
                 println!("End of Register Listing")
             }
+            ".debug" => {
+                self.debug_file(argument);
+            }
+            ".break" => {
+                match self.debugger.add_breakpoint(argument) {
+                    Ok(address) => { println!("Breakpoint at {}", self.describe_address(address)); },
+                    Err(e) => { println!("Unable to set breakpoint: {}", e); }
+                }
+            }
+            ".delete" => {
+                match self.debugger.remove_breakpoint(argument) {
+                    Ok(address) => { println!("Removed breakpoint at {}", self.describe_address(address)); },
+                    Err(e) => { println!("Unable to remove breakpoint: {}", e); }
+                }
+            }
+            ".watch" => {
+                match Watchpoint::parse(argument) {
+                    Ok(watchpoint) => {
+                        println!("Watching {}", watchpoint);
+                        self.debugger.add_watchpoint(watchpoint);
+                    },
+                    Err(e) => { println!("Unable to set watchpoint: {}", e); }
+                }
+            }
+            ".step" => {
+                let stop = self.debugger.step(&mut self.vm);
+                self.report_stop(stop);
+            }
+            ".next" => {
+                let stop = self.debugger.step_over(&mut self.vm);
+                self.report_stop(stop);
+            }
+            ".continue" => {
+                let stop = self.debugger.continue_execution(&mut self.vm);
+                self.report_stop(stop);
+            }
+            ".info" => {
+                match argument {
+                    "registers" => {
+                        println!("pc: {}", self.describe_address(self.vm.pc()));
+                        for (row, registers) in self.vm.registers.chunks(8).enumerate() {
+                            let line: Vec<String> = registers.iter().enumerate()
+                                .map(|(i, value)| format!("${:<2} {:>11}", row * 8 + i, value))
+                                .collect();
+                            println!("{}", line.join("  "));
+                        }
+                        for (register, value) in self.vm.float_registers.iter().enumerate().filter(|(_, v)| **v != 0.0) {
+                            println!("${}f {:?}", register, value);
+                        }
+                    },
+                    "breakpoints" => {
+                        for address in self.debugger.breakpoints() {
+                            println!("Breakpoint at {}", self.describe_address(*address));
+                        }
+                        for watchpoint in self.debugger.watchpoints() {
+                            println!("Watching {}", watchpoint);
+                        }
+                    },
+                    _ => { println!("Try .info registers or .info breakpoints"); }
+                }
+            }
+            ".executed" => {
+                println!("{} instructions executed", self.vm.instructions_executed());
+            }
             ".quit" => {
                 println!("Farewell! Have a great day!");
                 std::process::exit(0);
@@ -139,15 +210,23 @@ impl REPL {
                     }
                 };
                 let symbols = SymbolTable::new();
-                self.vm.program.append(&mut program.to_bytes(&symbols));
+                match program.to_bytes(&symbols, self.vm.program.len() as Address) {
+                    Ok(mut bytes) => { self.vm.program.append(&mut bytes); },
+                    Err(e) => { println!("Unable to assemble input: {:?}", e); }
+                }
             }
             _ => {
                 let parsed_program = program(CompleteStr(buffer));
-                if parsed_program.is_ok() {
-                    let (_, result) = parsed_program.unwrap();
+                if let Ok((_, result)) = parsed_program {
                     println!("{:?}", result);
                     let symbols = SymbolTable::new();
-                    let bytecode = result.to_bytes(&symbols);
+                    let bytecode = match result.to_bytes(&symbols, self.vm.program.len() as Address) {
+                        Ok(bytecode) => { bytecode },
+                        Err(e) => {
+                            println!("Unable to assemble input: {:?}", e);
+                            return;
+                        }
+                    };
                     // TODO: Make a function to let us add bytes to the VM
                     for byte in bytecode {
                         self.vm.add_byte(byte);
@@ -165,9 +244,73 @@ impl REPL {
                         }
                     };
                 }
-                self.vm.run_once();
+                match self.vm.run_once() {
+                    Ok(_) => {},
+                    Err(e) => { println!("Trap: {}", e); }
+                }
+            }
+        }
+    }
+
+    /// Assembles a file and loads it into a fresh VM, stopped at its entry point, so it can be debugged with its labels
+    fn debug_file(&mut self, filename: &str) {
+        let contents = match std::fs::read_to_string(filename) {
+            Ok(contents) => contents,
+            Err(e) => {
+                println!("Unable to read {}: {}", filename, e);
+                return;
+            }
+        };
+        let mut asm = Assembler::new();
+        asm.set_file_name(filename);
+        asm.set_debug_info(true);
+        let mut image = match asm.assemble(&contents) {
+            Ok(image) => image,
+            Err(errors) => {
+                for error in &errors {
+                    print!("{}", diagnostics::render(error, &contents));
+                }
+                return;
+            }
+        };
+        let mut vm = VM::new();
+        if let Err(e) = vm.add_bytes(&mut image) {
+            println!("Unable to load program: {}", e);
+            return;
+        }
+        vm.restart();
+        self.vm = vm;
+        self.debugger.set_symbols(asm.symbols);
+        println!("Loaded {}, stopped at {}", filename, self.describe_address(self.vm.pc()));
+    }
+
+    /// An address along with the label there, like `72 (@loop)`
+    fn describe_address(&self, address: usize) -> String {
+        match self.debugger.label_at(address) {
+            Some(label) => format!("{} (@{})", address, label),
+            None => address.to_string(),
+        }
+    }
+
+    fn report_stop(&self, stop: Result<Stop, VmError>) {
+        match stop {
+            Ok(Stop::Stepped) => {},
+            Ok(Stop::Breakpoint{address}) => { println!("Breakpoint at {}", self.describe_address(address)); },
+            Ok(Stop::Watchpoint{watchpoint, old, new}) => { println!("{} changed from {} to {}", watchpoint, old, new); },
+            Ok(Stop::Exited{reason}) => {
+                println!("Program stopped: {:?}", reason);
+                return;
+            },
+            Err(e) => {
+                println!("Trap: {}", self.vm.describe_error(&e));
+                return;
             }
         }
+        let pc = self.vm.pc();
+        match pc.checked_add(INSTRUCTION_LENGTH).and_then(|end| self.vm.program.get(pc..end)) {
+            Some(bytes) => { println!("{}: {}", self.describe_address(pc), format_instruction(bytes)); },
+            None => { println!("{}: end of program", self.describe_address(pc)); }
+        }
     }
 
     pub fn get_register(&self, index: usize) -> i32 {
@@ -202,3 +345,43 @@ fn test_main_loop() {
     repl.run_once(&mut TestSystemOperations::new("load $0 #3"));
     assert_eq!(repl.get_register(0), 3);
 }
+
+#[cfg(test)]
+fn run_commands(repl: &mut REPL, commands: &[&str]) {
+    for command in commands {
+        repl.run_once(&mut TestSystemOperations::new(command));
+    }
+}
+
+#[test]
+fn test_debug_commands() {
+    let path = std::env::temp_dir().join(format!("iridium-repl-{}.iasm", std::process::id()));
+    std::fs::write(&path, ".code\nload $0 #0\nload $1 #3\nloop: call @bump\nneq $0 $1\njmpe @loop\nhlt\nbump: inc $0\nret").unwrap();
+    let mut repl = REPL::new();
+    repl.run_once(&mut TestSystemOperations::new(&format!(".debug {}", path.display())));
+    std::fs::remove_file(&path).unwrap();
+    let start = repl.vm.pc();
+    let code_loop = repl.debugger.resolve("@loop").unwrap();
+    assert_eq!(code_loop, start + 2 * INSTRUCTION_LENGTH);
+
+    run_commands(&mut repl, &[".break @loop", ".continue"]);
+    assert_eq!(repl.debugger.breakpoints().collect::<Vec<_>>(), vec![&code_loop]);
+    assert_eq!((repl.vm.pc(), repl.get_register(1)), (code_loop, 3));
+
+    run_commands(&mut repl, &[".info registers", ".info breakpoints"]);
+    assert_eq!(repl.vm.pc(), code_loop);
+
+    // Over the call, then onto the jump
+    repl.run_once(&mut TestSystemOperations::new(".next"));
+    assert_eq!((repl.vm.pc(), repl.get_register(0)), (code_loop + INSTRUCTION_LENGTH, 1));
+    repl.run_once(&mut TestSystemOperations::new(".step"));
+    assert_eq!(repl.vm.pc(), code_loop + 2 * INSTRUCTION_LENGTH);
+
+    run_commands(&mut repl, &[".watch $0", ".watch heap 18446744073709551615 4", ".continue"]);
+    assert_eq!(repl.debugger.watchpoints(), &[Watchpoint::Register(0)]);
+    assert_eq!(repl.vm.pc(), code_loop);
+
+    run_commands(&mut repl, &[".delete @loop", ".continue"]);
+    assert_eq!(repl.debugger.breakpoints().count(), 0);
+    assert_eq!(repl.get_register(0), 2);
+}
@@ -1,3 +1,5 @@
+#![allow(clippy::new_without_default, clippy::single_match)]
+
 use std::io;
 use std::io::Stdin;
 
@@ -0,0 +1,456 @@
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::fmt;
+use std::io;
+use std::io::{Cursor, Read, Write};
+use std::rc::Rc;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::disassembler::format_instruction;
+use crate::instruction::Instruction;
+use crate::vm::Trap;
+
+pub const TRACE_PREFIX: [u8; 4] = *b"IRTR";
+/// Bumped whenever the layout of a recorded step changes
+pub const TRACE_VERSION: u16 = 1;
+
+/// The state comparisons leave behind for the conditional jumps
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Flags {
+    pub equal: bool,
+    pub comparison: Option<Ordering>,
+}
+
+impl Flags {
+    /// Packs the flags into a byte, with the equal flag in the low bit and the ordering in the two above it
+    pub fn to_byte(self) -> u8 {
+        let comparison = match self.comparison {
+            None => 0,
+            Some(Ordering::Less) => 1,
+            Some(Ordering::Equal) => 2,
+            Some(Ordering::Greater) => 3,
+        };
+        (comparison << 1) | self.equal as u8
+    }
+
+    pub fn from_byte(byte: u8) -> Flags {
+        let comparison = match (byte >> 1) & 3 {
+            1 => Some(Ordering::Less),
+            2 => Some(Ordering::Equal),
+            3 => Some(Ordering::Greater),
+            _ => None,
+        };
+        Flags { equal: byte & 1 == 1, comparison }
+    }
+}
+
+/// What a single executed instruction did
+#[derive(Debug, PartialEq, Clone)]
+pub struct Step {
+    /// Where the instruction is in the program
+    pub pc: usize,
+    pub instruction: Instruction,
+    /// Each integer register the instruction changed, with its value before and after
+    pub registers: Vec<(usize, i32, i32)>,
+    /// The same for the float registers
+    pub float_registers: Vec<(usize, f64, f64)>,
+    /// Where the instruction stored to the heap and the bytes it stored
+    pub heap: Vec<(usize, Vec<u8>)>,
+    /// The flags before and after, if the instruction changed them
+    pub flags: Option<(Flags, Flags)>,
+    /// What went wrong, if the instruction trapped
+    pub trap: Option<Trap>,
+}
+
+impl Step {
+    /// Works out which registers changed between two snapshots. Everything else starts out as not having happened
+    pub fn new(pc: usize, instruction: Instruction, before: (&[i32], &[f64]), after: (&[i32], &[f64])) -> Step {
+        Step {
+            pc,
+            instruction,
+            registers: changes(before.0, after.0),
+            float_registers: changes(before.1, after.1),
+            heap: vec![],
+            flags: None,
+            trap: None,
+        }
+    }
+
+    /// The step as a recorded trace stores it. Registers are numbered from 0 to 31 and instructions store at most a
+    /// word, so their counts and numbers all fit in a byte
+    pub fn to_bytes(&self) -> Vec<u8> {
+        // Writing into a Vec can't fail
+        let mut bytes = vec![];
+        bytes.write_u32::<LittleEndian>(self.pc as u32).unwrap();
+        bytes.extend_from_slice(&self.instruction.encode());
+        bytes.push(self.registers.len() as u8);
+        for (register, old, new) in &self.registers {
+            bytes.push(*register as u8);
+            bytes.write_i32::<LittleEndian>(*old).unwrap();
+            bytes.write_i32::<LittleEndian>(*new).unwrap();
+        }
+        bytes.push(self.float_registers.len() as u8);
+        for (register, old, new) in &self.float_registers {
+            bytes.push(*register as u8);
+            bytes.write_f64::<LittleEndian>(*old).unwrap();
+            bytes.write_f64::<LittleEndian>(*new).unwrap();
+        }
+        bytes.push(self.heap.len() as u8);
+        for (address, written) in &self.heap {
+            bytes.write_u32::<LittleEndian>(*address as u32).unwrap();
+            bytes.push(written.len() as u8);
+            bytes.extend_from_slice(written);
+        }
+        match self.flags {
+            Some((old, new)) => { bytes.extend_from_slice(&[1, old.to_byte(), new.to_byte()]); },
+            None => { bytes.push(0); }
+        }
+        let (tag, payload) = match &self.trap {
+            None => (0, 0),
+            Some(Trap::IllegalOpcode{opcode}) => (1, *opcode as u64),
+            Some(Trap::BadRegister{register}) => (2, *register as u64),
+            Some(Trap::PcOutOfBounds) => (3, 0),
+            Some(Trap::DivideByZero) => (4, 0),
+            Some(Trap::HeapOverflow{requested}) => (5, *requested as u64),
+            Some(Trap::HeapOutOfBounds{address}) => (6, *address as u64),
+            Some(Trap::ReadOnlyOutOfBounds{offset}) => (7, *offset as u64),
+            Some(Trap::OutputFailed) => (8, 0),
+            Some(Trap::ArithmeticOverflow) => (9, 0),
+            Some(Trap::StackOverflow) => (10, 0),
+            Some(Trap::StackUnderflow) => (11, 0),
+            Some(Trap::InvalidConversion{value}) => (12, value.to_bits()),
+        };
+        bytes.push(tag);
+        if tag != 0 {
+            bytes.write_u64::<LittleEndian>(payload).unwrap();
+        }
+        bytes
+    }
+
+    fn read(rdr: &mut Cursor<&[u8]>) -> io::Result<Step> {
+        let pc = rdr.read_u32::<LittleEndian>()? as usize;
+        let mut encoded = [0; 4];
+        rdr.read_exact(&mut encoded)?;
+        let mut step = Step::new(pc, Instruction::decode(&encoded), (&[], &[]), (&[], &[]));
+        for _ in 0..rdr.read_u8()? {
+            step.registers.push((rdr.read_u8()? as usize, rdr.read_i32::<LittleEndian>()?, rdr.read_i32::<LittleEndian>()?));
+        }
+        for _ in 0..rdr.read_u8()? {
+            step.float_registers.push((rdr.read_u8()? as usize, rdr.read_f64::<LittleEndian>()?, rdr.read_f64::<LittleEndian>()?));
+        }
+        for _ in 0..rdr.read_u8()? {
+            let address = rdr.read_u32::<LittleEndian>()? as usize;
+            let mut written = vec![0; rdr.read_u8()? as usize];
+            rdr.read_exact(&mut written)?;
+            step.heap.push((address, written));
+        }
+        if rdr.read_u8()? != 0 {
+            step.flags = Some((Flags::from_byte(rdr.read_u8()?), Flags::from_byte(rdr.read_u8()?)));
+        }
+        let tag = rdr.read_u8()?;
+        if tag != 0 {
+            let payload = rdr.read_u64::<LittleEndian>()?;
+            step.trap = Some(match tag {
+                1 => Trap::IllegalOpcode{opcode: payload as u8},
+                2 => Trap::BadRegister{register: payload as u8},
+                3 => Trap::PcOutOfBounds,
+                4 => Trap::DivideByZero,
+                5 => Trap::HeapOverflow{requested: payload as i64},
+                6 => Trap::HeapOutOfBounds{address: payload as i64},
+                7 => Trap::ReadOnlyOutOfBounds{offset: payload as usize},
+                8 => Trap::OutputFailed,
+                9 => Trap::ArithmeticOverflow,
+                10 => Trap::StackOverflow,
+                11 => Trap::StackUnderflow,
+                12 => Trap::InvalidConversion{value: f64::from_bits(payload)},
+                _ => { return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown trap")); }
+            });
+        }
+        Ok(step)
+    }
+}
+
+impl fmt::Display for Step {
+    /// Shows the step like `72: add $0 $1 $2 | $2: 0 -> 15`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.pc, format_instruction(&self.instruction.encode()))?;
+        let mut changes = vec![];
+        for (register, old, new) in &self.registers {
+            changes.push(format!("${}: {} -> {}", register, old, new));
+        }
+        for (register, old, new) in &self.float_registers {
+            changes.push(format!("${}f: {:?} -> {:?}", register, old, new));
+        }
+        for (address, written) in &self.heap {
+            changes.push(format!("heap[{}]: {:?}", address, written));
+        }
+        if let Some((old, new)) = self.flags {
+            changes.push(format!("flags: {} -> {}", old, new));
+        }
+        if let Some(trap) = &self.trap {
+            changes.push(format!("trap: {:?}", trap));
+        }
+        if !changes.is_empty() {
+            write!(f, " | {}", changes.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let comparison = match self.comparison {
+            Some(Ordering::Less) => "less",
+            Some(Ordering::Equal) => "equal",
+            Some(Ordering::Greater) => "greater",
+            None => "unordered",
+        };
+        write!(f, "{}/{}", if self.equal { "set" } else { "clear" }, comparison)
+    }
+}
+
+fn changes<T: PartialEq + Copy>(before: &[T], after: &[T]) -> Vec<(usize, T, T)> {
+    before.iter().zip(after).enumerate()
+        .filter(|(_, (old, new))| old != new)
+        .map(|(register, (old, new))| (register, *old, *new))
+        .collect()
+}
+
+/// Something the VM reports every instruction it executes to. An instruction that traps is still reported, with
+/// whatever it changed before trapping, but one that can't be decoded is not
+pub trait Tracer {
+    fn step(&mut self, step: &Step);
+
+    /// Writes out anything the tracer is holding on to, for when the program is done
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes a line per instruction in the same form `Step` displays as
+pub struct WriteTracer {
+    output: Box<dyn Write>,
+}
+
+impl WriteTracer {
+    pub fn new(output: Box<dyn Write>) -> WriteTracer {
+        WriteTracer { output }
+    }
+}
+
+impl Tracer for WriteTracer {
+    fn step(&mut self, step: &Step) {
+        // A trace that can't be written shouldn't stop the program it's tracing
+        if writeln!(self.output, "{}", step).is_err() {
+            warn!("unable to write trace for instruction at {}", step.pc);
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+/// Records a trace that `read_trace` can load back. It starts with `TRACE_PREFIX` and a little-endian
+/// `TRACE_VERSION`, followed by every step in the form `Step::to_bytes` writes
+pub struct TraceRecorder {
+    output: Box<dyn Write>,
+}
+
+impl TraceRecorder {
+    pub fn new(mut output: Box<dyn Write>) -> io::Result<TraceRecorder> {
+        output.write_all(&TRACE_PREFIX)?;
+        output.write_u16::<LittleEndian>(TRACE_VERSION)?;
+        Ok(TraceRecorder { output })
+    }
+}
+
+impl Tracer for TraceRecorder {
+    fn step(&mut self, step: &Step) {
+        if self.output.write_all(&step.to_bytes()).is_err() {
+            warn!("unable to record instruction at {}", step.pc);
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+/// Keeps every step in memory, where it can be looked at through a clone of the tracer after the VM has taken it
+#[derive(Clone, Default)]
+pub struct MemoryTracer {
+    pub steps: Rc<RefCell<Vec<Step>>>,
+}
+
+impl Tracer for MemoryTracer {
+    fn step(&mut self, step: &Step) {
+        self.steps.borrow_mut().push(step.clone());
+    }
+}
+
+/// Ways a recorded trace can fail to load
+#[derive(Debug, PartialEq, Clone)]
+pub enum TraceError {
+    BadPrefix,
+    UnsupportedVersion{version: u16},
+    Truncated{step: usize},
+    Corrupt{step: usize},
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TraceError::BadPrefix => write!(f, "not a recorded trace"),
+            TraceError::UnsupportedVersion{version} => write!(f, "unsupported trace version {}", version),
+            TraceError::Truncated{step} => write!(f, "trace ends partway through step {}", step),
+            TraceError::Corrupt{step} => write!(f, "step {} of the trace is corrupt", step),
+        }
+    }
+}
+
+/// Loads every step out of a trace written by `TraceRecorder`
+pub fn read_trace(bytes: &[u8]) -> Result<Vec<Step>, TraceError> {
+    if bytes.len() < TRACE_PREFIX.len() + 2 || bytes[0..4] != TRACE_PREFIX {
+        return Err(TraceError::BadPrefix);
+    }
+    let mut rdr = Cursor::new(&bytes[4..]);
+    let version = rdr.read_u16::<LittleEndian>().unwrap();
+    if version != TRACE_VERSION {
+        return Err(TraceError::UnsupportedVersion{version});
+    }
+    let mut steps = vec![];
+    while (rdr.position() as usize) < rdr.get_ref().len() {
+        match Step::read(&mut rdr) {
+            Ok(step) => { steps.push(step); },
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(TraceError::Truncated{step: steps.len()});
+            },
+            Err(_) => { return Err(TraceError::Corrupt{step: steps.len()}); }
+        }
+    }
+    Ok(steps)
+}
+
+/// Where two traces first disagree. A missing step means that trace ended before the other one did
+#[derive(Debug, PartialEq, Clone)]
+pub struct Divergence {
+    pub index: usize,
+    pub expected: Option<Step>,
+    pub actual: Option<Step>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "traces diverge at step {}", self.index)?;
+        for (name, step) in [("expected", &self.expected), ("actual", &self.actual)] {
+            match step {
+                Some(step) => writeln!(f, "  {}: {}", name, step)?,
+                None => writeln!(f, "  {}: end of trace", name)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Finds the first step where two traces differ, if they differ at all
+pub fn first_divergence(expected: &[Step], actual: &[Step]) -> Option<Divergence> {
+    for index in 0..expected.len().max(actual.len()) {
+        let (left, right) = (expected.get(index), actual.get(index));
+        // Comparing the recorded form means a NaN written to a register matches itself
+        if left.map(Step::to_bytes) != right.map(Step::to_bytes) {
+            return Some(Divergence { index, expected: left.cloned(), actual: right.cloned() });
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::Opcode;
+    use crate::vm::SharedBuffer;
+    use std::io::BufWriter;
+
+    fn add_step() -> Step {
+        let mut instruction = Instruction::new(Opcode::ADD);
+        instruction.add_operand(0);
+        instruction.add_operand(1);
+        instruction.add_operand(2);
+        let mut step = Step::new(72, instruction, (&[0, 0, 0], &[0.0]), (&[0, 0, 15], &[0.0]));
+        step.float_registers.push((3, 0.0, 2.5));
+        step
+    }
+
+    #[test]
+    fn test_step_changes() {
+        let before = ([1, 2, 3], [0.0, 1.5]);
+        let after = ([1, 7, 3], [0.0, 1.5]);
+        let step = Step::new(64, Instruction::new(Opcode::LOAD), (&before.0, &before.1), (&after.0, &after.1));
+        assert_eq!(step.registers, vec![(1, 2, 7)]);
+        assert!(step.float_registers.is_empty());
+    }
+
+    #[test]
+    fn test_write_tracer() {
+        let buffer = SharedBuffer(Rc::new(RefCell::new(vec![])));
+        let mut tracer = WriteTracer::new(Box::new(buffer.clone()));
+        tracer.step(&add_step());
+        let mut store = Step::new(76, Instruction::new(Opcode::SW), (&[], &[]), (&[], &[]));
+        store.heap.push((8, vec![15, 0, 0, 0]));
+        store.flags = Some((Flags { equal: false, comparison: None }, Flags { equal: true, comparison: Some(Ordering::Less) }));
+        store.trap = Some(Trap::DivideByZero);
+        tracer.step(&store);
+        assert_eq!(String::from_utf8(buffer.0.borrow().clone()).unwrap(),
+                   "72: add $0 $1 $2 | $2: 0 -> 15, $3f: 0.0 -> 2.5\n\
+                    76: sw $0 $0 #0 | heap[8]: [15, 0, 0, 0], flags: clear/unordered -> set/less, trap: DivideByZero\n");
+    }
+
+    #[test]
+    fn test_flush() {
+        let buffer = SharedBuffer(Rc::new(RefCell::new(vec![])));
+        let mut recorder = TraceRecorder::new(Box::new(BufWriter::new(buffer.clone()))).unwrap();
+        recorder.step(&add_step());
+        assert!(buffer.0.borrow().is_empty());
+        recorder.flush().unwrap();
+        assert_eq!(read_trace(&buffer.0.borrow()), Ok(vec![add_step()]));
+    }
+
+    #[test]
+    fn test_record_and_read_trace() {
+        let buffer = SharedBuffer(Rc::new(RefCell::new(vec![])));
+        let mut recorder = TraceRecorder::new(Box::new(buffer.clone())).unwrap();
+        let mut second = add_step();
+        second.heap.push((4, vec![1, 2]));
+        second.flags = Some((Flags { equal: true, comparison: Some(Ordering::Greater) }, Flags { equal: false, comparison: None }));
+        second.trap = Some(Trap::InvalidConversion{value: 1e300});
+        recorder.step(&add_step());
+        recorder.step(&second);
+        let bytes = buffer.0.borrow().clone();
+        assert_eq!(read_trace(&bytes), Ok(vec![add_step(), second]));
+
+        assert_eq!(read_trace(&bytes[..bytes.len() - 1]), Err(TraceError::Truncated{step: 1}));
+        assert_eq!(read_trace(b"IRTX\x01\x00"), Err(TraceError::BadPrefix));
+        assert_eq!(read_trace(b"IRTR\x02\x00"), Err(TraceError::UnsupportedVersion{version: 2}));
+        assert_eq!(read_trace(b"IRTR\x01\x00"), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_first_divergence() {
+        let mut nan = add_step();
+        nan.float_registers[0].2 = f64::NAN;
+        let trace = vec![add_step(), nan.clone()];
+        assert_eq!(first_divergence(&trace, &trace), None);
+
+        let mut changed = trace.clone();
+        changed[1].registers[0].2 = 16;
+        let divergence = first_divergence(&trace, &changed).unwrap();
+        assert_eq!(divergence.index, 1);
+        assert_eq!(divergence.actual.unwrap().registers, vec![(2, 0, 16)]);
+
+        let divergence = first_divergence(&trace, &trace[..1]).unwrap();
+        assert_eq!(divergence.actual, None);
+        assert_eq!(divergence.to_string(), format!("traces diverge at step 1\n  expected: {}\n  actual: end of trace\n", nan));
+    }
+}
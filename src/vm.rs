@@ -1,182 +1,665 @@
-use crate::instruction::Opcode;
-use crate::assembler::PIE_HEADER_LENGTH;
+#![allow(clippy::new_without_default)]
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::io;
+use std::io::Write;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::assembler::Address;
+use crate::debug_info::DebugInfo;
+use crate::instruction::{Instruction, Opcode, OperandKind, INSTRUCTION_LENGTH, REGISTER_COUNT};
+use crate::pie::{HeaderError, PieHeader, PIE_HEADER_LENGTH};
+use crate::tracer::{Flags, Step, Tracer};
+
+/// The most memory a program is allowed to ALOC
+pub const MAX_HEAP_SIZE: usize = 64 * 1024 * 1024;
+/// The most values a program can PUSH
+pub const MAX_STACK_SIZE: usize = 64 * 1024;
+/// The deepest CALLs can nest
+pub const MAX_CALL_DEPTH: usize = 4 * 1024;
+
+/// Where the heap starts in the address space `read_memory` and `write_memory` use. The program image, read-only
+/// section included, is mapped from 0, and the heap is far enough above it that the two never meet
+pub const HEAP_BASE: usize = 0x1000_0000;
+
+/// Why the VM stopped without an error
+#[derive(Debug, PartialEq, Clone)]
+pub enum ExitReason {
+    /// A HLT instruction was executed
+    Halted,
+    /// Execution ran off the end of the program
+    EndOfProgram,
+    /// A single instruction was executed and there is more to run
+    Stepped,
+    /// The instruction budget was used up before the program finished
+    OutOfFuel,
+}
+
+/// The kinds of fault an instruction can raise
+#[derive(Debug, PartialEq, Clone)]
+pub enum Trap {
+    IllegalOpcode{opcode: u8},
+    BadRegister{register: u8},
+    PcOutOfBounds,
+    DivideByZero,
+    HeapOverflow{requested: i64},
+    HeapOutOfBounds{address: i64},
+    ReadOnlyOutOfBounds{offset: usize},
+    OutputFailed,
+    ArithmeticOverflow,
+    StackOverflow,
+    StackUnderflow,
+    InvalidConversion{value: f64},
+}
+
+/// A trap along with the address of the instruction that raised it
+#[derive(Debug, PartialEq, Clone)]
+pub struct VmError {
+    pub pc: usize,
+    pub trap: Trap,
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Trap::IllegalOpcode{opcode} => write!(f, "illegal opcode {}", opcode),
+            Trap::BadRegister{register} => write!(f, "bad register ${}", register),
+            Trap::PcOutOfBounds => write!(f, "program counter out of bounds"),
+            Trap::DivideByZero => write!(f, "divide by zero"),
+            Trap::HeapOverflow{requested} => write!(f, "heap overflow allocating {} bytes", requested),
+            Trap::HeapOutOfBounds{address} => write!(f, "heap access out of bounds at {}", address),
+            Trap::ReadOnlyOutOfBounds{offset} => write!(f, "read-only access out of bounds at {}", offset),
+            Trap::OutputFailed => write!(f, "unable to write output"),
+            Trap::ArithmeticOverflow => write!(f, "arithmetic overflow"),
+            Trap::StackOverflow => write!(f, "stack overflow"),
+            Trap::StackUnderflow => write!(f, "stack underflow"),
+            Trap::InvalidConversion{value} => write!(f, "{} does not fit in a register", value),
+        }
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at pc {}", self.trap, self.pc)
+    }
+}
+
+/// Bookkeeping for a CALL that has not RET yet
+#[derive(Debug, PartialEq, Clone)]
+struct Frame {
+    return_address: usize,
+    // How deep the stack was when the call was made. Anything the callee leaves above this is dropped on RET
+    stack_base: usize,
+}
 
 pub struct VM {
-    pub registers: [i32; 32],
+    pub registers: [i32; REGISTER_COUNT],
+    pub float_registers: [f64; REGISTER_COUNT],
     pc: usize,
+    // Where `run` starts, as given by the program's header
+    entry_point: usize,
+    // Where the read-only section is in the program, so changes to the image can be copied into `ro_data`
+    ro_offset: usize,
     pub program: Vec<u8>,
+    ro_data: Vec<u8>,
     heap: Vec<u8>,
-    remainder: u32,
+    stack: Vec<i32>,
+    frames: Vec<Frame>,
+    // What was left over from the last DIV
+    remainder: i32,
     equal_flag: bool,
+    // How the two operands of the last comparison were ordered, if they could be
+    last_comparison: Option<Ordering>,
+    // Where PRTS sends its strings
+    output: Box<dyn Write>,
+    // How many more instructions may be executed, if there is a limit
+    fuel: Option<u64>,
+    executed: u64,
+    // Told about every instruction executed, if anything is listening
+    tracer: Option<Box<dyn Tracer>>,
+    // From the program's debug info section, if it had one
+    debug_info: Option<DebugInfo>,
 }
 
 impl VM {
     pub fn new() -> VM {
         VM {
-            registers: [0; 32],
+            registers: [0; REGISTER_COUNT],
+            float_registers: [0.0; REGISTER_COUNT],
             program: vec![],
+            ro_data: vec![],
             heap: vec![],
+            stack: vec![],
+            frames: vec![],
             pc: PIE_HEADER_LENGTH,
+            entry_point: PIE_HEADER_LENGTH,
+            ro_offset: PIE_HEADER_LENGTH,
             remainder: 0,
             equal_flag: false,
+            last_comparison: None,
+            output: Box::new(io::stdout()),
+            fuel: None,
+            executed: 0,
+            tracer: None,
+            debug_info: None,
+        }
+    }
+
+    /// Sends anything the program prints somewhere other than stdout
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    /// Reports every instruction executed from now on to `tracer`, or stops reporting them with `None`
+    pub fn set_tracer(&mut self, tracer: Option<Box<dyn Tracer>>) {
+        self.tracer = tracer;
+    }
+
+    /// Writes out whatever the tracer has buffered, which has to happen before the process exits
+    pub fn flush_tracer(&mut self) -> io::Result<()> {
+        match &mut self.tracer {
+            Some(tracer) => tracer.flush(),
+            None => Ok(()),
         }
     }
 
+    /// Limits how many more instructions can be executed, or lifts the limit with `None`
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    /// How many more instructions can be executed, if there is a limit
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// How many instructions have been executed since the VM was created, including any that trapped
+    pub fn instructions_executed(&self) -> u64 {
+        self.executed
+    }
+
     /// Loops as long as instructions can be executed.
-    pub fn run(&mut self) {
-        let mut is_done = false;
-        while !is_done {
-            is_done = self.execute_instruction();
+    pub fn run(&mut self) -> Result<ExitReason, VmError> {
+        self.restart();
+        self.resume()
+    }
+
+    /// Points the VM back at the program's entry point without running anything
+    pub fn restart(&mut self) {
+        self.pc = self.entry_point;
+    }
+
+    /// Where the next instruction will be executed from
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// Moves execution somewhere else, like a debugger changing the program counter
+    pub fn set_pc(&mut self, pc: usize) {
+        self.pc = pc;
+    }
+
+    /// How many CALLs are waiting on a RET
+    pub fn call_depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn heap(&self) -> &[u8] {
+        &self.heap
+    }
+
+    /// Everything PUSHed and not yet POPped, from the bottom of the stack up
+    pub fn stack(&self) -> &[i32] {
+        &self.stack
+    }
+
+    /// Where each CALL waiting on a RET will return to, innermost first
+    pub fn return_addresses(&self) -> Vec<usize> {
+        self.frames.iter().rev().map(|frame| frame.return_address).collect()
+    }
+
+    /// Reads from the program image or, at `HEAP_BASE` and above, the heap. Nothing is returned unless every byte asked
+    /// for is there
+    pub fn read_memory(&self, address: usize, length: usize) -> Option<&[u8]> {
+        let end = address.checked_add(length)?;
+        if address >= HEAP_BASE {
+            return self.heap.get(address - HEAP_BASE..end - HEAP_BASE);
+        }
+        self.program.get(address..end)
+    }
+
+    /// Writes to the same places `read_memory` reads from, returning false and leaving memory as it was if any of the
+    /// bytes would land outside them. Writes to the read-only section are seen by PRTS and LOADF
+    pub fn write_memory(&mut self, address: usize, bytes: &[u8]) -> bool {
+        let end = match address.checked_add(bytes.len()) {
+            Some(end) => end,
+            None => { return false; }
+        };
+        if address >= HEAP_BASE {
+            return match self.heap.get_mut(address - HEAP_BASE..end - HEAP_BASE) {
+                Some(heap) => {
+                    heap.copy_from_slice(bytes);
+                    true
+                },
+                None => false,
+            };
+        }
+        match self.program.get_mut(address..end) {
+            Some(program) => { program.copy_from_slice(bytes); },
+            None => { return false; }
+        }
+        for (i, byte) in bytes.iter().enumerate() {
+            if let Some(ro) = (address + i).checked_sub(self.ro_offset).and_then(|offset| self.ro_data.get_mut(offset)) {
+                *ro = *byte;
+            }
+        }
+        true
+    }
+
+    /// Runs from the entry point for at most `n` instructions, stopping with `ExitReason::OutOfFuel` if the program
+    /// hasn't finished by then
+    pub fn run_for(&mut self, n: u64) -> Result<ExitReason, VmError> {
+        self.fuel = Some(n);
+        self.run()
+    }
+
+    /// Carries on from wherever the VM stopped, like after running out of fuel
+    pub fn resume(&mut self) -> Result<ExitReason, VmError> {
+        loop {
+            match self.execute_instruction()? {
+                ExitReason::Stepped => {},
+                reason => { return Ok(reason); }
+            }
         }
     }
 
     /// Executes one instruction. Meant to allow for more controlled execution of the VM
-    pub fn run_once(&mut self) {
-        self.execute_instruction();
+    pub fn run_once(&mut self) -> Result<ExitReason, VmError> {
+        self.execute_instruction()
     }
 
     pub fn add_byte(&mut self, b: u8) {
         self.program.push(b);
     }
 
-    pub fn add_bytes(&mut self, program: &mut Vec<u8>) {
-        self.program.append(program)
+    /// Appends to the program and checks the result is a valid image. On failure the VM is left as it was
+    pub fn add_bytes(&mut self, program: &mut Vec<u8>) -> Result<(), HeaderError> {
+        let mut image = self.program.clone();
+        image.append(program);
+        let header = PieHeader::parse(&image)?;
+        self.ro_data = header.ro_section(&image).to_vec();
+        self.ro_offset = header.ro_offset as usize;
+        self.entry_point = header.entry_point as usize;
+        self.debug_info = match header.debug_section(&image) {
+            Some(section) => Some(DebugInfo::parse(section)?),
+            None => None,
+        };
+        // Execution stops at the end of the program, so the debug info can't be left after the code
+        image.truncate((header.code_offset + header.code_length) as usize);
+        self.program = image;
+        Ok(())
+    }
+
+    /// What the assembler knew about the loaded program, if it left debug info in the image
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.debug_info.as_ref()
+    }
+
+    /// Describes a trap by where in the source it happened, like `divide by zero at loop.iasm:12:5`, or by its pc if
+    /// the program has no debug info
+    pub fn describe_error(&self, error: &VmError) -> String {
+        match self.debug_info.as_ref().and_then(|debug_info| debug_info.location(error.pc as Address)) {
+            Some(location) => format!("{} at {}:{}:{}", error.trap, location.file, location.line, location.column),
+            None => error.to_string(),
+        }
     }
 
     pub fn clear_program(&mut self) {
         self.program.clear();
     }
 
-    fn execute_instruction(&mut self) -> bool {
-        println!("Executing instruction at {}. Program length: {}", self.pc, self.program.len());
-        if self.pc >= self.program.len() {
-            return true;
+    fn execute_instruction(&mut self) -> Result<ExitReason, VmError> {
+        trace!("Executing instruction at {}. Program length: {}", self.pc, self.program.len());
+        if self.pc == self.program.len() {
+            return Ok(ExitReason::EndOfProgram);
+        }
+        if self.fuel == Some(0) {
+            return Ok(ExitReason::OutOfFuel);
+        }
+        let pc = self.pc;
+        // Snapshotting the registers every instruction isn't free, so it's only done when someone is watching
+        let before = self.tracer.as_ref().map(|_| (self.registers, self.float_registers, self.flags()));
+        let result = self.execute(pc).map_err(|trap| VmError { pc, trap });
+        if let Some((registers, float_registers, flags)) = before {
+            let trap = result.as_ref().err().map(|e| e.trap.clone());
+            self.trace(pc, (&registers, &float_registers, flags), trap);
         }
-        match self.decode_opcode() {
-            Opcode::JEQ => {
-                let register = self.next_8_bits() as usize;
-                let target = self.registers[register];
+        self.executed += 1;
+        if let Some(fuel) = self.fuel.as_mut() {
+            *fuel -= 1;
+        }
+        result
+    }
+
+    fn execute(&mut self, pc: usize) -> Result<ExitReason, Trap> {
+        let instruction = self.decode(pc)?;
+        // Every instruction is the same length, so the next one is always right after it unless this one jumps.
+        // Decoding has already checked this doesn't overflow
+        self.pc = pc + INSTRUCTION_LENGTH;
+        let operands = &instruction.operands;
+        // Register operands have already been checked, so they can be used to index straight into the registers
+        let r = |i: usize| operands[i] as usize;
+        match instruction.opcode {
+            Opcode::JMPE => {
+                if self.equal_flag {
+                    self.pc = self.registers[r(0)] as usize;
+                }
+            },
+            Opcode::DJMP => {
+                self.pc = operands[0] as usize;
+            },
+            Opcode::DJMPE => {
                 if self.equal_flag {
-                    self.pc = target as usize;
+                    self.pc = operands[0] as usize;
+                }
+            },
+            Opcode::JNEQ => {
+                if !self.equal_flag {
+                    self.pc = operands[0] as usize;
+                }
+            },
+            Opcode::JGT | Opcode::JLT | Opcode::JGTE | Opcode::JLTE => {
+                let taken = matches!(
+                    (instruction.opcode, self.last_comparison),
+                    (Opcode::JGT, Some(Ordering::Greater)) | (Opcode::JLT, Some(Ordering::Less)) |
+                    (Opcode::JGTE, Some(Ordering::Greater | Ordering::Equal)) | (Opcode::JLTE, Some(Ordering::Less | Ordering::Equal))
+                );
+                if taken {
+                    self.pc = operands[0] as usize;
                 }
             },
-            Opcode::LTQ => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.equal_flag = register1 <= register2;
-                self.next_8_bits();
-            },
-            Opcode::GTQ => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.equal_flag = register1 >= register2;
-                self.next_8_bits();
-            },
-            Opcode::LT => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.equal_flag = register1 < register2;
-                self.next_8_bits();
-            },
-            Opcode::GT => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.equal_flag = register1 > register2;
-                self.next_8_bits();
-            },
-            Opcode::NEQ => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.equal_flag = register1 != register2;
-                self.next_8_bits();
-            },
-            Opcode::EQ => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.equal_flag = register1 == register2;
-                self.next_8_bits();
+            Opcode::EQ | Opcode::NEQ | Opcode::GT | Opcode::LT | Opcode::GTE | Opcode::LTE => {
+                let ordering = self.registers[r(0)].cmp(&self.registers[r(1)]);
+                self.compare(instruction.opcode, Some(ordering));
             },
+            // Relative jumps count from the start of the next instruction
             Opcode::JMPF => {
-                let value = self.registers[self.next_8_bits() as usize] as usize;
-                self.pc += value;
+                let value = self.registers[r(0)] as usize;
+                self.pc = self.pc.checked_add(value).ok_or(Trap::PcOutOfBounds)?;
             },
             Opcode::JMPB => {
-                let value = self.registers[self.next_8_bits() as usize] as usize;
-                self.pc -= value;
+                let value = self.registers[r(0)] as usize;
+                self.pc = self.pc.checked_sub(value).ok_or(Trap::PcOutOfBounds)?;
+            },
+            Opcode::DJMPF => {
+                self.pc = self.pc.checked_add(operands[0] as usize).ok_or(Trap::PcOutOfBounds)?;
+            },
+            Opcode::DJMPB => {
+                self.pc = self.pc.checked_sub(operands[0] as usize).ok_or(Trap::PcOutOfBounds)?;
             },
             Opcode::JMP => {
-                let target = self.registers[self.next_8_bits() as usize];
-                self.pc = target as usize;
+                self.pc = self.registers[r(0)] as usize;
             },
             Opcode::DIV => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 / register2;
-                self.remainder = (register1 % register2) as u32;
+                let register1 = self.registers[r(0)];
+                let register2 = self.registers[r(1)];
+                if register2 == 0 {
+                    return Err(Trap::DivideByZero);
+                }
+                self.registers[r(2)] = register1.checked_div(register2).ok_or(Trap::ArithmeticOverflow)?;
+                self.remainder = register1 % register2;
             },
             Opcode::ADD => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 + register2;
+                self.registers[r(2)] = self.registers[r(0)].checked_add(self.registers[r(1)]).ok_or(Trap::ArithmeticOverflow)?;
+            },
+            Opcode::MOD => {
+                let register1 = self.registers[r(0)];
+                let register2 = self.registers[r(1)];
+                if register2 == 0 {
+                    return Err(Trap::DivideByZero);
+                }
+                self.registers[r(2)] = register1.checked_rem(register2).ok_or(Trap::ArithmeticOverflow)?;
+            },
+            Opcode::RMDR => {
+                self.registers[r(0)] = self.remainder;
+            },
+            Opcode::NEG => {
+                self.registers[r(1)] = self.registers[r(0)].checked_neg().ok_or(Trap::ArithmeticOverflow)?;
+            },
+            // Bitwise operations never trap. Shifts only look at the bottom five bits of the amount, and whatever is
+            // shifted out is lost
+            Opcode::AND => {
+                self.registers[r(2)] = self.registers[r(0)] & self.registers[r(1)];
+            },
+            Opcode::OR => {
+                self.registers[r(2)] = self.registers[r(0)] | self.registers[r(1)];
+            },
+            Opcode::XOR => {
+                self.registers[r(2)] = self.registers[r(0)] ^ self.registers[r(1)];
+            },
+            Opcode::NOT => {
+                self.registers[r(1)] = !self.registers[r(0)];
+            },
+            Opcode::SHL => {
+                self.registers[r(2)] = self.registers[r(0)].wrapping_shl(self.registers[r(1)] as u32);
+            },
+            Opcode::SHR => {
+                self.registers[r(2)] = (self.registers[r(0)] as u32).wrapping_shr(self.registers[r(1)] as u32) as i32;
+            },
+            Opcode::SAR => {
+                self.registers[r(2)] = self.registers[r(0)].wrapping_shr(self.registers[r(1)] as u32);
             },
             Opcode::INC => {
-                self.registers[self.next_8_bits() as usize] += 1;
+                self.registers[r(0)] = self.registers[r(0)].checked_add(1).ok_or(Trap::ArithmeticOverflow)?;
             },
             Opcode::SUB => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 - register2;
+                self.registers[r(2)] = self.registers[r(0)].checked_sub(self.registers[r(1)]).ok_or(Trap::ArithmeticOverflow)?;
             },
             Opcode::DEC => {
-                self.registers[self.next_8_bits() as usize] -= 1;
+                self.registers[r(0)] = self.registers[r(0)].checked_sub(1).ok_or(Trap::ArithmeticOverflow)?;
             }
             Opcode::MUL => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 * register2;
+                self.registers[r(2)] = self.registers[r(0)].checked_mul(self.registers[r(1)]).ok_or(Trap::ArithmeticOverflow)?;
             },
             Opcode::LOAD => {
-                let register = self.next_8_bits() as usize;
-                println!("Register: {}", register);
-                let number = self.next_16_bits() as u32;
-                println!("Number: {}", number);
-                self.registers[register] = number as i32;
+                trace!("Loading {} into register {}", operands[1], r(0));
+                self.registers[r(0)] = operands[1] as i32;
+            },
+            Opcode::LOADHI => {
+                self.registers[r(0)] = ((operands[1] << 16) | (self.registers[r(0)] as u32 & 0xFFFF)) as i32;
             },
             Opcode::ALOC => {
-                let register = self.next_8_bits() as usize;
-                let bytes = self.registers[register];
-                let new_end = self.heap.len() as i32 + bytes;
+                let bytes = self.registers[r(0)] as i64;
+                let new_end = self.heap.len() as i64 + bytes;
+                if new_end < 0 || new_end > MAX_HEAP_SIZE as i64 {
+                    return Err(Trap::HeapOverflow{requested: bytes});
+                }
                 self.heap.resize(new_end as usize, 0);
             },
+            Opcode::CALL => {
+                if self.frames.len() >= MAX_CALL_DEPTH {
+                    return Err(Trap::StackOverflow);
+                }
+                self.frames.push(Frame { return_address: self.pc, stack_base: self.stack.len() });
+                self.pc = operands[0] as usize;
+            },
+            Opcode::RET => {
+                let frame = self.frames.pop().ok_or(Trap::StackUnderflow)?;
+                self.stack.truncate(frame.stack_base);
+                self.pc = frame.return_address;
+            },
+            Opcode::PUSH => {
+                if self.stack.len() >= MAX_STACK_SIZE {
+                    return Err(Trap::StackOverflow);
+                }
+                self.stack.push(self.registers[r(0)]);
+            },
+            Opcode::POP => {
+                self.registers[r(0)] = self.stack.pop().ok_or(Trap::StackUnderflow)?;
+            },
+            // Loads and stores take a register, then a base register and a byte offset into the heap. Words are
+            // little-endian and loads are zero-extended
+            Opcode::LB => {
+                let address = self.heap_address(r(1), operands[2], 1)?;
+                self.registers[r(0)] = self.heap[address] as i32;
+            },
+            Opcode::LH => {
+                let address = self.heap_address(r(1), operands[2], 2)?;
+                self.registers[r(0)] = LittleEndian::read_u16(&self.heap[address..]) as i32;
+            },
+            Opcode::LW => {
+                let address = self.heap_address(r(1), operands[2], 4)?;
+                self.registers[r(0)] = LittleEndian::read_i32(&self.heap[address..]);
+            },
+            Opcode::SB => {
+                let address = self.heap_address(r(1), operands[2], 1)?;
+                self.heap[address] = self.registers[r(0)] as u8;
+            },
+            Opcode::SH => {
+                let address = self.heap_address(r(1), operands[2], 2)?;
+                LittleEndian::write_u16(&mut self.heap[address..], self.registers[r(0)] as u16);
+            },
+            Opcode::SW => {
+                let address = self.heap_address(r(1), operands[2], 4)?;
+                LittleEndian::write_i32(&mut self.heap[address..], self.registers[r(0)]);
+            },
+            Opcode::PRTS => {
+                let start = operands[0] as usize;
+                if start >= self.ro_data.len() {
+                    return Err(Trap::ReadOnlyOutOfBounds{offset: start});
+                }
+                // Strings run up to their null terminator, or the end of the section if someone forgot it
+                let end = match self.ro_data[start..].iter().position(|b| *b == 0) {
+                    Some(length) => { start + length },
+                    None => { self.ro_data.len() }
+                };
+                self.output.write_all(&self.ro_data[start..end]).map_err(|_| Trap::OutputFailed)?;
+                self.output.flush().map_err(|_| Trap::OutputFailed)?;
+            },
+            Opcode::LOADF => {
+                let start = operands[1] as usize;
+                let bytes = self.ro_data.get(start..start + 8).ok_or(Trap::ReadOnlyOutOfBounds{offset: start})?;
+                self.float_registers[r(0)] = LittleEndian::read_f64(bytes);
+            },
+            // Float arithmetic follows IEEE 754 and never traps, so dividing by zero gives an infinity or NaN
+            Opcode::ADDF => {
+                self.float_registers[r(2)] = self.float_registers[r(0)] + self.float_registers[r(1)];
+            },
+            Opcode::SUBF => {
+                self.float_registers[r(2)] = self.float_registers[r(0)] - self.float_registers[r(1)];
+            },
+            Opcode::MULF => {
+                self.float_registers[r(2)] = self.float_registers[r(0)] * self.float_registers[r(1)];
+            },
+            Opcode::DIVF => {
+                self.float_registers[r(2)] = self.float_registers[r(0)] / self.float_registers[r(1)];
+            },
+            Opcode::EQF | Opcode::NEQF | Opcode::GTF | Opcode::LTF | Opcode::GTEF | Opcode::LTEF => {
+                let ordering = self.float_registers[r(0)].partial_cmp(&self.float_registers[r(1)]);
+                self.compare(instruction.opcode, ordering);
+            },
+            Opcode::ITOF => {
+                self.float_registers[r(1)] = self.registers[r(0)] as f64;
+            },
+            Opcode::FTOI => {
+                let value = self.float_registers[r(0)].trunc();
+                if value.is_nan() || value < i32::MIN as f64 || value > i32::MAX as f64 {
+                    return Err(Trap::InvalidConversion{value: self.float_registers[r(0)]});
+                }
+                self.registers[r(1)] = value as i32;
+            },
             Opcode::HLT => {
-                println!("HLT encountered");
-                return true;
+                trace!("HLT encountered");
+                return Ok(ExitReason::Halted);
             },
             Opcode::IGL => {
-                println!("Illegal instruction encountered");
-                return true;
+                return Err(Trap::IllegalOpcode{opcode: self.program[pc]});
+            }
+        }
+        Ok(ExitReason::Stepped)
+    }
+
+    /// Tells the tracer what the instruction at `pc` changed, given the registers and flags from before it ran
+    fn trace(&mut self, pc: usize, before: (&[i32], &[f64], Flags), trap: Option<Trap>) {
+        let instruction = match self.decode(pc) {
+            Ok(instruction) => instruction,
+            Err(_) => { return; }
+        };
+        let (registers, float_registers, flags) = before;
+        let mut step = Step::new(pc, instruction, (registers, float_registers), (&self.registers, &self.float_registers));
+        if self.flags() != flags {
+            step.flags = Some((flags, self.flags()));
+        }
+        // Stores don't change their base register, so the address they wrote to can be worked out again afterwards
+        let size = match step.instruction.opcode {
+            Opcode::SB => 1,
+            Opcode::SH => 2,
+            Opcode::SW => 4,
+            _ => 0,
+        };
+        if size > 0 && trap.is_none() {
+            let operands = &step.instruction.operands;
+            if let Ok(address) = self.heap_address(operands[1] as usize, operands[2], size) {
+                step.heap.push((address, self.heap[address..address + size].to_vec()));
             }
         }
-        false
+        step.trap = trap;
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.step(&step);
+        }
     }
 
-    fn decode_opcode(&mut self) -> Opcode {
-        let opcode = Opcode::from(self.program[self.pc]);
-        println!("opcode ({:?}): {:?}", self.program[self.pc], opcode);
-        self.pc += 1;
-        return opcode;
+    /// What the last comparison left for the conditional jumps
+    pub fn flags(&self) -> Flags {
+        Flags { equal: self.equal_flag, comparison: self.last_comparison }
     }
-    
-    fn next_8_bits(&mut self) -> u8 {
-        let result = self.program[self.pc];
-        self.pc += 1;
-        return result;
+
+    pub fn set_flags(&mut self, flags: Flags) {
+        self.equal_flag = flags.equal;
+        self.last_comparison = flags.comparison;
     }
 
-    fn next_16_bits(&mut self) -> u16 {
-        let result = ((self.program[self.pc] as u16) << 8) | self.program[self.pc + 1] as u16;
-        self.pc += 2;
-        return result;
+    /// Records the outcome of a comparison. The equal flag says whether the opcode's condition held, and the ordering is
+    /// kept for the branches that look at it. Floats that can't be ordered, because one is NaN, meet no condition
+    /// except NEQF's
+    fn compare(&mut self, opcode: Opcode, ordering: Option<Ordering>) {
+        self.last_comparison = ordering;
+        self.equal_flag = match opcode {
+            Opcode::EQ | Opcode::EQF => ordering == Some(Ordering::Equal),
+            Opcode::NEQ | Opcode::NEQF => ordering != Some(Ordering::Equal),
+            Opcode::GT | Opcode::GTF => ordering == Some(Ordering::Greater),
+            Opcode::LT | Opcode::LTF => ordering == Some(Ordering::Less),
+            Opcode::GTE | Opcode::GTEF => ordering == Some(Ordering::Greater) || ordering == Some(Ordering::Equal),
+            _ => ordering == Some(Ordering::Less) || ordering == Some(Ordering::Equal),
+        };
+    }
+
+    /// Reads the instruction at `pc`, making sure all of it is inside the program and every register it names exists
+    fn decode(&self, pc: usize) -> Result<Instruction, Trap> {
+        // A jump to a negative address leaves the pc so close to the top of the address space that the end of the
+        // instruction would wrap around
+        let end = pc.checked_add(INSTRUCTION_LENGTH).ok_or(Trap::PcOutOfBounds)?;
+        let bytes = self.program.get(pc..end).ok_or(Trap::PcOutOfBounds)?;
+        let instruction = Instruction::decode(bytes);
+        trace!("opcode ({:?}): {:?}", bytes[0], instruction.opcode);
+        for (kind, value) in instruction.opcode.operands().iter().zip(&instruction.operands) {
+            let is_register = *kind == OperandKind::Register || *kind == OperandKind::FloatRegister;
+            if is_register && *value as usize >= self.registers.len() {
+                return Err(Trap::BadRegister{register: *value as u8});
+            }
+        }
+        Ok(instruction)
+    }
+
+    /// Works out the address of a base register plus an offset, making sure `size` bytes there are inside the heap
+    fn heap_address(&self, base: usize, offset: u32, size: usize) -> Result<usize, Trap> {
+        let address = self.registers[base] as i64 + offset as i64;
+        if address < 0 || address + size as i64 > self.heap.len() as i64 {
+            return Err(Trap::HeapOutOfBounds{address});
+        }
+        Ok(address as usize)
     }
 
     pub fn get_test_vm() -> VM {
@@ -185,31 +668,33 @@ impl VM {
         test_vm.registers[1] = 10;
         test_vm
     }
+}
 
-    // fn verify_header(&self) -> bool {
-    //     if self.program[0..4] != PIE_HEADER_PREFIX {
-    //         return false;
-    //     }
-    //     true
-    // }
+/// Lets tests read back what the VM printed
+#[cfg(test)]
+#[derive(Clone)]
+pub struct SharedBuffer(pub std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+#[cfg(test)]
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
-    use crate::assembler::PIE_HEADER_PREFIX;
-
-    fn prepend_header(mut b: Vec<u8>) -> Vec<u8> {
-        let mut prepension = vec![];
-        for byte in PIE_HEADER_PREFIX.iter() {
-            prepension.push(byte.clone());
-        }
-        while prepension.len() < PIE_HEADER_LENGTH {
-            prepension.push(0);
-        }
-        prepension.append(&mut b);
-        prepension
-    }
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::assembler::Assembler;
+    use crate::pie::{build_image, prepend_header};
+    use crate::tracer::MemoryTracer;
 
     #[test]
     fn test_create_vm() {
@@ -222,8 +707,8 @@ mod tests {
       let mut test_vm = VM::new();
       let test_bytes = vec![5,0,0,0];
       test_vm.program = prepend_header(test_bytes);
-      test_vm.run();
-      assert_eq!(test_vm.pc, PIE_HEADER_LENGTH + 1);
+      assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+      assert_eq!(test_vm.pc, PIE_HEADER_LENGTH + 4);
     }
 
     #[test]
@@ -231,8 +716,320 @@ mod tests {
       let mut test_vm = VM::new();
       let test_bytes = vec![200,0,0,0];
       test_vm.program = prepend_header(test_bytes);
-      test_vm.run();
-      assert_eq!(test_vm.pc, PIE_HEADER_LENGTH + 1);
+      assert_eq!(test_vm.run(), Err(VmError { pc: PIE_HEADER_LENGTH, trap: Trap::IllegalOpcode { opcode: 200 } }));
+      assert_eq!(test_vm.pc, PIE_HEADER_LENGTH + 4);
+    }
+
+    #[test]
+    fn test_end_of_program() {
+      let mut test_vm = VM::new();
+      test_vm.program = prepend_header(vec![17, 0, 0, 0]);
+      assert_eq!(test_vm.run(), Ok(ExitReason::EndOfProgram));
+    }
+
+    #[test]
+    fn test_run_for_infinite_loop() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.program = prepend_header(vec![Opcode::INC as u8, 2, 0, 0, Opcode::DJMP as u8, 0, 0, PIE_HEADER_LENGTH as u8]);
+        assert_eq!(test_vm.run_for(101), Ok(ExitReason::OutOfFuel));
+        assert_eq!(test_vm.instructions_executed(), 101);
+        assert_eq!(test_vm.registers[2], 51);
+        assert_eq!(test_vm.fuel(), Some(0));
+
+        // More fuel lets it carry on from where it stopped
+        test_vm.set_fuel(Some(1));
+        assert_eq!(test_vm.resume(), Ok(ExitReason::OutOfFuel));
+        assert_eq!(test_vm.pc, PIE_HEADER_LENGTH);
+        assert_eq!(test_vm.instructions_executed(), 102);
+    }
+
+    #[test]
+    fn test_run_for_halts_and_traps() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.program = prepend_header(vec![Opcode::INC as u8, 2, 0, 0, 5, 0, 0, 0]);
+        assert_eq!(test_vm.run_for(10), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.fuel(), Some(8));
+        assert_eq!(test_vm.run_for(1), Ok(ExitReason::OutOfFuel));
+
+        test_vm.program = prepend_header(vec![200, 0, 0, 0]);
+        assert_eq!(test_vm.run_for(10), Err(VmError { pc: PIE_HEADER_LENGTH, trap: Trap::IllegalOpcode { opcode: 200 } }));
+        assert_eq!(test_vm.instructions_executed(), 4);
+    }
+
+    #[test]
+    fn test_tracer() {
+        let mut test_vm = VM::get_test_vm();
+        let tracer = MemoryTracer::default();
+        test_vm.set_tracer(Some(Box::new(tracer.clone())));
+        test_vm.program = prepend_header(vec![
+            Opcode::ADD as u8, 0, 1, 2, Opcode::ITOF as u8, 2, 3, 0, Opcode::ALOC as u8, 2, 0, 0, Opcode::SW as u8, 1, 3, 4,
+            Opcode::LT as u8, 0, 1, 0, Opcode::DIV as u8, 0, 4, 5,
+        ]);
+        assert_eq!(test_vm.run(), Err(VmError { pc: PIE_HEADER_LENGTH + 20, trap: Trap::DivideByZero }));
+        let steps = tracer.steps.borrow();
+        assert_eq!(steps.len(), 6);
+        assert_eq!(steps[0].pc, PIE_HEADER_LENGTH);
+        assert_eq!(steps[0].instruction.opcode, Opcode::ADD);
+        assert_eq!(steps[0].instruction.operands, vec![0, 1, 2]);
+        assert_eq!(steps[0].registers, vec![(2, 0, 15)]);
+        assert_eq!(steps[1].float_registers, vec![(3, 0.0, 15.0)]);
+        assert_eq!(steps[3].heap, vec![(4, vec![10, 0, 0, 0])]);
+        assert_eq!(steps[4].flags, Some((Flags { equal: false, comparison: None }, Flags { equal: true, comparison: Some(Ordering::Less) })));
+        assert!(steps[5].registers.is_empty());
+        assert_eq!(steps[5].trap, Some(Trap::DivideByZero));
+    }
+
+    #[test]
+    fn test_read_write_memory() {
+        let mut test_vm = VM::new();
+        test_vm.add_bytes(&mut build_image(b"Hi\0", &[Opcode::PRTS as u8, 0, 0, 0])).unwrap();
+        assert_eq!(test_vm.read_memory(PIE_HEADER_LENGTH, 2), Some(&b"Hi"[..]));
+        assert!(test_vm.write_memory(PIE_HEADER_LENGTH, b"Yo"));
+        assert_eq!(test_vm.ro_data, b"Yo\0");
+        assert!(!test_vm.write_memory(test_vm.program.len() - 1, b"ab"));
+        assert_eq!(test_vm.read_memory(HEAP_BASE, 1), None);
+
+        test_vm.heap = vec![0; 4];
+        assert!(test_vm.write_memory(HEAP_BASE + 2, &[7, 8]));
+        assert_eq!(test_vm.read_memory(HEAP_BASE, 4), Some(&[0, 0, 7, 8][..]));
+        assert!(!test_vm.write_memory(HEAP_BASE + 3, &[1, 2]));
+    }
+
+    #[test]
+    fn test_bad_register_trap() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.program = prepend_header(vec![17, 0, 0, 0, 1, 0, 32, 2]);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.run_once(), Err(VmError { pc: PIE_HEADER_LENGTH + 4, trap: Trap::BadRegister { register: 32 } }));
+    }
+
+    #[test]
+    fn test_truncated_instruction_trap() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.program = prepend_header(vec![0, 0, 1]);
+        assert_eq!(test_vm.run(), Err(VmError { pc: PIE_HEADER_LENGTH, trap: Trap::PcOutOfBounds }));
+    }
+
+    #[test]
+    fn test_jump_out_of_bounds_trap() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.registers[0] = 1000;
+        test_vm.program = prepend_header(vec![6, 0, 0, 0]);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.run_once(), Err(VmError { pc: 1000, trap: Trap::PcOutOfBounds }));
+    }
+
+    #[test]
+    fn test_jump_to_negative_address_trap() {
+        let mut test_vm = VM::new();
+        test_vm.add_bytes(&mut Assembler::new().assemble(".code\nload $0 #-2\njmp $0").unwrap()).unwrap();
+        assert_eq!(test_vm.run(), Err(VmError { pc: -2i32 as usize, trap: Trap::PcOutOfBounds }));
+    }
+
+    #[test]
+    fn test_jmpb_underflow_trap() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.registers[0] = 1000;
+        test_vm.program = prepend_header(vec![8, 0, 0, 0]);
+        assert_eq!(test_vm.run_once(), Err(VmError { pc: PIE_HEADER_LENGTH, trap: Trap::PcOutOfBounds }));
+    }
+
+    #[test]
+    fn test_divide_by_zero_trap() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.registers[1] = 0;
+        test_vm.program = prepend_header(vec![4, 0, 1, 2]);
+        assert_eq!(test_vm.run_once(), Err(VmError { pc: PIE_HEADER_LENGTH, trap: Trap::DivideByZero }));
+    }
+
+    #[test]
+    fn test_trap_source_location() {
+        let mut asm = Assembler::new();
+        asm.set_file_name("loop.iasm");
+        asm.set_debug_info(true);
+        let mut image = asm.assemble(".code\nload $0 #4\nload $1 #0\n  div $0 $1 $2\nhlt").unwrap();
+        let code_end = PieHeader::parse(&image).unwrap();
+        let code_end = (code_end.code_offset + code_end.code_length) as usize;
+        let mut test_vm = VM::new();
+        test_vm.add_bytes(&mut image).unwrap();
+        assert_eq!(test_vm.program.len(), code_end);
+        let error = test_vm.run().unwrap_err();
+        assert_eq!(test_vm.describe_error(&error), "divide by zero at loop.iasm:4:3");
+        // Running off the end isn't anywhere in the source
+        let error = VmError { pc: code_end, trap: Trap::PcOutOfBounds };
+        assert_eq!(test_vm.describe_error(&error), format!("{} at pc {}", Trap::PcOutOfBounds, code_end));
+
+        // Without debug info all there is to go on is the pc
+        let mut test_vm = VM::new();
+        test_vm.add_bytes(&mut Assembler::new().assemble(".code\nload $1 #0\ndiv $0 $1 $2").unwrap()).unwrap();
+        let error = test_vm.run().unwrap_err();
+        assert_eq!(test_vm.describe_error(&error), format!("divide by zero at pc {}", PIE_HEADER_LENGTH + 4));
+    }
+
+    #[test]
+    fn test_div_rmdr_opcodes() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.registers[0] = -17;
+        test_vm.program = prepend_header(vec![Opcode::DIV as u8, 0, 1, 2, Opcode::RMDR as u8, 3, 0, 0]);
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2], -1);
+        assert_eq!(test_vm.registers[3], -7);
+    }
+
+    #[test]
+    fn test_mod_opcode() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.registers[0] = 17;
+        test_vm.registers[1] = -5;
+        test_vm.program = prepend_header(vec![Opcode::MOD as u8, 0, 1, 2]);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], 2);
+
+        test_vm.registers[1] = 0;
+        test_vm.program = prepend_header(vec![Opcode::MOD as u8, 0, 1, 2]);
+        assert_eq!(test_vm.run(), Err(VmError { pc: PIE_HEADER_LENGTH, trap: Trap::DivideByZero }));
+
+        test_vm.registers[0] = i32::MIN;
+        test_vm.registers[1] = -1;
+        assert_eq!(test_vm.run(), Err(VmError { pc: PIE_HEADER_LENGTH, trap: Trap::ArithmeticOverflow }));
+    }
+
+    #[test]
+    fn test_neg_opcode() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.program = prepend_header(vec![Opcode::NEG as u8, 0, 2, 0]);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], -5);
+
+        test_vm.registers[0] = i32::MIN;
+        assert_eq!(test_vm.run(), Err(VmError { pc: PIE_HEADER_LENGTH, trap: Trap::ArithmeticOverflow }));
+    }
+
+    #[test]
+    fn test_bitwise_opcodes() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.registers[0] = 0b1100;
+        test_vm.registers[1] = 0b1010;
+        test_vm.program = prepend_header(vec![
+            Opcode::AND as u8, 0, 1, 2,
+            Opcode::OR as u8, 0, 1, 3,
+            Opcode::XOR as u8, 0, 1, 4,
+            Opcode::NOT as u8, 0, 5, 0,
+        ]);
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2], 0b1000);
+        assert_eq!(test_vm.registers[3], 0b1110);
+        assert_eq!(test_vm.registers[4], 0b0110);
+        assert_eq!(test_vm.registers[5], !0b1100);
+    }
+
+    #[test]
+    fn test_shift_opcodes() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.registers[0] = -16;
+        test_vm.registers[1] = 2;
+        test_vm.registers[6] = 33;
+        test_vm.program = prepend_header(vec![
+            Opcode::SHL as u8, 0, 1, 2,
+            Opcode::SHR as u8, 0, 1, 3,
+            Opcode::SAR as u8, 0, 1, 4,
+            Opcode::SHL as u8, 0, 6, 5,
+        ]);
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2], -64);
+        assert_eq!(test_vm.registers[3], 0x3fff_fffc);
+        assert_eq!(test_vm.registers[4], -4);
+        // Shift amounts wrap around at 32
+        assert_eq!(test_vm.registers[5], -32);
+    }
+
+    #[test]
+    fn test_loadf_opcode() {
+        let mut test_vm = VM::new();
+        let mut ro = vec![1, 2];
+        ro.extend_from_slice(&2.5f64.to_le_bytes());
+        test_vm.add_bytes(&mut build_image(&ro, &[Opcode::LOADF as u8, 3, 0, 2, Opcode::LOADF as u8, 4, 0, 3])).unwrap();
+        test_vm.pc = test_vm.entry_point;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.float_registers[3], 2.5);
+        assert_eq!(test_vm.run_once(), Err(VmError { pc: PIE_HEADER_LENGTH + 14, trap: Trap::ReadOnlyOutOfBounds { offset: 3 } }));
+    }
+
+    #[test]
+    fn test_float_arithmetic_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.float_registers[0] = 7.5;
+        test_vm.float_registers[1] = 2.5;
+        test_vm.program = prepend_header(vec![
+            Opcode::ADDF as u8, 0, 1, 2,
+            Opcode::SUBF as u8, 0, 1, 3,
+            Opcode::MULF as u8, 0, 1, 4,
+            Opcode::DIVF as u8, 0, 1, 5,
+            Opcode::DIVF as u8, 0, 6, 7,
+        ]);
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.float_registers[2], 10.0);
+        assert_eq!(test_vm.float_registers[3], 5.0);
+        assert_eq!(test_vm.float_registers[4], 18.75);
+        assert_eq!(test_vm.float_registers[5], 3.0);
+        assert_eq!(test_vm.float_registers[7], f64::INFINITY);
+    }
+
+    #[test]
+    fn test_float_comparison_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.float_registers[0] = 1.5;
+        test_vm.float_registers[1] = 2.5;
+        test_vm.float_registers[2] = f64::NAN;
+        let cases = [
+            (Opcode::EQF, 0, 0, true), (Opcode::EQF, 0, 1, false),
+            (Opcode::NEQF, 0, 1, true), (Opcode::NEQF, 2, 2, true),
+            (Opcode::GTF, 1, 0, true), (Opcode::GTF, 0, 1, false),
+            (Opcode::LTF, 0, 1, true), (Opcode::LTF, 2, 1, false),
+            (Opcode::GTEF, 0, 0, true), (Opcode::GTEF, 0, 1, false),
+            (Opcode::LTEF, 0, 0, true), (Opcode::LTEF, 2, 2, false),
+        ];
+        for (opcode, first, second, expected) in cases {
+            test_vm.program = prepend_header(vec![opcode as u8, first, second, 0]);
+            test_vm.run().unwrap();
+            assert_eq!(test_vm.equal_flag, expected, "{:?} ${} ${}", opcode, first, second);
+        }
+    }
+
+    #[test]
+    fn test_float_conversion_opcodes() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.float_registers[1] = -3.75;
+        test_vm.program = prepend_header(vec![Opcode::ITOF as u8, 0, 0, 0, Opcode::FTOI as u8, 1, 2, 0]);
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.float_registers[0], 5.0);
+        assert_eq!(test_vm.registers[2], -3);
+
+        for value in [f64::NAN, 1e10, f64::NEG_INFINITY] {
+            test_vm.float_registers[1] = value;
+            test_vm.program = prepend_header(vec![Opcode::FTOI as u8, 1, 2, 0]);
+            match test_vm.run() {
+                Err(VmError { trap: Trap::InvalidConversion { .. }, .. }) => {},
+                other => panic!("Expected an invalid conversion for {}, got {:?}", value, other)
+            }
+        }
+    }
+
+    #[test]
+    fn test_arithmetic_overflow_trap() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.registers[0] = i32::MAX;
+        test_vm.program = prepend_header(vec![17, 0, 0, 0]);
+        assert_eq!(test_vm.run_once(), Err(VmError { pc: PIE_HEADER_LENGTH, trap: Trap::ArithmeticOverflow }));
+        assert_eq!(test_vm.registers[0], i32::MAX);
+    }
+
+    #[test]
+    fn test_heap_overflow_trap() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.registers[0] = -1;
+        test_vm.program = prepend_header(vec![19, 0, 0, 0]);
+        assert_eq!(test_vm.run_once(), Err(VmError { pc: PIE_HEADER_LENGTH, trap: Trap::HeapOverflow { requested: -1 } }));
     }
 
     #[test]
@@ -240,17 +1037,25 @@ mod tests {
         let mut test_vm = VM::get_test_vm();
         test_vm.program = vec![0, 0, 1, 244]; // Remember, this is how we represent 500 using two u8s in little endian format
         test_vm.program = prepend_header(test_vm.program);
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[0], 500);
     }
 
+    #[test]
+    fn test_loadhi_opcode() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.program = prepend_header(vec![0, 0, 0x56, 0x78, Opcode::LOADHI as u8, 0, 0x12, 0x34]);
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], 0x1234_5678);
+    }
+
     #[test]
     fn test_jmp_opcode() {
         let mut test_vm = VM::get_test_vm();
         test_vm.registers[0] = 1;
         test_vm.program = vec![6, 0, 0, 0];
         test_vm.program = prepend_header(test_vm.program);
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.pc, 1);
     }
 
@@ -260,18 +1065,18 @@ mod tests {
         test_vm.registers[0] = 2;
         test_vm.program = vec![7, 0, 0, 0, 6, 0, 0, 0];
         test_vm.program = prepend_header(test_vm.program);
-        test_vm.run_once();
-        assert_eq!(test_vm.pc, PIE_HEADER_LENGTH + 4);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, PIE_HEADER_LENGTH + 6);
     }
 
     #[test]
     fn test_jmpb_opcode() {
         let mut test_vm = VM::get_test_vm();
-        test_vm.registers[0] = 1;
+        test_vm.registers[0] = 4;
         test_vm.program = vec![8, 0, 0, 0, 6, 0, 0, 0];
         test_vm.program = prepend_header(test_vm.program);
-        test_vm.run_once();
-        assert_eq!(test_vm.pc, PIE_HEADER_LENGTH + 1);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, PIE_HEADER_LENGTH);
     }
 
     #[test]
@@ -281,10 +1086,10 @@ mod tests {
         test_vm.registers[1] = 10;
         test_vm.program = vec![10, 0, 1, 0, 10, 0, 1, 0];
         test_vm.program = prepend_header(test_vm.program);
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.equal_flag, true);
         test_vm.registers[1] = 20;
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.equal_flag, false);
     }
 
@@ -295,10 +1100,10 @@ mod tests {
         test_vm.registers[1] = 10;
         test_vm.program = vec![11, 0, 1, 0, 11, 0, 1, 0, 11, 0, 1, 0];
         test_vm.program = prepend_header(test_vm.program);
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.equal_flag, false);
         test_vm.registers[1] = 20;
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.equal_flag, true);
     }
 
@@ -309,13 +1114,13 @@ mod tests {
         test_vm.registers[1] = 10;
         test_vm.program = vec![12, 0, 1, 0, 12, 0, 1, 0, 12, 0, 1, 0];
         test_vm.program = prepend_header(test_vm.program);
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.equal_flag, false);
         test_vm.registers[1] = 20;
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.equal_flag, false);
         test_vm.registers[1] = 5;
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.equal_flag, true);
     }
 
@@ -326,13 +1131,13 @@ mod tests {
         test_vm.registers[1] = 10;
         test_vm.program = vec![13, 0, 1, 0, 13, 0, 1, 0, 13, 0, 1, 0];
         test_vm.program = prepend_header(test_vm.program);
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.equal_flag, false);
         test_vm.registers[1] = 20;
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.equal_flag, true);
         test_vm.registers[1] = 5;
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.equal_flag, false);
     }
 
@@ -343,13 +1148,13 @@ mod tests {
         test_vm.registers[1] = 10;
         test_vm.program = vec![14, 0, 1, 0, 14, 0, 1, 0, 14, 0, 1, 0];
         test_vm.program = prepend_header(test_vm.program);
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.equal_flag, true);
         test_vm.registers[1] = 20;
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.equal_flag, false);
         test_vm.registers[1] = 5;
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.equal_flag, true);
     }
 
@@ -360,13 +1165,13 @@ mod tests {
         test_vm.registers[1] = 10;
         test_vm.program = vec![15, 0, 1, 0, 15, 0, 1, 0, 15, 0, 1, 0];
         test_vm.program = prepend_header(test_vm.program);
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.equal_flag, true);
         test_vm.registers[1] = 20;
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.equal_flag, true);
         test_vm.registers[1] = 5;
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.equal_flag, false);
     }
 
@@ -377,28 +1182,234 @@ mod tests {
         test_vm.equal_flag = true;
         test_vm.program = vec![16, 0, 0, 0];
         test_vm.program = prepend_header(test_vm.program);
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.pc, PIE_HEADER_LENGTH + 7);
     }
 
+    #[test]
+    fn test_djmp_opcode() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.program = vec![20, 0, 1, 8];
+        test_vm.program = prepend_header(test_vm.program);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 264);
+    }
+
+    #[test]
+    fn test_djmpe_opcode() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.program = vec![21, 0, 0, (PIE_HEADER_LENGTH + 8) as u8, 21, 0, 0, (PIE_HEADER_LENGTH + 8) as u8];
+        test_vm.program = prepend_header(test_vm.program);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, PIE_HEADER_LENGTH + 4);
+        test_vm.equal_flag = true;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, PIE_HEADER_LENGTH + 8);
+    }
+
+    #[test]
+    fn test_jneq_opcode() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.program = prepend_header(vec![Opcode::JNEQ as u8, 0, 0, 100]);
+        test_vm.equal_flag = true;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, PIE_HEADER_LENGTH + 4);
+        test_vm.pc = PIE_HEADER_LENGTH;
+        test_vm.equal_flag = false;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 100);
+    }
+
+    #[test]
+    fn test_ordered_branch_opcodes() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.float_registers[0] = f64::NAN;
+        // $0 is 5 and $1 is 10
+        let cases = [
+            (Opcode::LT, 0, 1, Opcode::JLT, true), (Opcode::LT, 0, 1, Opcode::JGT, false),
+            (Opcode::EQ, 1, 0, Opcode::JGT, true), (Opcode::EQ, 1, 0, Opcode::JLTE, false),
+            (Opcode::EQ, 0, 0, Opcode::JGTE, true), (Opcode::EQ, 0, 0, Opcode::JLTE, true),
+            (Opcode::EQ, 0, 0, Opcode::JLT, false), (Opcode::EQF, 0, 0, Opcode::JGTE, false),
+        ];
+        for (compare, first, second, branch, taken) in cases {
+            test_vm.program = prepend_header(vec![compare as u8, first, second, 0, branch as u8, 0, 0, 100]);
+            test_vm.pc = PIE_HEADER_LENGTH;
+            test_vm.run_once().unwrap();
+            test_vm.run_once().unwrap();
+            assert_eq!(test_vm.pc == 100, taken, "{:?} ${} ${} then {:?}", compare, first, second, branch);
+        }
+    }
+
+    #[test]
+    fn test_relative_jump_opcodes() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.program = prepend_header(vec![Opcode::DJMPF as u8, 0, 0, 4, 5, 0, 0, 0, Opcode::DJMPB as u8, 0, 0, 8]);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, PIE_HEADER_LENGTH + 8);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, PIE_HEADER_LENGTH + 4);
+    }
+
+    #[test]
+    fn test_run_skips_ro_section() {
+        let mut test_vm = VM::new();
+        test_vm.add_bytes(&mut build_image(&[72, 105, 0], &[5, 0, 0, 0])).unwrap();
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.pc, PIE_HEADER_LENGTH + 3 + 4);
+    }
+
+    #[test]
+    fn test_add_bytes_rejects_bad_header() {
+        let mut test_vm = VM::new();
+        let mut image = prepend_header(vec![5, 0, 0, 0]);
+        image[4] = 0;
+        assert_eq!(test_vm.add_bytes(&mut image), Err(HeaderError::UnsupportedVersion { version: 0 }));
+        assert_eq!(test_vm.program.len(), 0);
+        let mut image = prepend_header(vec![5, 0, 0, 0]);
+        image.push(0);
+        match test_vm.add_bytes(&mut image) {
+            Err(HeaderError::ChecksumMismatch { .. }) => {},
+            other => panic!("Expected a checksum mismatch, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_push_pop_opcodes() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.program = prepend_header(vec![24, 0, 0, 0, 24, 1, 0, 0, 25, 0, 0, 0, 25, 1, 0, 0]);
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.stack, vec![5, 10]);
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[0], 10);
+        assert_eq!(test_vm.registers[1], 5);
+        assert_eq!(test_vm.stack.len(), 0);
+        assert_eq!(test_vm.run_once(), Ok(ExitReason::EndOfProgram));
+    }
+
+    #[test]
+    fn test_pop_empty_stack_trap() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.program = prepend_header(vec![25, 0, 0, 0]);
+        assert_eq!(test_vm.run_once(), Err(VmError { pc: PIE_HEADER_LENGTH, trap: Trap::StackUnderflow }));
+    }
+
+    #[test]
+    fn test_call_ret_opcodes() {
+        let mut test_vm = VM::get_test_vm();
+        // call the subroutine at +8, which pushes a value it never pops and then returns
+        test_vm.program = prepend_header(vec![22, 0, 0, (PIE_HEADER_LENGTH + 8) as u8, 5, 0, 0, 0, 24, 0, 0, 0, 23, 0, 0, 0]);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, PIE_HEADER_LENGTH + 8);
+        assert_eq!(test_vm.frames.len(), 1);
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, PIE_HEADER_LENGTH + 4);
+        assert_eq!(test_vm.frames.len(), 0);
+        assert_eq!(test_vm.stack.len(), 0);
+    }
+
+    #[test]
+    fn test_ret_without_call_trap() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.program = prepend_header(vec![23, 0, 0, 0]);
+        assert_eq!(test_vm.run_once(), Err(VmError { pc: PIE_HEADER_LENGTH, trap: Trap::StackUnderflow }));
+    }
+
+    #[test]
+    fn test_unbounded_recursion_trap() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.program = prepend_header(vec![22, 0, 0, PIE_HEADER_LENGTH as u8]);
+        assert_eq!(test_vm.run(), Err(VmError { pc: PIE_HEADER_LENGTH, trap: Trap::StackOverflow }));
+        assert_eq!(test_vm.frames.len(), MAX_CALL_DEPTH);
+    }
+
     #[test]
     fn test_aloc_opcode() {
         let mut test_vm = VM::get_test_vm();
         test_vm.registers[0] = 1024;
         test_vm.program = vec![19, 0, 0, 0];
         test_vm.program = prepend_header(test_vm.program);
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.heap.len(), 1024);
     }
 
+    #[test]
+    fn test_word_load_store_opcodes() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.heap = vec![0; 16];
+        test_vm.registers[0] = -2;
+        test_vm.registers[1] = 4;
+        test_vm.program = prepend_header(vec![31, 0, 1, 4, 28, 2, 1, 4]);
+        test_vm.run_once().unwrap();
+        assert_eq!(&test_vm.heap[8..12], &[254, 255, 255, 255]);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], -2);
+    }
+
+    #[test]
+    fn test_halfword_load_store_opcodes() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.heap = vec![0; 4];
+        test_vm.registers[0] = 0x12345;
+        test_vm.registers[1] = 0;
+        test_vm.program = prepend_header(vec![30, 0, 1, 1, 27, 2, 1, 1]);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.heap, vec![0, 0x45, 0x23, 0]);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], 0x2345);
+    }
+
+    #[test]
+    fn test_byte_load_store_opcodes() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.heap = vec![0; 4];
+        test_vm.registers[0] = -1;
+        test_vm.registers[1] = 3;
+        test_vm.program = prepend_header(vec![29, 0, 1, 0, 26, 2, 1, 0]);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.heap, vec![0, 0, 0, 255]);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], 255);
+    }
+
+    #[test]
+    fn test_heap_out_of_bounds_trap() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.heap = vec![0; 4];
+        test_vm.registers[1] = 1;
+        test_vm.program = prepend_header(vec![28, 0, 1, 0]);
+        assert_eq!(test_vm.run_once(), Err(VmError { pc: PIE_HEADER_LENGTH, trap: Trap::HeapOutOfBounds { address: 1 } }));
+        test_vm.registers[1] = -1;
+        test_vm.pc = PIE_HEADER_LENGTH;
+        assert_eq!(test_vm.run_once(), Err(VmError { pc: PIE_HEADER_LENGTH, trap: Trap::HeapOutOfBounds { address: -1 } }));
+    }
+
+    #[test]
+    fn test_prts_opcode() {
+        let mut test_vm = VM::get_test_vm();
+        let buffer = SharedBuffer(Rc::new(RefCell::new(vec![])));
+        test_vm.set_output(Box::new(buffer.clone()));
+        let mut program = build_image(&[72, 105, 0, 33], &[32, 0, 0, 0, 32, 0, 0, 3, 32, 0, 0, 4]);
+        test_vm.add_bytes(&mut program).unwrap();
+        assert_eq!(test_vm.ro_data, vec![72, 105, 0, 33]);
+        test_vm.pc = PIE_HEADER_LENGTH + 4;
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        assert_eq!(*buffer.0.borrow(), b"Hi!".to_vec());
+        assert_eq!(test_vm.run_once(), Err(VmError { pc: PIE_HEADER_LENGTH + 12, trap: Trap::ReadOnlyOutOfBounds { offset: 4 } }));
+    }
+
     #[test]
     fn test_inc_opcode() {
         let mut test_vm = VM::get_test_vm();
         test_vm.registers[0] = 1024;
         test_vm.program = vec![17, 0, 0, 0];
         test_vm.program = prepend_header(test_vm.program);
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.registers[0], 1025);
+        assert_eq!(test_vm.pc, PIE_HEADER_LENGTH + 4);
     }
 
     #[test]
@@ -407,7 +1418,7 @@ mod tests {
         test_vm.registers[0] = 1024;
         test_vm.program = vec![18, 0, 0, 0];
         test_vm.program = prepend_header(test_vm.program);
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.registers[0], 1023);
     }
 }